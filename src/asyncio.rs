@@ -0,0 +1,168 @@
+use std::future::{self, Future};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::{connected, ConnectionManager, Interface, InterfaceHandle, Quad};
+use crate::{Socket, TcpListener, TcpStream, Wait};
+
+/*
+  Async sockets
+
+  The same sockets, without blocking a thread. Where the blocking calls wait on a condvar, the
+  poll functions leave the task's waker with the connection manager, under the socket it's
+  waiting on, and packet_loop wakes it wherever it notifies the condvar:
+
+      poll_read --> nothing to read --> wakers.rcv[quad] = waker --> Pending
+      packet_loop --> data arrived for quad --> wake(Rcv, quad) --> notify_all + wake rcv[quad]
+
+  Only the task waiting on that socket is woken, the others have nothing new to look at.
+
+  It doesn't need any particular runtime, anything that polls futures will do. Nonblocking mode
+  and timeouts are for the blocking calls only, an executor has its own ways of giving up.
+*/
+
+// runs try_ with the manager locked, and registers for w on s if it has to wait
+fn poll<T>(
+    h: &InterfaceHandle,
+    cx: &mut Context<'_>,
+    w: Wait,
+    s: Socket,
+    try_: impl FnOnce(&mut ConnectionManager) -> io::Result<Option<T>>,
+) -> Poll<io::Result<T>> {
+    let mut cm = h.manager.lock().unwrap();
+    match try_(&mut cm) {
+        Ok(Some(v)) => Poll::Ready(Ok(v)),
+        Err(e) => Poll::Ready(Err(e)),
+        Ok(None) => {
+            cm.wakers.register(w, s, cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+impl TcpStream {
+    pub fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let (h, s) = (self.h.clone(), Socket::Stream(self.quad));
+        poll(&h, cx, Wait::Rcv, s, |cm| self.try_read(cm, buf))
+    }
+
+    pub fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let (h, s) = (self.h.clone(), Socket::Stream(self.quad));
+        poll(&h, cx, Wait::Snd, s, |cm| self.try_queue(cm, buf, false))
+    }
+
+    // ready once everything written has been acked
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let (h, s) = (self.h.clone(), Socket::Stream(self.quad));
+        poll(&h, cx, Wait::Snd, s, |cm| self.try_flush(cm))
+    }
+
+    pub async fn read_async(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        future::poll_fn(|cx| self.poll_read(cx, buf)).await
+    }
+
+    pub async fn write_async(&mut self, buf: &[u8]) -> io::Result<usize> {
+        future::poll_fn(|cx| self.poll_write(cx, buf)).await
+    }
+
+    pub async fn flush_async(&mut self) -> io::Result<()> {
+        future::poll_fn(|cx| self.poll_flush(cx)).await
+    }
+}
+
+impl TcpListener {
+    pub fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<TcpStream>> {
+        let (h, s) = (self.h.clone(), Socket::Listener(self.port));
+        poll(&h, cx, Wait::Pending, s, |cm| Ok(self.try_accept(cm)))
+    }
+
+    pub async fn accept_async(&mut self) -> io::Result<TcpStream> {
+        future::poll_fn(|cx| self.poll_accept(cx)).await
+    }
+}
+
+impl Interface {
+    // like connect, but waits for the handshake without blocking
+    pub async fn connect_async(&mut self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let quad = self.start_connect(addr)?;
+        Connecting {
+            quad,
+            h: Some(self.ih.as_ref().unwrap().clone()),
+        }
+        .await
+    }
+}
+
+// a connection on its way up. if it's dropped before it's done, so is the connection.
+struct Connecting {
+    quad: Quad,
+    // taken once the stream is handed out
+    h: Option<InterfaceHandle>,
+}
+
+impl Future for Connecting {
+    type Output = io::Result<TcpStream>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let quad = self.quad;
+        let h = self.h.clone().expect("polled after completion");
+        let r = poll(&h, cx, Wait::Rcv, Socket::Stream(quad), |cm| {
+            Ok(connected(cm, &quad)?.then_some(()))
+        });
+        match r {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(r) => {
+                self.h = None;
+                Poll::Ready(r.map(|()| TcpStream::new(quad, h)))
+            }
+        }
+    }
+}
+
+impl Drop for Connecting {
+    fn drop(&mut self) {
+        if let Some(h) = self.h.take() {
+            // nobody's going to get a stream for it. if the peer answers our SYN, it gets a
+            // RST for its trouble.
+            h.manager.lock().unwrap().connections.remove(&self.quad);
+        }
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl futures_io::AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        TcpStream::poll_read(self.get_mut(), cx, buf)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl futures_io::AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        TcpStream::poll_write(self.get_mut(), cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        TcpStream::poll_flush(self.get_mut(), cx)
+    }
+
+    // waits for everything written to be acked, then sends our FIN. like shutdown, it doesn't
+    // wait for the FIN to be acked.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let s = self.get_mut();
+        match TcpStream::poll_flush(s, cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(s.shutdown(std::net::Shutdown::Write)),
+            r => r,
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time;
+
+use tun_tap::Mode;
+
+use crate::clock::{self, Clock};
+use crate::device::Device;
+use crate::link::Nic;
+use crate::{icmp, ip, isn, netlink, tcp, ConnectionManager, Interface};
+
+/*
+  Setting up an interface
+
+  Everything Interface::new takes for granted, spelled out:
+
+      InterfaceBuilder::new()
+          .name("tun1")
+          .addr("10.0.0.2".parse().unwrap(), 24)
+          .mtu(1400)
+          .recv_buffer(16 * 1024)
+          .build()?
+
+  An interface with addresses only takes packets sent to them, or to the broadcast address of
+  one of their networks, or to a multicast group. One without takes whatever it gets and
+  learns its addresses from that, like before.
+
+  Those are our addresses. The kernel's end of the device is left alone unless asked for with
+  device_addr, route and up, which build sets up over netlink (see netlink.rs):
+
+      InterfaceBuilder::new()
+          .device_addr("10.0.0.1".parse().unwrap(), 24)
+          .addr("10.0.0.2".parse().unwrap(), 24)
+          .up()
+          .build()?
+*/
+
+// the largest packet an IP header can describe
+const MAX_MTU: usize = u16::MAX as usize;
+// how often a SYN or SYN-ACK may be sent again. the timeout doubles every time but stops at a
+// minute, so with the default RTO this many give the handshake some 12 minutes, longer than
+// anyone will wait on a connect.
+const MAX_SYN_RETRIES: u32 = 16;
+
+pub struct InterfaceBuilder {
+    name: String,
+    addrs: Vec<ip::Cidr>,
+    // for a tap device, the MAC address we answer ARP requests with
+    mac: Option<[u8; 6]>,
+    // and the router for everything off its network
+    gateway: Option<Ipv4Addr>,
+    clock: clock::SharedClock,
+    isn_secret: Option<[u8; 16]>,
+    tcp: tcp::Config,
+    // what to configure on the kernel's side of the device
+    setup: netlink::Setup,
+}
+
+impl Default for InterfaceBuilder {
+    fn default() -> Self {
+        InterfaceBuilder {
+            name: "tun0".to_string(),
+            addrs: Vec::new(),
+            mac: None,
+            gateway: None,
+            clock: clock::SharedClock::default(),
+            isn_secret: None,
+            tcp: tcp::Config::default(),
+            setup: netlink::Setup::default(),
+        }
+    }
+}
+
+impl InterfaceBuilder {
+    pub fn new() -> Self {
+        InterfaceBuilder::default()
+    }
+
+    // the tun (or tap) device to open, tun0 by default
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    // an address of ours, on a network with a prefix that long. the first one of each address
+    // family is what we send from, like add_local_addr.
+    pub fn addr(mut self, addr: IpAddr, prefix: u8) -> Self {
+        self.addrs.push(ip::Cidr { addr, prefix });
+        self
+    }
+
+    // an address for the kernel's end of the device, like ip addr add. it also gets the kernel
+    // a route to the rest of the network.
+    pub fn device_addr(mut self, addr: IpAddr, prefix: u8) -> Self {
+        self.setup.addrs.push(ip::Cidr { addr, prefix });
+        self
+    }
+
+    // has the kernel send packets for the network dst/prefix to the device, like ip route add
+    pub fn route(mut self, dst: IpAddr, prefix: u8) -> Self {
+        self.setup.routes.push(ip::Cidr { addr: dst, prefix });
+        self
+    }
+
+    // brings the device up with our MTU, like ip link set up
+    pub fn up(mut self) -> Self {
+        self.setup.up = true;
+        self
+    }
+
+    // open a tap device instead, speaking Ethernet as mac. needs an IPv4 address to answer ARP
+    // requests for.
+    pub fn tap(mut self, mac: [u8; 6]) -> Self {
+        self.mac = Some(mac);
+        self
+    }
+
+    // where a tap device sends what isn't on the network of its IPv4 address
+    pub fn gateway(mut self, gateway: Ipv4Addr) -> Self {
+        self.gateway = Some(gateway);
+        self
+    }
+
+    // the largest packet we send, and receive. it should match the device's.
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.tcp.mtu = mtu;
+        self
+    }
+
+    // how many bytes a connection holds until the application reads them. without window
+    // scaling, the window can't offer more than 64k.
+    pub fn recv_buffer(mut self, size: usize) -> Self {
+        self.tcp.recv_buffer = size;
+        self
+    }
+
+    // how many bytes a connection holds until the peer acks them
+    pub fn send_buffer(mut self, size: usize) -> Self {
+        self.tcp.send_buffer = size;
+        self
+    }
+
+    // the key for initial sequence numbers, random unless given
+    pub fn isn_secret(mut self, secret: [u8; 16]) -> Self {
+        self.isn_secret = Some(secret);
+        self
+    }
+
+    // the retransmission timeout until a round trip has been timed, the SYN's or SYN-ACK's first
+    pub fn initial_rto(mut self, rto: time::Duration) -> Self {
+        self.tcp.initial_rto = rto;
+        self
+    }
+
+    // how often to send a SYN again before connect gives up
+    pub fn syn_retries(mut self, n: u32) -> Self {
+        self.tcp.syn_retries = n;
+        self
+    }
+
+    // how often to send a SYN-ACK again before forgetting a half-open connection
+    pub fn syn_ack_retries(mut self, n: u32) -> Self {
+        self.tcp.syn_ack_retries = n;
+        self
+    }
+
+    pub fn time_wait(mut self, dur: time::Duration) -> Self {
+        self.tcp.time_wait = dur;
+        self
+    }
+
+    // how long to keep retransmitting without hearing back before aborting a connection
+    pub fn user_timeout(mut self, dur: time::Duration) -> Self {
+        self.tcp.user_timeout = dur;
+        self
+    }
+
+    // what all timers go by, see Interface::with_device_and_clock
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = clock::SharedClock::new(clock);
+        self
+    }
+
+    // opens the device by name, configures it if asked to, and starts the stack on it
+    pub fn build(mut self) -> io::Result<Interface> {
+        self.validate()?;
+        let mode = if self.mac.is_some() {
+            Mode::Tap
+        } else {
+            Mode::Tun
+        };
+        let iface = tun_tap::Iface::without_packet_info(&self.name, mode)?;
+        let setup = std::mem::take(&mut self.setup);
+        let device = if setup.is_empty() {
+            None
+        } else {
+            // the kernel may have filled in a name like tun%d
+            Some(setup.apply(iface.name(), self.tcp.mtu)?)
+        };
+        let mut i = self.start(iface)?;
+        i.device = device;
+        Ok(i)
+    }
+
+    // like build, but over a device of the caller's. the name is ignored, and so the device
+    // can't be configured.
+    pub fn build_with_device(self, dev: impl Device + 'static) -> io::Result<Interface> {
+        self.validate()?;
+        if !self.setup.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only a device opened by name can be configured",
+            ));
+        }
+        self.start(dev)
+    }
+
+    fn start(self, dev: impl Device + 'static) -> io::Result<Interface> {
+        let nic = match self.mac {
+            Some(mac) => {
+                let (v4, prefix) = self
+                    .addrs
+                    .iter()
+                    .find_map(|c| match c.addr {
+                        IpAddr::V4(a) => Some((a, c.prefix)),
+                        IpAddr::V6(_) => None,
+                    })
+                    .expect("validated");
+                let clock = self.clock.clone();
+                Nic::ethernet(Box::new(dev), mac, v4, prefix, self.gateway, clock)
+            }
+            None => Nic::ip(Box::new(dev), self.clock.clone()),
+        };
+
+        let now = self.clock.now();
+        let mut cm = ConnectionManager::with_clock(self.clock);
+        cm.config = self.tcp;
+        if let Some(secret) = self.isn_secret {
+            cm.isn = isn::Isn::with_secret(secret, now);
+        }
+        for c in &self.addrs {
+            if cm.local_addr_for(c.addr).is_none() {
+                cm.local_addrs.push(c.addr);
+            }
+        }
+        cm.addrs = self.addrs;
+        Interface::with_nic(nic, cm)
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        let all = self.addrs.iter();
+        for c in all.chain(&self.setup.addrs).chain(&self.setup.routes) {
+            let max = if c.addr.is_ipv4() { 32 } else { 128 };
+            if c.prefix > max {
+                return invalid("prefix longer than the address");
+            }
+        }
+        let mtu = self.tcp.mtu;
+        // RFC 1122 3.3.3 and RFC 8200 5, the least every link has to carry
+        if !(icmp::MIN_MTU_V4..=MAX_MTU).contains(&mtu) {
+            return invalid("mtu out of range");
+        }
+        let mut all = self.addrs.iter().chain(&self.setup.addrs);
+        if mtu < icmp::MIN_MTU_V6 && all.any(|c| c.addr.is_ipv6()) {
+            return invalid("mtu too small for IPv6");
+        }
+        if self.tcp.recv_buffer == 0 || self.tcp.recv_buffer > u16::MAX as usize {
+            return invalid("receive buffer must fit in a window");
+        }
+        if self.tcp.send_buffer == 0 {
+            return invalid("send buffer must not be empty");
+        }
+        if self.tcp.initial_rto.is_zero() || self.tcp.user_timeout.is_zero() {
+            return invalid("cannot set a 0 duration timeout");
+        }
+        if self.tcp.syn_retries > MAX_SYN_RETRIES || self.tcp.syn_ack_retries > MAX_SYN_RETRIES {
+            return invalid("too many SYN retries");
+        }
+        if self.mac.is_some() && !self.addrs.iter().any(|c| c.addr.is_ipv4()) {
+            return invalid("a tap device needs an IPv4 address");
+        }
+        if self.gateway.is_some() && self.mac.is_none() {
+            return invalid("only a tap device has a gateway");
+        }
+        Ok(())
+    }
+}
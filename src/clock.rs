@@ -0,0 +1,82 @@
+use std::sync::{Arc, Mutex};
+use std::time;
+
+// where the stack gets the time from for all of its timers. the usual clock is the system's,
+// but a test can hand us a ManualClock and decide itself when time passes.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> time::Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> time::Instant {
+        time::Instant::now()
+    }
+}
+
+// a clock that stands still until it's advanced. clones share the same time.
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<time::Instant>>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        // Instant has no zero, so we start from the real time and go our own way from there
+        ManualClock {
+            now: Arc::new(Mutex::new(time::Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, by: time::Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> time::Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+// the clock an interface runs on, shared by everything in it that keeps time
+#[derive(Clone)]
+pub(crate) struct SharedClock {
+    clock: Arc<dyn Clock>,
+    // whether it's the system's, so a timer set for a deadline goes off when it's due
+    real: bool,
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        SharedClock {
+            clock: Arc::new(SystemClock),
+            real: true,
+        }
+    }
+}
+
+impl SharedClock {
+    pub(crate) fn new(clock: impl Clock + 'static) -> Self {
+        SharedClock {
+            clock: Arc::new(clock),
+            real: false,
+        }
+    }
+
+    pub(crate) fn now(&self) -> time::Instant {
+        self.clock.now()
+    }
+
+    // any other clock may jump ahead whenever it likes, without telling us
+    pub(crate) fn is_real(&self) -> bool {
+        self.real
+    }
+}
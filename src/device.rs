@@ -0,0 +1,63 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+
+// something that moves packets for us. what a frame is depends on how the interface was set up:
+// a bare IP packet, or an Ethernet frame.
+//
+// packet_loop waits for the fd to become readable before calling recv, so recv should not block
+// once it is.
+pub trait Device: AsRawFd + Send {
+    fn send(&mut self, frame: &[u8]) -> io::Result<usize>;
+    // one frame per call, like a datagram socket
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+impl Device for tun_tap::Iface {
+    fn send(&mut self, frame: &[u8]) -> io::Result<usize> {
+        tun_tap::Iface::send(self, frame)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        tun_tap::Iface::recv(self, buf)
+    }
+}
+
+// one end of an in-memory link, everything sent into one end comes out of the other. good for
+// wiring two interfaces together in the same process, say in a test.
+pub struct VirtualDevice(UnixDatagram);
+
+// a pair of devices connected to each other
+pub fn virtual_link() -> io::Result<(VirtualDevice, VirtualDevice)> {
+    let (a, b) = UnixDatagram::pair()?;
+    // a wire doesn't wait for the other end to catch up, and neither do we
+    a.set_nonblocking(true)?;
+    b.set_nonblocking(true)?;
+    Ok((VirtualDevice(a), VirtualDevice(b)))
+}
+
+impl Device for VirtualDevice {
+    fn send(&mut self, frame: &[u8]) -> io::Result<usize> {
+        match self.0.send(frame) {
+            // the other end isn't reading fast enough, or has gone away. either way the frame
+            // is lost, like it would be on a real link.
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::ConnectionRefused =>
+            {
+                Ok(frame.len())
+            }
+            r => r,
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+impl AsRawFd for VirtualDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
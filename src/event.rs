@@ -0,0 +1,113 @@
+use std::io;
+use std::os::unix::io::{BorrowedFd, RawFd};
+use std::sync::Arc;
+use std::time;
+
+use nix::errno::Errno;
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
+use nix::sys::eventfd::{EfdFlags, EventFd};
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
+
+/*
+  The event loop
+
+  packet_loop sleeps in epoll until one of three things happens:
+
+      device   -- a packet came in
+      timerfd  -- the next deadline of any connection, fragment or neighbor is here
+      eventfd  -- an application thread gave us something to do, like data to send
+
+  Only the device says something by itself, the other two are just there to wake us up, and
+  whatever woke us, we go through everything that's due.
+*/
+
+const DEVICE: u64 = 0;
+const TIMER: u64 = 1;
+const WAKEUP: u64 = 2;
+
+// wakes up packet_loop from other threads. clones poke the same loop.
+#[derive(Clone)]
+pub(crate) struct Wakeup(Arc<EventFd>);
+
+impl Wakeup {
+    pub(crate) fn new() -> io::Result<Self> {
+        let fd = EventFd::from_flags(EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC)?;
+        Ok(Wakeup(Arc::new(fd)))
+    }
+
+    pub(crate) fn wake(&self) {
+        match self.0.write(1) {
+            // the counter is full, so it's woken up already
+            Ok(_) | Err(Errno::EAGAIN) => {}
+            Err(e) => eprintln!("can't wake up packet_loop {:?}", e),
+        }
+    }
+}
+
+pub(crate) struct EventLoop {
+    epoll: Epoll,
+    timer: TimerFd,
+    wakeup: Wakeup,
+}
+
+impl EventLoop {
+    pub(crate) fn new(device: RawFd, wakeup: Wakeup) -> io::Result<Self> {
+        let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
+        let timer = TimerFd::new(
+            ClockId::CLOCK_MONOTONIC,
+            TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+        )?;
+        // the device outlives the loop, packet_loop owns both
+        let device = unsafe { BorrowedFd::borrow_raw(device) };
+        epoll.add(device, EpollEvent::new(EpollFlags::EPOLLIN, DEVICE))?;
+        epoll.add(&timer, EpollEvent::new(EpollFlags::EPOLLIN, TIMER))?;
+        epoll.add(&*wakeup.0, EpollEvent::new(EpollFlags::EPOLLIN, WAKEUP))?;
+        Ok(EventLoop {
+            epoll,
+            timer,
+            wakeup,
+        })
+    }
+
+    // sleeps until something happens, but no longer than timeout. true if there's a packet to
+    // read.
+    pub(crate) fn wait(&mut self, timeout: Option<time::Duration>) -> io::Result<bool> {
+        let epoll_timeout = match timeout {
+            // an expiration of 0 disarms the timer, so don't sleep at all instead
+            Some(t) if t.is_zero() => EpollTimeout::ZERO,
+            Some(t) => {
+                self.timer.set(
+                    Expiration::OneShot(TimeSpec::from_duration(t)),
+                    TimerSetTimeFlags::empty(),
+                )?;
+                EpollTimeout::NONE
+            }
+            None => {
+                self.timer.unset()?;
+                EpollTimeout::NONE
+            }
+        };
+        let mut events = [EpollEvent::empty(); 3];
+        let n = match self.epoll.wait(&mut events, epoll_timeout) {
+            Ok(n) => n,
+            Err(Errno::EINTR) => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let mut readable = false;
+        for e in &events[..n] {
+            match e.data() {
+                DEVICE => readable = true,
+                TIMER => match self.timer.wait() {
+                    Ok(()) | Err(Errno::EAGAIN) => {}
+                    Err(e) => return Err(e.into()),
+                },
+                _ => match self.wakeup.0.read() {
+                    Ok(_) | Err(Errno::EAGAIN) => {}
+                    Err(e) => return Err(e.into()),
+                },
+            }
+        }
+        Ok(readable)
+    }
+}
@@ -0,0 +1,522 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time;
+
+use crate::ip::IpHeader;
+use crate::link::Nic;
+use crate::Quad;
+
+// how many echo replies we send per second unless told otherwise
+const DEFAULT_ECHO_RATE: u32 = 100;
+// how many error messages we send per second
+const ERROR_RATE: u32 = 100;
+// the smallest MTUs, which an error message has to fit in along with what it quotes
+pub(crate) const MIN_MTU_V4: usize = 576;
+pub(crate) const MIN_MTU_V6: usize = 1280;
+
+pub(crate) struct Icmp {
+    // whether we answer pings at all
+    pub(crate) echo: bool,
+    pub(crate) echo_limit: RateLimit,
+    error_limit: RateLimit,
+}
+
+// an ICMP error about a TCP segment we sent
+pub(crate) struct TcpError {
+    pub(crate) quad: Quad,
+    // sequence number of the segment that caused it
+    pub(crate) seq: u32,
+    pub(crate) problem: Problem,
+}
+
+pub(crate) enum Problem {
+    // RFC 5927: hard errors are worth aborting a connection over while it's still being set
+    // up, soft ones are only worth mentioning if the connection fails anyway
+    Unreachable { hard: bool, kind: io::ErrorKind },
+    // the segment didn't fit through a link that only takes packets up to mtu bytes
+    TooBig { mtu: usize },
+}
+
+impl Default for Icmp {
+    fn default() -> Self {
+        Icmp {
+            echo: true,
+            echo_limit: RateLimit::new(DEFAULT_ECHO_RATE),
+            error_limit: RateLimit::new(ERROR_RATE),
+        }
+    }
+}
+
+impl Icmp {
+    // answers pings, and returns the error if the packet is about one of our TCP segments
+    pub(crate) fn on_packet(
+        &mut self,
+        nic: &mut Nic,
+        src: IpAddr,
+        dst: IpAddr,
+        data: &[u8],
+    ) -> io::Result<Option<TcpError>> {
+        let (IpAddr::V4(src), IpAddr::V4(dst)) = (src, dst) else {
+            // ICMPv4 has no business in an IPv6 packet
+            return Ok(None);
+        };
+        let icmp = match etherparse::Icmpv4Slice::from_slice(data) {
+            Ok(icmp) => icmp,
+            Err(e) => {
+                eprintln!("ignoring weird icmp packets {:?}", e);
+                return Ok(None);
+            }
+        };
+        let expected =
+            etherparse::Icmpv4Header::with_checksum(icmp.icmp_type(), icmp.payload()).checksum;
+        if icmp.checksum() != expected {
+            eprintln!("ignoring icmp packet with bad checksum");
+            return Ok(None);
+        }
+
+        use etherparse::icmpv4::DestUnreachableHeader as Unreach;
+        use io::ErrorKind;
+        // which errors are hard follows RFC 1122 4.2.3.9, and the error kinds are what Linux
+        // reports for them
+        let problem = match icmp.icmp_type() {
+            etherparse::Icmpv4Type::EchoRequest(echo) => {
+                self.echo(nic, src, dst, echo, icmp.payload())?;
+                return Ok(None);
+            }
+            etherparse::Icmpv4Type::DestinationUnreachable(h) => match h {
+                Unreach::Network | Unreach::NetworkUnknown | Unreach::TosNetwork => {
+                    unreachable(false, ErrorKind::NetworkUnreachable)
+                }
+                Unreach::Host
+                | Unreach::HostUnknown
+                | Unreach::TosHost
+                | Unreach::SourceRouteFailed => unreachable(false, ErrorKind::HostUnreachable),
+                Unreach::Protocol | Unreach::Port => {
+                    unreachable(true, ErrorKind::ConnectionRefused)
+                }
+                Unreach::Isolated => unreachable(true, ErrorKind::HostUnreachable),
+                Unreach::NetworkProhibited
+                | Unreach::HostProhibited
+                | Unreach::FilterProhibited
+                | Unreach::HostPrecedenceViolation
+                | Unreach::PrecedenceCutoff => unreachable(true, ErrorKind::PermissionDenied),
+                // routers from before RFC 1191 don't say how big, which leaves us at the base
+                Unreach::FragmentationNeeded { next_hop_mtu } => Problem::TooBig {
+                    mtu: next_hop_mtu as usize,
+                },
+            },
+            etherparse::Icmpv4Type::TimeExceeded(_) => {
+                unreachable(false, ErrorKind::HostUnreachable)
+            }
+            etherparse::Icmpv4Type::ParameterProblem(_) => {
+                unreachable(true, ErrorKind::InvalidData)
+            }
+            // source quench is deprecated (RFC 6633), and we don't do anything with redirects
+            _ => return Ok(None),
+        };
+        let Ok(ip) = etherparse::Ipv4HeaderSlice::from_slice(icmp.payload()) else {
+            return Ok(None);
+        };
+        if ip.protocol() != etherparse::IpNumber::TCP {
+            return Ok(None);
+        }
+        Ok(tcp_error(
+            ip.source_addr().into(),
+            ip.destination_addr().into(),
+            &icmp.payload()[ip.slice().len()..],
+            problem,
+        ))
+    }
+
+    pub(crate) fn on_packet_v6(
+        &mut self,
+        src: IpAddr,
+        dst: IpAddr,
+        data: &[u8],
+    ) -> io::Result<Option<TcpError>> {
+        let (IpAddr::V6(src), IpAddr::V6(dst)) = (src, dst) else {
+            return Ok(None);
+        };
+        let icmp = match etherparse::Icmpv6Slice::from_slice(data) {
+            Ok(icmp) => icmp,
+            Err(e) => {
+                eprintln!("ignoring weird icmpv6 packets {:?}", e);
+                return Ok(None);
+            }
+        };
+        let expected = icmp
+            .icmp_type()
+            .calc_checksum(src.octets(), dst.octets(), icmp.payload());
+        if expected.ok() != Some(icmp.checksum()) {
+            eprintln!("ignoring icmpv6 packet with bad checksum");
+            return Ok(None);
+        }
+
+        use etherparse::icmpv6::DestUnreachableCode as Unreach;
+        use io::ErrorKind;
+        let problem = match icmp.icmp_type() {
+            etherparse::Icmpv6Type::DestinationUnreachable(code) => match code {
+                Unreach::NoRoute => unreachable(false, ErrorKind::NetworkUnreachable),
+                Unreach::BeyondScope | Unreach::Address => {
+                    unreachable(false, ErrorKind::HostUnreachable)
+                }
+                Unreach::Port => unreachable(true, ErrorKind::ConnectionRefused),
+                Unreach::Prohibited | Unreach::SourceAddressFailedPolicy | Unreach::RejectRoute => {
+                    unreachable(true, ErrorKind::PermissionDenied)
+                }
+            },
+            etherparse::Icmpv6Type::PacketTooBig { mtu } => Problem::TooBig { mtu: mtu as usize },
+            etherparse::Icmpv6Type::TimeExceeded(_) => {
+                unreachable(false, ErrorKind::HostUnreachable)
+            }
+            etherparse::Icmpv6Type::ParameterProblem(_) => {
+                unreachable(true, ErrorKind::InvalidData)
+            }
+            // TODO: answer echo requests, and neighbor discovery for TAP
+            _ => return Ok(None),
+        };
+        let Ok(ip) = etherparse::Ipv6HeaderSlice::from_slice(icmp.payload()) else {
+            return Ok(None);
+        };
+        // we never send extension headers, so anything else isn't ours
+        if ip.next_header() != etherparse::IpNumber::TCP {
+            return Ok(None);
+        }
+        Ok(tcp_error(
+            ip.source_addr().into(),
+            ip.destination_addr().into(),
+            &icmp.payload()[ip.slice().len()..],
+            problem,
+        ))
+    }
+
+    // RFC 1122 4.1.3.1: tell whoever sent us packet that nothing is listening on the port it
+    // was for
+    pub(crate) fn port_unreachable(
+        &mut self,
+        nic: &mut Nic,
+        src: IpAddr,
+        dst: IpAddr,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        // RFC 1122 3.2.2: never about packets to or from more than one host
+        let many = match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                dst.is_broadcast() || dst.is_multicast() || src.is_multicast()
+            }
+            _ => dst.is_multicast() || src.is_multicast(),
+        };
+        if many || src.is_unspecified() || !self.error_limit.allow(nic.now()) {
+            return Ok(());
+        }
+
+        let mut buf = Vec::new();
+        match (src, dst) {
+            (IpAddr::V4(_), IpAddr::V4(_)) => {
+                // quote as much of the packet as fits (RFC 1812 4.3.2.3)
+                let quote = &packet[..std::cmp::min(packet.len(), MIN_MTU_V4 - 20 - 8)];
+                let icmp = etherparse::Icmpv4Header::with_checksum(
+                    etherparse::Icmpv4Type::DestinationUnreachable(
+                        etherparse::icmpv4::DestUnreachableHeader::Port,
+                    ),
+                    quote,
+                );
+                let mut ip = IpHeader::new(dst, src, etherparse::IpNumber::ICMP);
+                ip.set_payload_len(icmp.header_len() + quote.len());
+                ip.write(&mut buf)?;
+                icmp.write(&mut buf)?;
+                buf.extend_from_slice(quote);
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                // RFC 4443 2.4
+                let quote = &packet[..std::cmp::min(packet.len(), MIN_MTU_V6 - 40 - 8)];
+                let icmp = etherparse::Icmpv6Header::with_checksum(
+                    etherparse::Icmpv6Type::DestinationUnreachable(
+                        etherparse::icmpv6::DestUnreachableCode::Port,
+                    ),
+                    dst.octets(),
+                    src.octets(),
+                    quote,
+                )
+                .expect("quote always fits");
+                let mut ip = IpHeader::new(dst.into(), src.into(), etherparse::IpNumber::IPV6_ICMP);
+                ip.set_payload_len(icmp.header_len() + quote.len());
+                ip.write(&mut buf)?;
+                icmp.write(&mut buf)?;
+                buf.extend_from_slice(quote);
+            }
+            _ => return Ok(()),
+        }
+        nic.send(&buf)?;
+        Ok(())
+    }
+
+    fn echo(
+        &mut self,
+        nic: &mut Nic,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        echo: etherparse::IcmpEchoHeader,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        if !self.echo {
+            return Ok(());
+        }
+        // RFC 1122 3.2.2.6 lets us ignore pings to broadcast and multicast addresses
+        if dst.is_broadcast() || dst.is_multicast() {
+            return Ok(());
+        }
+        if !self.echo_limit.allow(nic.now()) {
+            return Ok(());
+        }
+
+        let reply = etherparse::Icmpv4Header::with_checksum(
+            etherparse::Icmpv4Type::EchoReply(echo),
+            payload,
+        );
+        let mut ip = IpHeader::new(dst.into(), src.into(), etherparse::IpNumber::ICMP);
+        ip.set_payload_len(reply.header_len() + payload.len());
+        let mut buf = Vec::with_capacity(ip.header_len() + reply.header_len() + payload.len());
+        ip.write(&mut buf)?;
+        reply.write(&mut buf)?;
+        buf.extend_from_slice(payload);
+        nic.send(&buf)?;
+        Ok(())
+    }
+}
+
+fn unreachable(hard: bool, kind: io::ErrorKind) -> Problem {
+    Problem::Unreachable { hard, kind }
+}
+
+// an ICMP error quotes the IP header of the packet that caused it and at least the first 8 bytes
+// after it, which for TCP are the ports and the sequence number
+fn tcp_error(src: IpAddr, dst: IpAddr, tcp: &[u8], problem: Problem) -> Option<TcpError> {
+    if tcp.len() < 8 {
+        return None;
+    }
+    let src_port = u16::from_be_bytes([tcp[0], tcp[1]]);
+    let dst_port = u16::from_be_bytes([tcp[2], tcp[3]]);
+    let seq = u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]);
+    Some(TcpError {
+        // we sent the quoted packet, so the quad is the other way around
+        quad: Quad {
+            src: (dst, dst_port),
+            dst: (src, src_port),
+        },
+        seq,
+        problem,
+    })
+}
+
+// a token bucket that allows up to a second's worth of packets in one burst
+pub(crate) struct RateLimit {
+    per_sec: u32,
+    tokens: f64,
+    // when we last refilled the bucket, it starts out full
+    last: Option<time::Instant>,
+}
+
+impl RateLimit {
+    pub(crate) fn new(per_sec: u32) -> Self {
+        RateLimit {
+            per_sec,
+            tokens: per_sec as f64,
+            last: None,
+        }
+    }
+
+    pub(crate) fn set_rate(&mut self, per_sec: u32) {
+        self.per_sec = per_sec;
+        self.tokens = self.tokens.min(per_sec as f64);
+    }
+
+    pub(crate) fn allow(&mut self, now: time::Instant) -> bool {
+        if let Some(last) = self.last {
+            let refill = now.duration_since(last).as_secs_f64() * self.per_sec as f64;
+            self.tokens = (self.tokens + refill).min(self.per_sec as f64);
+        }
+        self.last = Some(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+    use crate::clock::SharedClock;
+    use crate::device::virtual_link;
+
+    const US: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const ROUTER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 254);
+
+    // the start of a TCP segment we sent to the peer, as an ICMP error quotes it
+    fn quote(ours: IpAddr, peer: IpAddr, protocol: etherparse::IpNumber) -> Vec<u8> {
+        let mut tcp = Vec::new();
+        tcp.extend(8080u16.to_be_bytes());
+        tcp.extend(40000u16.to_be_bytes());
+        tcp.extend(1234u32.to_be_bytes());
+        let mut ip = IpHeader::new(ours, peer, protocol);
+        ip.set_payload_len(20);
+        let mut q = Vec::new();
+        ip.write(&mut q).unwrap();
+        q.extend(tcp);
+        q
+    }
+
+    fn v4_error(t: etherparse::Icmpv4Type) -> Option<TcpError> {
+        let (a, _b) = virtual_link().unwrap();
+        let mut nic = Nic::ip(Box::new(a), SharedClock::default());
+        let quote = quote(US.into(), PEER.into(), etherparse::IpNumber::TCP);
+        let mut data = etherparse::Icmpv4Header::with_checksum(t, &quote)
+            .to_bytes()
+            .to_vec();
+        data.extend(quote);
+        Icmp::default()
+            .on_packet(&mut nic, ROUTER.into(), US.into(), &data)
+            .unwrap()
+    }
+
+    fn v6_error(t: etherparse::Icmpv6Type) -> Option<TcpError> {
+        let us: Ipv6Addr = "fd00::1".parse().unwrap();
+        let peer: Ipv6Addr = "fd00::2".parse().unwrap();
+        let router: Ipv6Addr = "fd00::fe".parse().unwrap();
+        let quote = quote(us.into(), peer.into(), etherparse::IpNumber::TCP);
+        let icmp = etherparse::Icmpv6Header::with_checksum(t, router.octets(), us.octets(), &quote)
+            .unwrap();
+        let mut data = icmp.to_bytes().to_vec();
+        data.extend(quote);
+        Icmp::default()
+            .on_packet_v6(router.into(), us.into(), &data)
+            .unwrap()
+    }
+
+    fn unreachable_of(e: Option<TcpError>) -> (bool, io::ErrorKind) {
+        match e.expect("an error about our segment").problem {
+            Problem::Unreachable { hard, kind } => (hard, kind),
+            Problem::TooBig { mtu } => panic!("too big, {}", mtu),
+        }
+    }
+
+    // RFC 5927 and RFC 1122 4.2.3.9
+    #[test]
+    fn hard_and_soft_v4() {
+        use etherparse::icmpv4::DestUnreachableHeader as Unreach;
+        use etherparse::Icmpv4Type::*;
+        use io::ErrorKind::*;
+        let unreach = |h| unreachable_of(v4_error(DestinationUnreachable(h)));
+        assert_eq!(unreach(Unreach::Network), (false, NetworkUnreachable));
+        assert_eq!(unreach(Unreach::Host), (false, HostUnreachable));
+        assert_eq!(
+            unreach(Unreach::SourceRouteFailed),
+            (false, HostUnreachable)
+        );
+        assert_eq!(unreach(Unreach::Protocol), (true, ConnectionRefused));
+        assert_eq!(unreach(Unreach::Port), (true, ConnectionRefused));
+        assert_eq!(unreach(Unreach::HostProhibited), (true, PermissionDenied));
+        assert_eq!(
+            unreachable_of(v4_error(TimeExceeded(
+                etherparse::icmpv4::TimeExceededCode::TtlExceededInTransit
+            ))),
+            (false, HostUnreachable)
+        );
+
+        let e = v4_error(DestinationUnreachable(Unreach::FragmentationNeeded {
+            next_hop_mtu: 1400,
+        }))
+        .unwrap();
+        assert!(matches!(e.problem, Problem::TooBig { mtu: 1400 }));
+        // it's about the segment we sent, so from the peer's point of view
+        assert_eq!(e.quad.src, (PEER.into(), 40000));
+        assert_eq!(e.quad.dst, (US.into(), 8080));
+        assert_eq!(e.seq, 1234);
+    }
+
+    #[test]
+    fn hard_and_soft_v6() {
+        use etherparse::icmpv6::DestUnreachableCode as Unreach;
+        use etherparse::Icmpv6Type::*;
+        use io::ErrorKind::*;
+        let unreach = |c| unreachable_of(v6_error(DestinationUnreachable(c)));
+        assert_eq!(unreach(Unreach::NoRoute), (false, NetworkUnreachable));
+        assert_eq!(unreach(Unreach::Address), (false, HostUnreachable));
+        assert_eq!(unreach(Unreach::Port), (true, ConnectionRefused));
+        assert_eq!(unreach(Unreach::Prohibited), (true, PermissionDenied));
+        let e = v6_error(PacketTooBig { mtu: 1300 }).unwrap();
+        assert!(matches!(e.problem, Problem::TooBig { mtu: 1300 }));
+        assert_eq!(e.seq, 1234);
+    }
+
+    // errors that aren't about TCP, or don't add up, aren't for any connection
+    #[test]
+    fn not_about_tcp() {
+        let (a, _b) = virtual_link().unwrap();
+        let mut nic = Nic::ip(Box::new(a), SharedClock::default());
+        let t = etherparse::Icmpv4Type::DestinationUnreachable(
+            etherparse::icmpv4::DestUnreachableHeader::Port,
+        );
+        let udp = quote(US.into(), PEER.into(), etherparse::IpNumber::UDP);
+        let mut data = etherparse::Icmpv4Header::with_checksum(t.clone(), &udp)
+            .to_bytes()
+            .to_vec();
+        data.extend(udp);
+        let mut icmp = Icmp::default();
+        let got = icmp.on_packet(&mut nic, ROUTER.into(), US.into(), &data);
+        assert!(got.unwrap().is_none());
+
+        let tcp = quote(US.into(), PEER.into(), etherparse::IpNumber::TCP);
+        let mut data = etherparse::Icmpv4Header::with_checksum(t, &tcp)
+            .to_bytes()
+            .to_vec();
+        data.extend(tcp);
+        // a bad checksum
+        data[2] ^= 0xff;
+        let got = icmp.on_packet(&mut nic, ROUTER.into(), US.into(), &data);
+        assert!(got.unwrap().is_none());
+    }
+
+    #[test]
+    fn rate_limit_bursts_then_refills() {
+        let mut l = RateLimit::new(10);
+        let start = time::Instant::now();
+        // starts out full, a second's worth
+        for _ in 0..10 {
+            assert!(l.allow(start));
+        }
+        assert!(!l.allow(start));
+
+        // a token every 100ms
+        assert!(!l.allow(start + time::Duration::from_millis(50)));
+        assert!(l.allow(start + time::Duration::from_millis(100)));
+        assert!(!l.allow(start + time::Duration::from_millis(100)));
+
+        // however long it's been, the bucket only holds a second's worth
+        let later = start + time::Duration::from_secs(60);
+        for _ in 0..10 {
+            assert!(l.allow(later));
+        }
+        assert!(!l.allow(later));
+    }
+
+    #[test]
+    fn rate_limit_set_rate() {
+        let mut l = RateLimit::new(100);
+        let now = time::Instant::now();
+        // a lower rate also means a smaller burst
+        l.set_rate(2);
+        assert!(l.allow(now));
+        assert!(l.allow(now));
+        assert!(!l.allow(now));
+
+        l.set_rate(0);
+        assert!(!l.allow(now + time::Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,391 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::io::{self, Read};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::{thread, time};
+
+use nix::poll::{PollFd, PollFlags, PollTimeout};
+
+use crate::device::Device;
+
+/*
+  Network impairment
+
+  Impaired sits between packet_loop and the real device and makes the link between them
+  worse, on purpose. A worker thread owns the real device, and everything goes through it in
+  both directions:
+
+      packet_loop <--> Impaired <==pair==> worker <--> device
+
+  Each direction has its own pipeline, applied in this order:
+
+      loss --> duplication --> reordering --> bandwidth cap --> latency and jitter
+
+  Loss is either independent per packet or follows a Gilbert-Elliott channel, which flips
+  between a good and a bad state to get bursts. Decisions come from a seeded RNG, so the same
+  seed loses, duplicates and reorders the same packets. When they arrive is up to real time,
+  whatever clock the interface runs on.
+*/
+
+// how long a packet held back for reordering waits for others to overtake it, so a quiet link
+// still delivers it
+const REORDER_HOLD: time::Duration = time::Duration::from_millis(5);
+
+// what to do to packets going one way
+#[derive(Clone, Debug, Default)]
+pub struct Impairment {
+    // chance of losing a packet, ignored if there's a burst loss model
+    pub loss: f64,
+    pub burst_loss: Option<GilbertElliott>,
+    // chance of delivering a packet twice
+    pub duplicate: f64,
+    // how many later packets may overtake one, 0 keeps them in order
+    pub reorder: usize,
+    pub latency: time::Duration,
+    // extra delay of up to this much, picked anew for every packet
+    pub jitter: time::Duration,
+    // the most bytes per second the link carries, packets queue up behind each other beyond it
+    pub rate: Option<u64>,
+}
+
+impl Impairment {
+    fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        // also false for NaN
+        let chance = |p: f64| (0.0..=1.0).contains(&p);
+        let mut chances = vec![self.loss, self.duplicate];
+        if let Some(ge) = self.burst_loss {
+            chances.extend([ge.p, ge.r, ge.loss_good, ge.loss_bad]);
+        }
+        if !chances.into_iter().all(chance) {
+            return invalid("chances must be between 0 and 1");
+        }
+        if self.rate == Some(0) {
+            // nothing would ever get through
+            return invalid("rate must not be 0");
+        }
+        Ok(())
+    }
+}
+
+// a two-state Markov channel for bursty loss
+#[derive(Clone, Copy, Debug)]
+pub struct GilbertElliott {
+    // chance of going from good to bad after a packet
+    pub p: f64,
+    // and from bad back to good
+    pub r: f64,
+    // chance of losing a packet in each state
+    pub loss_good: f64,
+    pub loss_bad: f64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Impairments {
+    pub seed: u64,
+    // to the stack from the device
+    pub inbound: Impairment,
+    // from the stack to the device
+    pub outbound: Impairment,
+}
+
+// a device with its link made worse as configured
+pub struct Impaired {
+    link: UnixDatagram,
+    // the worker stops once this is gone
+    _alive: UnixStream,
+}
+
+impl Impaired {
+    pub fn new(dev: impl Device + 'static, config: Impairments) -> io::Result<Self> {
+        config.inbound.validate()?;
+        config.outbound.validate()?;
+        let (link, worker_link) = UnixDatagram::pair()?;
+        link.set_nonblocking(true)?;
+        worker_link.set_nonblocking(true)?;
+        let (alive, dead) = UnixStream::pair()?;
+        let mut rng = Rng::new(config.seed);
+        let worker = Worker {
+            dev: Box::new(dev),
+            link: worker_link,
+            dead,
+            inbound: Pipeline::new(config.inbound, rng.fork()),
+            outbound: Pipeline::new(config.outbound, rng.fork()),
+            scheduled: BinaryHeap::new(),
+            next: 0,
+        };
+        thread::spawn(move || {
+            if let Err(e) = worker.run() {
+                eprintln!("impaired link went down: {:?}", e);
+            }
+        });
+        Ok(Impaired {
+            link,
+            _alive: alive,
+        })
+    }
+}
+
+impl Device for Impaired {
+    fn send(&mut self, frame: &[u8]) -> io::Result<usize> {
+        match self.link.send(frame) {
+            // the worker is behind, which is just more loss
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(frame.len()),
+            r => r,
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.link.recv(buf)
+    }
+}
+
+impl AsRawFd for Impaired {
+    fn as_raw_fd(&self) -> RawFd {
+        self.link.as_raw_fd()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Direction {
+    Inbound,
+    Outbound,
+}
+
+// when it's due, the order it was scheduled in, and which way it's going
+type Scheduled = (time::Instant, u64, Direction, Vec<u8>);
+
+struct Worker {
+    dev: Box<dyn Device>,
+    link: UnixDatagram,
+    dead: UnixStream,
+    inbound: Pipeline,
+    outbound: Pipeline,
+    // packets on their way, soonest first. the counter keeps packets due at the same time in
+    // the order they were scheduled.
+    scheduled: BinaryHeap<Reverse<Scheduled>>,
+    next: u64,
+}
+
+impl Worker {
+    fn run(mut self) -> io::Result<()> {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let now = time::Instant::now();
+            for (direction, packet) in self
+                .inbound
+                .flush(now)
+                .into_iter()
+                .map(|p| (Direction::Inbound, p))
+                .chain(
+                    self.outbound
+                        .flush(now)
+                        .into_iter()
+                        .map(|p| (Direction::Outbound, p)),
+                )
+                .collect::<Vec<_>>()
+            {
+                self.schedule(direction, packet, now);
+            }
+            while let Some(Reverse((due, ..))) = self.scheduled.peek() {
+                if *due > now {
+                    break;
+                }
+                let Reverse((_, _, direction, packet)) = self.scheduled.pop().unwrap();
+                match direction {
+                    Direction::Inbound => match self.link.send(&packet) {
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        r => drop(r?),
+                    },
+                    Direction::Outbound => drop(self.dev.send(&packet)?),
+                }
+            }
+
+            let wake = [
+                self.scheduled.peek().map(|Reverse((due, ..))| *due),
+                self.inbound.wake(),
+                self.outbound.wake(),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            let timeout = match wake {
+                // rounded up, or we'd spin until it's due
+                Some(t) => {
+                    let ms = (t.saturating_duration_since(now).as_micros() as u64).div_ceil(1000);
+                    PollTimeout::try_from(ms).unwrap_or(PollTimeout::MAX)
+                }
+                None => PollTimeout::NONE,
+            };
+            // the device is ours for as long as the worker runs
+            let dev = unsafe { BorrowedFd::borrow_raw(self.dev.as_raw_fd()) };
+            let mut pfd = [
+                PollFd::new(dev, PollFlags::POLLIN),
+                PollFd::new(self.link.as_fd(), PollFlags::POLLIN),
+                PollFd::new(self.dead.as_fd(), PollFlags::POLLIN),
+            ];
+            nix::poll::poll(&mut pfd[..], timeout)?;
+            let ready = pfd.map(|p| p.revents().is_some_and(|r| !r.is_empty()));
+            if ready[2] {
+                // whoever had the Impaired is gone, or said something they shouldn't have
+                let _ = self.dead.read(&mut buf);
+                return Ok(());
+            }
+            let now = time::Instant::now();
+            if ready[0] {
+                let n = self.dev.recv(&mut buf)?;
+                for p in self.inbound.take(&buf[..n], now) {
+                    self.schedule(Direction::Inbound, p, now);
+                }
+            }
+            if ready[1] {
+                if let Ok(n) = self.link.recv(&mut buf) {
+                    for p in self.outbound.take(&buf[..n], now) {
+                        self.schedule(Direction::Outbound, p, now);
+                    }
+                }
+            }
+        }
+    }
+
+    fn schedule(&mut self, direction: Direction, packet: Vec<u8>, now: time::Instant) {
+        let pipeline = match direction {
+            Direction::Inbound => &mut self.inbound,
+            Direction::Outbound => &mut self.outbound,
+        };
+        let due = pipeline.due(packet.len(), now);
+        self.scheduled
+            .push(Reverse((due, self.next, direction, packet)));
+        self.next += 1;
+    }
+}
+
+struct Pipeline {
+    config: Impairment,
+    rng: Rng,
+    // whether the Gilbert-Elliott channel is in its bad state
+    bad: bool,
+    // packets held back so others can overtake them, and since when
+    held: VecDeque<(time::Instant, Vec<u8>)>,
+    // when the bandwidth cap lets the next packet go
+    free_at: Option<time::Instant>,
+}
+
+impl Pipeline {
+    fn new(config: Impairment, rng: Rng) -> Self {
+        Pipeline {
+            config,
+            rng,
+            bad: false,
+            held: VecDeque::new(),
+            free_at: None,
+        }
+    }
+
+    // the packets that make it past loss, duplication and reordering right now
+    fn take(&mut self, packet: &[u8], now: time::Instant) -> Vec<Vec<u8>> {
+        if self.lost() {
+            return Vec::new();
+        }
+        let copies = if self.rng.chance(self.config.duplicate) {
+            2
+        } else {
+            1
+        };
+        if self.config.reorder == 0 {
+            return vec![packet.to_vec(); copies];
+        }
+        let mut out = Vec::new();
+        for _ in 0..copies {
+            self.held.push_back((now, packet.to_vec()));
+            if self.held.len() > self.config.reorder {
+                // any of them may go first
+                let i = self.rng.below(self.held.len() as u64) as usize;
+                out.push(self.held.remove(i).unwrap().1);
+            }
+        }
+        out
+    }
+
+    // packets that were held back long enough
+    fn flush(&mut self, now: time::Instant) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        while self
+            .held
+            .front()
+            .is_some_and(|(t, _)| now.duration_since(*t) >= REORDER_HOLD)
+        {
+            out.push(self.held.pop_front().unwrap().1);
+        }
+        out
+    }
+
+    fn wake(&self) -> Option<time::Instant> {
+        self.held.front().map(|(t, _)| *t + REORDER_HOLD)
+    }
+
+    fn lost(&mut self) -> bool {
+        let Some(ge) = self.config.burst_loss else {
+            return self.rng.chance(self.config.loss);
+        };
+        let lost = self
+            .rng
+            .chance(if self.bad { ge.loss_bad } else { ge.loss_good });
+        self.bad = if self.bad {
+            !self.rng.chance(ge.r)
+        } else {
+            self.rng.chance(ge.p)
+        };
+        lost
+    }
+
+    // when a packet of len bytes that's ready to go now comes out the other end
+    fn due(&mut self, len: usize, now: time::Instant) -> time::Instant {
+        let mut sent = now;
+        if let Some(rate) = self.config.rate {
+            // it has to wait for everything queued before it to go out first
+            let start = self.free_at.map_or(now, |t| t.max(now));
+            sent = start + time::Duration::from_secs_f64(len as f64 / rate as f64);
+            self.free_at = Some(sent);
+        }
+        let jitter = self.config.jitter.as_nanos() as u64;
+        let jitter = if jitter == 0 {
+            0
+        } else {
+            self.rng.below(jitter + 1)
+        };
+        sent + self.config.latency + time::Duration::from_nanos(jitter)
+    }
+}
+
+// splitmix64, good enough to decide which packets to mess with and small enough to not need a
+// dependency for
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // an independent stream for something else
+    fn fork(&mut self) -> Rng {
+        Rng(self.next())
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
@@ -0,0 +1,127 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// the largest packet we send
+pub(crate) const MTU: usize = 1500;
+
+// one of our addresses, and how long the prefix of the network it's on is
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Cidr {
+    pub(crate) addr: IpAddr,
+    pub(crate) prefix: u8,
+}
+
+impl Cidr {
+    // the address with the host part cleared
+    pub(crate) fn network(&self) -> IpAddr {
+        let host = |bits: u32| bits.saturating_sub(self.prefix as u32);
+        match self.addr {
+            IpAddr::V4(a) => {
+                let mask = u32::MAX.checked_shl(host(32)).unwrap_or(0);
+                Ipv4Addr::from(u32::from(a) & mask).into()
+            }
+            IpAddr::V6(a) => {
+                let mask = u128::MAX.checked_shl(host(128)).unwrap_or(0);
+                Ipv6Addr::from(u128::from(a) & mask).into()
+            }
+        }
+    }
+
+    // the directed broadcast address of an IPv4 network, if it's big enough to have one
+    fn broadcast(&self) -> Option<IpAddr> {
+        match self.addr {
+            IpAddr::V4(a) if self.prefix < 31 => {
+                let host = u32::MAX >> self.prefix;
+                Some(Ipv4Addr::from(u32::from(a) | host).into())
+            }
+            _ => None,
+        }
+    }
+}
+
+// whether a packet sent to dst is for an interface with the given addresses. one that hasn't
+// been given any takes whatever the device hands it.
+pub(crate) fn is_for_us(ours: &[Cidr], dst: IpAddr) -> bool {
+    if ours.is_empty() || dst.is_multicast() {
+        return true;
+    }
+    if let IpAddr::V4(a) = dst {
+        if a.is_broadcast() {
+            return true;
+        }
+    }
+    ours.iter()
+        .any(|c| c.addr == dst || c.broadcast() == Some(dst))
+}
+
+// the IP header we put in front of everything we send, for either address family
+pub(crate) enum IpHeader {
+    V4(etherparse::Ipv4Header),
+    V6(etherparse::Ipv6Header),
+}
+
+impl IpHeader {
+    pub(crate) fn new(src: IpAddr, dst: IpAddr, protocol: etherparse::IpNumber) -> Self {
+        match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => IpHeader::V4(
+                etherparse::Ipv4Header::new(0, 64, protocol, src.octets(), dst.octets()).unwrap(),
+            ),
+            (IpAddr::V6(src), IpAddr::V6(dst)) => IpHeader::V6(etherparse::Ipv6Header {
+                traffic_class: 0,
+                flow_label: etherparse::Ipv6FlowLabel::ZERO,
+                payload_length: 0,
+                next_header: protocol,
+                hop_limit: 64,
+                source: src.octets(),
+                destination: dst.octets(),
+            }),
+            _ => unreachable!("source and destination are from different address families"),
+        }
+    }
+
+    // IPv6 routers never fragment, so this only matters for IPv4
+    pub(crate) fn set_dont_fragment(&mut self) {
+        if let IpHeader::V4(ip) = self {
+            ip.dont_fragment = true;
+        }
+    }
+
+    pub(crate) fn header_len(&self) -> usize {
+        match self {
+            IpHeader::V4(ip) => ip.header_len(),
+            IpHeader::V6(ip) => ip.header_len(),
+        }
+    }
+
+    pub(crate) fn set_payload_len(&mut self, len: usize) {
+        match self {
+            IpHeader::V4(ip) => ip.set_payload_len(len),
+            IpHeader::V6(ip) => ip.set_payload_length(len),
+        }
+        .expect("payload always fits in an ip packet");
+    }
+
+    pub(crate) fn write<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            IpHeader::V4(ip) => ip.write(w),
+            IpHeader::V6(ip) => ip.write(w),
+        }
+    }
+
+    // the checksum of a tcp segment, including the pseudo-header for our address family
+    pub(crate) fn tcp_checksum(&self, tcp: &etherparse::TcpHeader, payload: &[u8]) -> u16 {
+        match self {
+            IpHeader::V4(ip) => tcp.calc_checksum_ipv4(ip, payload),
+            IpHeader::V6(ip) => tcp.calc_checksum_ipv6(ip, payload),
+        }
+        .expect("Failed")
+    }
+
+    pub(crate) fn udp_checksum(&self, udp: &etherparse::UdpHeader, payload: &[u8]) -> u16 {
+        match self {
+            IpHeader::V4(ip) => udp.calc_checksum_ipv4(ip, payload),
+            IpHeader::V6(ip) => udp.calc_checksum_ipv6(ip, payload),
+        }
+        .expect("payload always fits in a udp datagram")
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::IpAddr;
+use std::time;
+
+use siphasher::sip::SipHasher24;
+
+use crate::Quad;
+
+/*
+  Initial sequence numbers (RFC 6528)
+
+      ISN = M + F(localip, localport, remoteip, remoteport, secretkey)
+
+  M is a clock that ticks every 4 microseconds, so a new incarnation of a connection starts
+  past where the old one left off, and F is a keyed hash so nobody off-path can guess where
+  a connection starts. F is SipHash-2-4 keyed with the secret, which RFC 6528 suggests and
+  which, unlike std's hasher, gives the same answer on every build.
+*/
+
+pub(crate) struct Isn {
+    secret: [u8; 16],
+    epoch: time::Instant,
+}
+
+impl Default for Isn {
+    fn default() -> Self {
+        // std seeds every RandomState from the OS, so that's as random as a key gets here
+        let s = RandomState::new();
+        let (a, b) = (s.hash_one(0u8), s.hash_one(1u8));
+        let mut secret = [0u8; 16];
+        secret[..8].copy_from_slice(&a.to_ne_bytes());
+        secret[8..].copy_from_slice(&b.to_ne_bytes());
+        Isn::with_secret(secret, time::Instant::now())
+    }
+}
+
+impl Isn {
+    // for when the secret can't be random, like when a run has to be reproduced. M counts
+    // from epoch, so on a ManualClock that's the clock's time.
+    pub(crate) fn with_secret(secret: [u8; 16], epoch: time::Instant) -> Self {
+        Isn { secret, epoch }
+    }
+
+    pub(crate) fn generate(&self, quad: &Quad, now: time::Instant) -> u32 {
+        let m = (now.duration_since(self.epoch).as_micros() / 4) as u32;
+        m.wrapping_add(self.hash(quad))
+    }
+
+    fn hash(&self, quad: &Quad) -> u32 {
+        let mut h = SipHasher24::new_with_key(&self.secret);
+        // ours, then theirs, as RFC 6528 has it
+        for (ip, port) in [quad.dst, quad.src] {
+            match ip {
+                IpAddr::V4(a) => h.write(&a.octets()),
+                IpAddr::V6(a) => h.write(&a.octets()),
+            }
+            h.write(&port.to_be_bytes());
+        }
+        h.finish() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const QUAD: Quad = Quad {
+        src: (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 40000),
+        dst: (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 8080),
+    };
+
+    // the same secret gives the same ISNs on every build, or recorded runs wouldn't replay
+    #[test]
+    fn stable() {
+        let epoch = time::Instant::now();
+        let isn = Isn::with_secret(*b"replay handshake", epoch);
+        assert_eq!(isn.generate(&QUAD, epoch), 3108708659);
+        // and M moves it along by one every 4 microseconds
+        assert_eq!(
+            isn.generate(&QUAD, epoch + time::Duration::from_micros(40)),
+            3108708669
+        );
+        let other = Isn::with_secret([0; 16], epoch);
+        assert_ne!(other.generate(&QUAD, epoch), isn.generate(&QUAD, epoch));
+    }
+}
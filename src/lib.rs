@@ -1,95 +1,636 @@
-use std::collections::{HashMap, VecDeque};
+pub use builder::InterfaceBuilder;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use device::{virtual_link, Device, VirtualDevice};
+pub use impair::{GilbertElliott, Impaired, Impairment, Impairments};
+use link::Nic;
+pub use readiness::Readiness;
+pub use replay::{replay, Replay, ReplayDevice};
+use std::collections::hash_map::{Entry, VacantEntry};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::prelude::*;
 use std::io::{self, ErrorKind};
-use std::net::Ipv4Addr;
-use std::sync::{Arc, Condvar, Mutex};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::Waker;
 use std::thread;
-use tcp::State;
+use std::time;
 use tun_tap::Mode;
+mod asyncio;
+mod builder;
+mod clock;
+mod device;
+mod event;
+mod icmp;
+mod impair;
+mod ip;
+mod isn;
+mod link;
+mod netlink;
+mod pcap;
+mod pmtu;
+mod readiness;
+mod reassembly;
+mod replay;
+mod syncookie;
 pub mod tcp;
+mod timer;
+mod udp;
 
-const SENQ_QEUEU_SIZE: usize = 1024;
+// how many established connections a listener holds on to until they're accepted, and by
+// default also how many it lets sit in SYN-RECEIVED
+const DEFAULT_BACKLOG: usize = 128;
+// how often packet_loop looks at the time when it runs on a clock other than the system's
+const MANUAL_CLOCK_TICK: time::Duration = time::Duration::from_millis(1);
+// where connect() picks local ports from, the IANA dynamic range (RFC 6335)
+const EPHEMERAL_PORTS_START: u16 = 49152;
+const EPHEMERAL_PORTS: u16 = u16::MAX - EPHEMERAL_PORTS_START + 1;
 
-#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Quad {
-    src: (Ipv4Addr, u16),
-    dst: (Ipv4Addr, u16),
+    src: (IpAddr, u16),
+    dst: (IpAddr, u16),
 }
 
-#[derive(Default)]
 struct Foobar {
     manager: Mutex<ConnectionManager>,
     pending_var: Condvar,
     rcv_var: Condvar,
+    snd_var: Condvar,
+    // set up once somebody asks for readiness notifications
+    notifier: Mutex<Option<readiness::Notifier>>,
+    // gets packet_loop going when there's something for it to do
+    wakeup: event::Wakeup,
 }
 type InterfaceHandle = Arc<Foobar>;
 
+// what a socket can be waiting for, the data to read, room to write, or a connection to
+// accept
+#[derive(Clone, Copy)]
+enum Wait {
+    Rcv,
+    Snd,
+    Pending,
+}
+
+// whose waker it is. a stream waits for its own data and room, a listener for its own
+// connections, so packet_loop only wakes the task the news is for.
+#[derive(Clone, Copy, Hash, Eq, PartialEq)]
+enum Socket {
+    Stream(Quad),
+    Listener(u16),
+    Udp(u16),
+}
+
+// the tasks to wake up once what they're waiting for may have happened, one per socket
+#[derive(Default)]
+struct Wakers {
+    rcv: HashMap<Socket, Waker>,
+    snd: HashMap<Socket, Waker>,
+    pending: HashMap<Socket, Waker>,
+}
+
+impl Wakers {
+    fn list(&mut self, w: Wait) -> &mut HashMap<Socket, Waker> {
+        match w {
+            Wait::Rcv => &mut self.rcv,
+            Wait::Snd => &mut self.snd,
+            Wait::Pending => &mut self.pending,
+        }
+    }
+
+    fn register(&mut self, w: Wait, s: Socket, waker: &Waker) {
+        // a future gets polled again and again with the same waker until it's done. the
+        // socket is borrowed mutably while it's polled, so there's only ever one task
+        // waiting on it for w, the one that polled it last.
+        match self.list(w).entry(s) {
+            Entry::Occupied(mut o) => {
+                if !o.get().will_wake(waker) {
+                    o.insert(waker.clone());
+                }
+            }
+            Entry::Vacant(v) => {
+                v.insert(waker.clone());
+            }
+        }
+    }
+}
+
+impl Foobar {
+    // tells packet_loop there's something to send, or that it's time to go
+    fn kick(&self) {
+        self.wakeup.wake();
+    }
+
+    fn var(&self, w: Wait) -> &Condvar {
+        match w {
+            Wait::Rcv => &self.rcv_var,
+            Wait::Snd => &self.snd_var,
+            Wait::Pending => &self.pending_var,
+        }
+    }
+
+    // wakes up whoever waits for w on s, blocked or async, and whoever polls the interface's
+    // readiness fd. the manager must not be locked.
+    fn wake(&self, w: Wait, s: Socket) {
+        self.var(w).notify_all();
+        let waker = self.manager.lock().unwrap().wakers.list(w).remove(&s);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        if let Some(n) = &*self.notifier.lock().unwrap() {
+            n.notify();
+        }
+    }
+
+    // waits for w until woken up, but no longer than until deadline if there is one
+    fn wait<'a>(
+        &self,
+        w: Wait,
+        cm: MutexGuard<'a, ConnectionManager>,
+        deadline: Option<time::Instant>,
+    ) -> io::Result<MutexGuard<'a, ConnectionManager>> {
+        let var = self.var(w);
+        let Some(deadline) = deadline else {
+            return Ok(var.wait(cm).unwrap());
+        };
+        let left = deadline.saturating_duration_since(time::Instant::now());
+        if left.is_zero() {
+            // like std on unix
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out"));
+        }
+        Ok(var.wait_timeout(cm, left).unwrap().0)
+    }
+}
+
+// whether the connection to quad is done with its handshake. one that failed is forgotten.
+fn connected(cm: &mut ConnectionManager, quad: &Quad) -> io::Result<bool> {
+    let c = cm.connections.get(quad).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "stream was terminated unexpectedly",
+        )
+    })?;
+    if c.is_connecting() {
+        return Ok(false);
+    }
+    if let Some(e) = c.error() {
+        cm.connections.remove(quad);
+        return Err(e);
+    }
+    Ok(true)
+}
+
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "operation would block")
+}
+
+// timeouts are in real time, whatever clock the interface runs its timers on
+fn deadline(timeout: &Mutex<Option<time::Duration>>) -> Option<time::Instant> {
+    timeout.lock().unwrap().map(|t| time::Instant::now() + t)
+}
+
+fn set_timeout(
+    timeout: &Mutex<Option<time::Duration>>,
+    dur: Option<time::Duration>,
+) -> io::Result<()> {
+    if dur.is_some_and(|d| d.is_zero()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
+    *timeout.lock().unwrap() = dur;
+    Ok(())
+}
+
 pub struct TcpListener {
     port: u16,
     h: InterfaceHandle,
+    nonblocking: AtomicBool,
+    accept_timeout: Mutex<Option<time::Duration>>,
 }
 pub struct Interface {
     ih: Option<InterfaceHandle>,
-    jh: Option<thread::JoinHandle<io::Result<()>>>,
+    jh: Option<thread::JoinHandle<(Nic, io::Result<()>)>>,
+    capture: link::Capture,
+    // what we set up on the device in the kernel, if anything
+    device: Option<netlink::Configured>,
 }
 
 impl Drop for Interface {
     fn drop(&mut self) {
-        self.ih.as_mut().unwrap().manager.lock().unwrap().terminated = true;
-        drop(self.ih.take());
-        self.jh
-            .take()
-            .expect("interface droped more than once")
-            .join()
-            .unwrap()
-            .unwrap();
+        // packet_loop goes first, it has no business with a device that's being taken apart
+        let ih = self.ih.take().unwrap();
+        ih.manager.lock().unwrap().terminated = true;
+        ih.kick();
+        drop(ih);
+        let jh = self.jh.take().expect("interface droped more than once");
+        // a drop has nobody to return an error to, and panicking here takes the caller with it
+        let nic = match jh.join() {
+            Ok((nic, r)) => {
+                if let Err(e) = r {
+                    eprintln!("packet loop failed {:?}", e);
+                }
+                Some(nic)
+            }
+            Err(_) => {
+                eprintln!("packet loop panicked");
+                None
+            }
+        };
+        // while the device is still there to take it off
+        drop(self.device.take());
+        drop(nic);
     }
 }
 #[derive(Default)]
 struct ConnectionManager {
     terminated: bool,
+    clock: clock::SharedClock,
     connections: HashMap<Quad, tcp::Connection>,
-    pending: HashMap<u16, VecDeque<Quad>>,
+    // what new connections go by
+    config: tcp::Config,
+    // when each of them has to be ticked next
+    timers: timer::TimerQueue,
+    listeners: HashMap<u16, Listener>,
+    syn_cookies: syncookie::SynCookies,
+    isn: isn::Isn,
+    // the ephemeral port to try next
+    next_port: u16,
+    icmp: icmp::Icmp,
+    udp: HashMap<u16, udp::Socket>,
+    // the addresses we send from when nobody sent to us first. tun hands us whatever the kernel
+    // routes our way, so unless we're told, we go by where packets for our ports were sent.
+    local_addrs: Vec<IpAddr>,
+    // the addresses we were set up with. with any, packets for other addresses are dropped.
+    addrs: Vec<ip::Cidr>,
+    wakers: Wakers,
 }
 
-fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
-    let mut buf = [0u8; 1504];
-    loop {
-        //we want to read from the nic, but we want to make sure that we'll wake up when the next
-        //timer has to be triggered
-        use std::os::unix::io::AsRawFd;
-        let mut pfd = [nix::poll::PollFd::new(
-            nic.as_raw_fd(),
-            nix::poll::EventFlags::POLLIN,
-        )];
-        // poll must be changed to epoll(or anything else)
-        // nix crate is so old and must changed
-        let n = nix::poll::poll(&mut pfd[..], 1).map_err(|e| e.as_errno().unwrap())?;
-        assert_ne!(n, -1);
-        if n == 0 {
-            let mut cmg = ih.manager.lock().unwrap();
-            for connection in cmg.connections.values_mut() {
-                // TODO: don't die on errors
-                connection.on_tick(&mut nic);
+impl ConnectionManager {
+    fn with_clock(clock: clock::SharedClock) -> Self {
+        ConnectionManager {
+            clock,
+            ..Default::default()
+        }
+    }
+
+    // has packet_loop tick the connection to quad as soon as it gets to it
+    fn tick_soon(&mut self, quad: Quad) {
+        let now = self.clock.now();
+        self.timers.schedule(quad, now);
+    }
+
+    // which of our addresses to talk to peer from
+    fn local_addr_for(&self, peer: IpAddr) -> Option<IpAddr> {
+        self.local_addrs
+            .iter()
+            .find(|a| a.is_ipv4() == peer.is_ipv4())
+            .copied()
+    }
+
+    // a local port nobody listens on and that isn't already talking to remote
+    fn ephemeral_port(&mut self, local: IpAddr, remote: (IpAddr, u16)) -> Option<u16> {
+        for _ in 0..EPHEMERAL_PORTS {
+            let port = EPHEMERAL_PORTS_START + self.next_port % EPHEMERAL_PORTS;
+            self.next_port = self.next_port.wrapping_add(1);
+            let q = Quad {
+                src: remote,
+                dst: (local, port),
+            };
+            if !self.listeners.contains_key(&port) && !self.connections.contains_key(&q) {
+                return Some(port);
+            }
+        }
+        None
+    }
+}
+
+fn learn_local_addr(addrs: &mut Vec<IpAddr>, addr: IpAddr) {
+    if addr.is_multicast() || addrs.iter().any(|a| a.is_ipv4() == addr.is_ipv4()) {
+        return;
+    }
+    addrs.push(addr);
+}
+
+// what a listener does with a connection it has no room for
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Overflow {
+    // ignore the segment, the peer will try again later and maybe there's room by then
+    Drop,
+    // refuse the connection with a RST
+    Reset,
+}
+
+struct Listener {
+    // connections to this port that are still in SYN-RECEIVED
+    syn_queue: HashSet<Quad>,
+    syn_backlog: usize,
+    // established connections waiting for accept()
+    accept_queue: VecDeque<Quad>,
+    backlog: usize,
+    overflow: Overflow,
+    // once the SYN queue is full, answer new SYNs with a cookie instead of applying overflow
+    syn_cookies: bool,
+    // when we last answered a SYN with a cookie, so we know whether to bother checking ACKs
+    cookie_sent_at: Option<time::Instant>,
+    // what the connections it accepts go by
+    config: tcp::Config,
+}
+
+impl Listener {
+    // handle a segment for this port that doesn't belong to any connection we know of. returns
+    // whether a connection became ready to be accepted.
+    fn on_segment<'a>(
+        &mut self,
+        nic: &mut Nic,
+        cookies: &syncookie::SynCookies,
+        isn: &isn::Isn,
+        e: VacantEntry<Quad, tcp::Connection>,
+        tcph: etherparse::TcpHeaderSlice<'a>,
+        data: &'a [u8],
+    ) -> io::Result<bool> {
+        let q = *e.key();
+        if tcph.rst() {
+            return Ok(false);
+        }
+
+        if tcph.ack() {
+            if self
+                .cookie_sent_at
+                .is_some_and(|t| cookies.may_be_valid(t, nic.now()))
+            {
+                // may be the end of a handshake we sent a cookie for
+                let iss = tcph.acknowledgment_number().wrapping_sub(1);
+                let irs = tcph.sequence_number().wrapping_sub(1);
+                if let Some(mss) = cookies.check(&q, irs, iss, nic.now()) {
+                    if self.accept_queue.len() >= self.backlog {
+                        return self.overflow(nic, &q, tcph, data);
+                    }
+                    let c = e.insert(tcp::Connection::from_cookie(
+                        &q,
+                        &self.config,
+                        tcph.clone(),
+                        iss,
+                        mss,
+                    ));
+                    c.on_packet(nic, tcph, data)?;
+                    self.accept_queue.push_back(q);
+                    return Ok(true);
+                }
+            }
+            // RFC 793: an ACK for a LISTEN socket is always bad
+            tcp::send_reset(nic, &q, tcph, data)?;
+            return Ok(false);
+        }
+
+        if !tcph.syn() {
+            return Ok(false);
+        }
+
+        if self.accept_queue.len() >= self.backlog {
+            return self.overflow(nic, &q, tcph, data);
+        }
+
+        if self.syn_queue.len() >= self.syn_backlog {
+            if !self.syn_cookies {
+                return self.overflow(nic, &q, tcph, data);
+            }
+            // don't keep any state for this one, only send the SYN-ACK
+            let now = nic.now();
+            let iss = cookies.generate(&q, tcph.sequence_number(), tcp::peer_mss(&tcph), now);
+            tcp::Connection::accept(nic, &q, tcph, data, iss, &self.config)?;
+            self.cookie_sent_at = Some(now);
+            return Ok(false);
+        }
+
+        let iss = isn.generate(&q, nic.now());
+        if let Some(c) = tcp::Connection::accept(nic, &q, tcph, data, iss, &self.config)? {
+            e.insert(c);
+            self.syn_queue.insert(q);
+        }
+        Ok(false)
+    }
+
+    fn overflow<'a>(
+        &self,
+        nic: &mut Nic,
+        q: &Quad,
+        tcph: etherparse::TcpHeaderSlice<'a>,
+        data: &'a [u8],
+    ) -> io::Result<bool> {
+        if let Overflow::Reset = self.overflow {
+            tcp::send_reset(nic, q, tcph, data)?;
+        }
+        Ok(false)
+    }
+}
+
+// everything that's due, and whatever the application left for us to do. returns when it's
+// next due, if nothing happens until then.
+fn tick(
+    nic: &mut Nic,
+    ih: &InterfaceHandle,
+    fragments: &mut reassembly::Reassembly,
+) -> Option<time::Instant> {
+    let mut cmg = ih.manager.lock().unwrap();
+    let cm = &mut *cmg;
+    let mut aborted = Vec::new();
+    // only the connections that are due, the rest have nothing to do yet
+    for q in cm.timers.due(nic.now()) {
+        let Some(connection) = cm.connections.get_mut(&q) else {
+            // gone since it was scheduled
+            continue;
+        };
+        let was_closed = connection.is_closed();
+        if let Err(e) = connection.on_tick(nic) {
+            eprintln!("tick failed {:?}", e);
+        }
+        if !connection.is_closed() {
+            if let Some(t) = connection.next_timeout() {
+                cm.timers.schedule(q, t);
             }
             continue;
         }
-        assert_eq!(1, n);
-        // TODO: set timeout for this recv for TCP timers or ConnectionManager::terminate
-        let nbytes = nic.recv(&mut buf[..])?;
-        match etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]) {
+        if connection.orphaned {
+            // its TcpStream is gone, so nobody is waiting to hear about it
+            cm.connections.remove(&q);
+            continue;
+        }
+        if was_closed {
+            continue;
+        }
+        aborted.push(q);
+        // a handshake that timed out has nobody to tell. anything else sticks around
+        // until its TcpStream is dropped, so the error can be reported.
+        if cm
+            .listeners
+            .get_mut(&q.dst.1)
+            .is_some_and(|l| l.syn_queue.remove(&q))
+        {
+            cm.connections.remove(&q);
+        }
+    }
+    let next = cm.timers.next();
+    // sockets whose send queue we emptied, someone may be waiting for room
+    let mut drained = Vec::new();
+    for (&port, s) in cm.udp.iter_mut() {
+        if !s.has_unsent() {
+            continue;
+        }
+        if let Err(e) = s.on_tick(nic) {
+            eprintln!("udp send failed {:?}", e);
+        }
+        drained.push(port);
+    }
+    drop(cmg);
+    fragments.expire(nic.now());
+    if let Err(e) = nic.on_tick() {
+        eprintln!("link tick failed {:?}", e);
+    }
+    for q in aborted {
+        ih.wake(Wait::Rcv, Socket::Stream(q));
+        ih.wake(Wait::Snd, Socket::Stream(q));
+    }
+    for port in drained {
+        ih.wake(Wait::Snd, Socket::Udp(port));
+    }
+    let next = earliest(next, fragments.next_expiry());
+    earliest(next, nic.next_timeout())
+}
+
+fn earliest(a: Option<time::Instant>, b: Option<time::Instant>) -> Option<time::Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn packet_loop(nic: &mut Nic, ih: InterfaceHandle) -> io::Result<()> {
+    let (mtu, ours) = {
+        let cm = ih.manager.lock().unwrap();
+        (cm.config.mtu, cm.addrs.clone())
+    };
+    let mut buf = vec![0u8; mtu + link::ETHERNET_HEADER_LEN];
+    // only ever touched by this thread, so it can live outside the connection manager
+    let mut fragments = reassembly::Reassembly::default();
+    let mut events = event::EventLoop::new(nic.as_raw_fd(), ih.wakeup.clone())?;
+    let real_clock = ih.manager.lock().unwrap().clock.is_real();
+    loop {
+        if ih.manager.lock().unwrap().terminated {
+            return Ok(());
+        }
+        let next = tick(nic, &ih, &mut fragments);
+        let mut timeout = next.map(|t| t.saturating_duration_since(nic.now()));
+        if !real_clock {
+            // we can't tell when a clock like that gets to a deadline, so keep looking
+            timeout = Some(timeout.map_or(MANUAL_CLOCK_TICK, |t| t.min(MANUAL_CLOCK_TICK)));
+        }
+        if !events.wait(timeout)? {
+            continue;
+        }
+        let Some(packet) = nic.recv(&mut buf[..])? else {
+            // something for the link layer
+            continue;
+        };
+        // tun gives us both IPv4 and IPv6, the version nibble tells them apart
+        match etherparse::IpSlice::from_slice(packet) {
             Ok(iph) => {
                 let src = iph.source_addr();
                 let dst = iph.destination_addr();
-                // 0x06 is tcp
-                if iph.protocol() != etherparse::IpNumber::TCP {
-                    // not tcp
+                if !ip::is_for_us(&ours, dst) {
+                    // the kernel routed it our way, but it isn't addressed to us
+                    eprintln!("ignoring packet for {}", dst);
                     continue;
                 }
-                match etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..nbytes]) {
+                let whole;
+                let payload = match &iph {
+                    _ if !iph.is_fragmenting_payload() => iph.payload().payload,
+                    etherparse::IpSlice::Ipv4(v4) => {
+                        match fragments.on_fragment(&v4.header(), iph.payload().payload, nic.now())
+                        {
+                            Some(p) => {
+                                whole = p;
+                                &whole[..]
+                            }
+                            None => continue,
+                        }
+                    }
+                    etherparse::IpSlice::Ipv6(_) => {
+                        // TODO: IPv6 reassembly. we never send anything that big, so neither
+                        // should our peers.
+                        continue;
+                    }
+                };
+                match iph.payload_ip_number() {
+                    // 0x06 is tcp
+                    etherparse::IpNumber::TCP => {}
+                    etherparse::IpNumber::UDP => {
+                        let Some((udph, data)) = udp::parse(src, dst, payload) else {
+                            continue;
+                        };
+                        let mut cmg = ih.manager.lock().unwrap();
+                        let cm = &mut *cmg;
+                        let Some(s) = cm.udp.get_mut(&udph.destination_port()) else {
+                            cm.icmp.port_unreachable(nic, src, dst, packet)?;
+                            continue;
+                        };
+                        if !s.deliver(SocketAddr::new(src, udph.source_port()), data) {
+                            // the application isn't keeping up, same as a full socket buffer
+                            continue;
+                        }
+                        learn_local_addr(&mut cm.local_addrs, dst);
+                        drop(cmg);
+                        ih.wake(Wait::Rcv, Socket::Udp(udph.destination_port()));
+                        continue;
+                    }
+                    etherparse::IpNumber::ICMP | etherparse::IpNumber::IPV6_ICMP => {
+                        let mut cmg = ih.manager.lock().unwrap();
+                        let cm = &mut *cmg;
+                        let err = if iph.payload_ip_number() == etherparse::IpNumber::ICMP {
+                            cm.icmp.on_packet(nic, src, dst, payload)?
+                        } else {
+                            cm.icmp.on_packet_v6(src, dst, payload)?
+                        };
+                        let Some(err) = err else {
+                            continue;
+                        };
+                        let Some(c) = cm.connections.get_mut(&err.quad) else {
+                            continue;
+                        };
+                        match err.problem {
+                            icmp::Problem::Unreachable { hard, kind } => {
+                                c.on_icmp_error(err.seq, hard, kind)
+                            }
+                            icmp::Problem::TooBig { mtu } => {
+                                c.on_icmp_too_big(nic, err.seq, mtu)?
+                            }
+                        }
+                        let closed = c.is_closed();
+                        // it may have a packet to send again, or be gone for good
+                        cm.tick_soon(err.quad);
+                        if closed {
+                            if let Some(l) = cm.listeners.get_mut(&err.quad.dst.1) {
+                                if l.syn_queue.remove(&err.quad) {
+                                    cm.connections.remove(&err.quad);
+                                }
+                            }
+                            drop(cmg);
+                            ih.wake(Wait::Rcv, Socket::Stream(err.quad));
+                            ih.wake(Wait::Snd, Socket::Stream(err.quad));
+                        }
+                        continue;
+                    }
+                    _ => {
+                        // not something we speak
+                        continue;
+                    }
+                }
+                match etherparse::TcpHeaderSlice::from_slice(payload) {
                     Ok(tcph) => {
-                        use std::collections::hash_map::Entry;
-                        let datai = iph.slice().len() + tcph.slice().len();
+                        let data = &payload[tcph.slice().len()..];
                         let mut cmg = ih.manager.lock().unwrap();
                         let cm = &mut *cmg;
                         let q = Quad {
@@ -98,39 +639,81 @@ fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
                         };
                         match cm.connections.entry(q) {
                             Entry::Occupied(mut c) => {
-                                eprintln!("got occ");
-                                let a = c.get_mut().on_packet(
-                                    &mut nic,
-                                    iph,
-                                    tcph,
-                                    &buf[datai..nbytes],
-                                )?;
+                                if c.get().is_closed() {
+                                    // aborted, only waiting for its TcpStream to go away
+                                    if !tcph.rst() {
+                                        tcp::send_reset(nic, &q, tcph, data)?;
+                                    }
+                                    continue;
+                                }
+                                let half_open = c.get().is_half_open();
+                                if half_open && tcph.ack() {
+                                    if let Some(l) = cm.listeners.get(&q.dst.1) {
+                                        if l.accept_queue.len() >= l.backlog {
+                                            // this may complete the handshake, but there'd be
+                                            // nowhere to put the connection afterwards
+                                            if let Overflow::Reset = l.overflow {
+                                                tcp::send_reset(nic, &q, tcph, data)?;
+                                                c.remove();
+                                                cm.listeners
+                                                    .get_mut(&q.dst.1)
+                                                    .unwrap()
+                                                    .syn_queue
+                                                    .remove(&q);
+                                            }
+                                            continue;
+                                        }
+                                    }
+                                }
+                                let connecting = c.get().is_connecting();
+                                let a = c.get_mut().on_packet(nic, tcph, data)?;
+                                // connect() is waiting to hear how the handshake went
+                                let connected = connecting && !c.get().is_connecting();
+                                let mut established = false;
+                                if half_open && !c.get().is_half_open() {
+                                    if let Some(l) = cm.listeners.get_mut(&q.dst.1) {
+                                        l.syn_queue.remove(&q);
+                                        if c.get().is_closed() {
+                                            // reset before the handshake completed
+                                            c.remove();
+                                        } else {
+                                            l.accept_queue.push_back(q);
+                                            established = true;
+                                        }
+                                    }
+                                }
+                                // there may be more it can send now, or it may be done
+                                cm.tick_soon(q);
                                 //TODO: compare before/after
                                 drop(cmg);
-                                if a.contains(tcp::Available::READ) {
-                                    ih.rcv_var.notify_all()
+                                if established {
+                                    ih.wake(Wait::Pending, Socket::Listener(q.dst.1));
+                                }
+                                if connected || a.contains(tcp::Available::READ) {
+                                    ih.wake(Wait::Rcv, Socket::Stream(q));
                                 }
                                 if a.contains(tcp::Available::WRITE) {
-                                    //TODO: ih.snd_var.notify_all
+                                    ih.wake(Wait::Snd, Socket::Stream(q));
                                 }
                             }
 
                             Entry::Vacant(e) => {
-                                if let Some(pending) = cm.pending.get_mut(&tcph.destination_port())
-                                {
-                                    eprintln!("got vacant");
-                                    if let Some(c) = tcp::Connection::accept(
-                                        &mut nic,
-                                        iph,
-                                        tcph,
-                                        &buf[datai..nbytes],
-                                    )? {
-                                        e.insert(c);
-                                        pending.push_back(q);
-                                        // TODO: wake up pending accept()
-                                        drop(cmg);
-                                        ih.pending_var.notify_all();
+                                let Some(l) = cm.listeners.get_mut(&q.dst.1) else {
+                                    // nobody's listening. like CLOSED in RFC 793, answer
+                                    // anything but a RST with a RST
+                                    if !tcph.rst() {
+                                        tcp::send_reset(nic, &q, tcph, data)?;
                                     }
+                                    continue;
+                                };
+                                learn_local_addr(&mut cm.local_addrs, dst);
+                                let established =
+                                    l.on_segment(nic, &cm.syn_cookies, &cm.isn, e, tcph, data)?;
+                                // a new connection has a SYN-ACK that may need sending again
+                                cm.tick_soon(q);
+                                if established {
+                                    drop(cmg);
+                                    ih.wake(Wait::Pending, Socket::Listener(q.dst.1));
                                 }
                             }
                         }
@@ -148,26 +731,232 @@ fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
 }
 
 impl Interface {
+    // tun0, with everything else left to the defaults
     pub fn new() -> io::Result<Self> {
-        let nic = tun_tap::Iface::without_packet_info("tun0", Mode::Tun)?;
-        let ih: InterfaceHandle = Arc::default();
+        InterfaceBuilder::new().build()
+    }
+
+    pub fn builder() -> InterfaceBuilder {
+        InterfaceBuilder::new()
+    }
+
+    // like new, but with the link to tun0 made worse as configured
+    pub fn new_impaired(config: Impairments) -> io::Result<Self> {
+        let iface = tun_tap::Iface::without_packet_info("tun0", Mode::Tun)?;
+        Interface::with_device(Impaired::new(iface, config)?)
+    }
+
+    // a tap device speaks Ethernet, so we get a MAC address of our own, answer ARP requests for
+    // addr and send from it. addr/prefix is the network we can reach directly, everything else
+    // goes through the gateway.
+    pub fn new_tap(
+        name: &str,
+        mac: [u8; 6],
+        addr: Ipv4Addr,
+        prefix: u8,
+        gateway: Option<Ipv4Addr>,
+    ) -> io::Result<Self> {
+        let iface = tun_tap::Iface::without_packet_info(name, Mode::Tap)?;
+        Interface::with_ethernet_device(iface, mac, addr, prefix, gateway)
+    }
+
+    // runs the stack over any device whose frames are bare IP packets
+    pub fn with_device(dev: impl Device + 'static) -> io::Result<Self> {
+        Interface::with_device_and_clock(dev, SystemClock)
+    }
+
+    // like with_device, but all timers go by clock. with a ManualClock, time only passes
+    // when the caller says so.
+    pub fn with_device_and_clock(
+        dev: impl Device + 'static,
+        clock: impl Clock + 'static,
+    ) -> io::Result<Self> {
+        let clock = clock::SharedClock::new(clock);
+        let nic = Nic::ip(Box::new(dev), clock.clone());
+        Interface::with_nic(nic, ConnectionManager::with_clock(clock))
+    }
+
+    // runs the stack over any device whose frames are Ethernet frames, see new_tap
+    pub fn with_ethernet_device(
+        dev: impl Device + 'static,
+        mac: [u8; 6],
+        addr: Ipv4Addr,
+        prefix: u8,
+        gateway: Option<Ipv4Addr>,
+    ) -> io::Result<Self> {
+        let clock = clock::SharedClock::default();
+        let nic = Nic::ethernet(Box::new(dev), mac, addr, prefix, gateway, clock.clone());
+        let mut i = Interface::with_nic(nic, ConnectionManager::with_clock(clock))?;
+        i.add_local_addr(addr.into());
+        Ok(i)
+    }
+
+    fn with_nic(nic: Nic, cm: ConnectionManager) -> io::Result<Self> {
+        let capture = nic.capture.clone();
+        let ih = Arc::new(Foobar {
+            manager: Mutex::new(cm),
+            pending_var: Condvar::new(),
+            rcv_var: Condvar::new(),
+            snd_var: Condvar::new(),
+            notifier: Mutex::new(None),
+            wakeup: event::Wakeup::new()?,
+        });
         let jh = {
             let ih = ih.clone();
-            thread::spawn(move || packet_loop(nic, ih))
+            thread::spawn(move || {
+                let mut nic = nic;
+                let r = packet_loop(&mut nic, ih);
+                // hands the device back, so it's still open while Interface's drop is done
+                // with it
+                (nic, r)
+            })
         };
 
         Ok(Interface {
             ih: Some(ih),
             jh: Some(jh),
+            capture,
+            device: None,
+        })
+    }
+
+    // record every IP packet we send and receive from now on to a pcapng file at path,
+    // replacing any capture already running
+    pub fn capture(&mut self, path: impl AsRef<std::path::Path>) -> io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        self.capture_to(file)
+    }
+
+    // like capture, but into anything that takes bytes
+    pub fn capture_to(&mut self, out: impl Write + Send + 'static) -> io::Result<()> {
+        let clock = self
+            .ih
+            .as_ref()
+            .unwrap()
+            .manager
+            .lock()
+            .unwrap()
+            .clock
+            .clone();
+        let w = pcap::Writer::new(Box::new(out), &clock)?;
+        *self.capture.lock().unwrap() = Some(w);
+        Ok(())
+    }
+
+    pub fn stop_capture(&mut self) {
+        *self.capture.lock().unwrap() = None;
+    }
+
+    // an fd to register with epoll or mio, that's readable whenever a socket on this interface
+    // may be ready. see readiness.rs for how to use it.
+    pub fn readiness(&mut self) -> io::Result<Readiness> {
+        let mut n = self.ih.as_mut().unwrap().notifier.lock().unwrap();
+        if n.is_none() {
+            *n = Some(readiness::Notifier::new()?);
+        }
+        n.as_ref().unwrap().watch()
+    }
+
+    // whether to answer pings, on by default
+    pub fn set_icmp_echo(&mut self, on: bool) {
+        self.ih.as_mut().unwrap().manager.lock().unwrap().icmp.echo = on;
+    }
+
+    // the most echo replies to send per second
+    pub fn set_icmp_echo_rate(&mut self, per_sec: u32) {
+        let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
+        cm.icmp.echo_limit.set_rate(per_sec);
+    }
+
+    // send from addr when talking to peers of its address family that haven't talked to us
+    // first. otherwise we pick whatever address the first packet for one of our ports was
+    // sent to.
+    pub fn add_local_addr(&mut self, addr: IpAddr) {
+        let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
+        cm.local_addrs.retain(|a| a.is_ipv4() != addr.is_ipv4());
+        cm.local_addrs.push(addr);
+    }
+
+    // sends a SYN to addr, the handshake finishes in packet_loop
+    fn start_connect(&mut self, addr: SocketAddr) -> io::Result<Quad> {
+        let ih = self.ih.as_ref().unwrap();
+        let mut cm = ih.manager.lock().unwrap();
+        let local = cm.local_addr_for(addr.ip()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "no local address for that address family",
+            )
+        })?;
+        let remote = (addr.ip(), addr.port());
+        let port = cm
+            .ephemeral_port(local, remote)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "out of ports"))?;
+        // like every other connection, seen from the peer's side
+        let quad = Quad {
+            src: remote,
+            dst: (local, port),
+        };
+        let iss = cm.isn.generate(&quad, cm.clock.now());
+        let c = tcp::Connection::connect(&quad, &cm.config, iss);
+        cm.connections.insert(quad, c);
+        cm.tick_soon(quad);
+        ih.kick();
+        Ok(quad)
+    }
+
+    // actively opens a connection to addr, from whatever local address add_local_addr set up
+    // for its address family and a port of our choosing. blocks until the handshake is done.
+    pub fn connect(&mut self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let quad = self.start_connect(addr)?;
+        let ih = self.ih.as_ref().unwrap();
+        let mut cm = ih.manager.lock().unwrap();
+        while !connected(&mut cm, &quad)? {
+            cm = ih.wait(Wait::Rcv, cm, None)?;
+        }
+        Ok(TcpStream::new(quad, ih.clone()))
+    }
+
+    pub fn bind_udp(&mut self, port: u16) -> io::Result<UdpSocket> {
+        let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
+        match cm.udp.entry(port) {
+            Entry::Vacant(v) => {
+                v.insert(udp::Socket::default());
+            }
+            Entry::Occupied(_) => {
+                return Err(io::Error::new(ErrorKind::AddrInUse, "port already bound"));
+            }
+        };
+        drop(cm);
+        Ok(UdpSocket {
+            port,
+            h: self.ih.as_mut().unwrap().clone(),
+            nonblocking: AtomicBool::new(false),
+            read_timeout: Mutex::new(None),
+            write_timeout: Mutex::new(None),
         })
     }
 
     pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
-        use std::collections::hash_map::Entry;
+        self.bind_with_backlog(port, DEFAULT_BACKLOG)
+    }
+
+    // backlog is how many established connections may wait for accept(), and also how many
+    // may be half-open until set_syn_backlog says otherwise
+    pub fn bind_with_backlog(&mut self, port: u16, backlog: usize) -> io::Result<TcpListener> {
         let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
-        match cm.pending.entry(port) {
+        let config = cm.config;
+        match cm.listeners.entry(port) {
             Entry::Vacant(v) => {
-                v.insert(VecDeque::new());
+                v.insert(Listener {
+                    syn_queue: HashSet::new(),
+                    syn_backlog: backlog,
+                    accept_queue: VecDeque::new(),
+                    backlog,
+                    overflow: Overflow::Drop,
+                    syn_cookies: true,
+                    cookie_sent_at: None,
+                    config,
+                });
             }
             Entry::Occupied(_) => {
                 return Err(io::Error::new(ErrorKind::AddrInUse, "port already bound"));
@@ -175,55 +964,131 @@ impl Interface {
         };
         drop(cm);
         Ok(TcpListener {
-            port: port,
+            port,
             h: self.ih.as_mut().unwrap().clone(),
+            nonblocking: AtomicBool::new(false),
+            accept_timeout: Mutex::new(None),
         })
     }
 }
 pub struct TcpStream {
     quad: Quad,
     h: InterfaceHandle,
+    nonblocking: AtomicBool,
+    read_timeout: Mutex<Option<time::Duration>>,
+    write_timeout: Mutex<Option<time::Duration>>,
 }
 
 impl Read for TcpStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        eprintln!("got to read");
+        let deadline = deadline(&self.read_timeout);
         let mut cm = self.h.manager.lock().unwrap();
         loop {
-            let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "stream was terminated unexpectedly",
-                )
-            })?;
-
-            if c.is_rcv_closed() && c.incoming.is_empty() {
-                // no more data to read, and no need to block, because there won't ber anymore
-                return Ok(0);
-            };
-
-            if !c.incoming.is_empty() {
-                //TODO: detect FIN and return nread==0
-
-                let mut nread = 0;
-                let (head, tail) = c.incoming.as_slices();
-                let hread = std::cmp::min(buf.len(), head.len());
-                buf[..hread].copy_from_slice(&head[..hread]);
-                nread += hread;
-                let tread = std::cmp::min(buf.len() - nread, tail.len());
-                buf[hread..(hread + tread)].copy_from_slice(&tail[..tread]);
-                nread += tread;
-                drop(c.incoming.drain(..nread));
-                return Ok(nread);
+            if let Some(n) = self.try_read(&mut cm, buf)? {
+                return Ok(n);
+            }
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(would_block());
             }
-            cm = self.h.rcv_var.wait(cm).unwrap();
+            cm = self.h.wait(Wait::Rcv, cm, deadline)?;
         }
     }
 }
 
 impl Write for TcpStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.queue(buf, false)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let deadline = deadline(&self.write_timeout);
+        let mut cm = self.h.manager.lock().unwrap();
+        loop {
+            if self.try_flush(&mut cm)?.is_some() {
+                return Ok(());
+            }
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(would_block());
+            }
+            cm = self.h.wait(Wait::Snd, cm, deadline)?;
+        }
+    }
+}
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut cm = self.h.manager.lock().unwrap();
+        let Some(c) = cm.connections.get_mut(&self.quad) else {
+            return;
+        };
+        if c.is_closed() {
+            cm.connections.remove(&self.quad);
+            return;
+        }
+        // send our FIN, if we haven't yet. packet_loop removes the connection once it's done.
+        let _ = c.close();
+        c.orphaned = true;
+        cm.tick_soon(self.quad);
+        self.h.kick();
+    }
+}
+
+impl TcpStream {
+    fn new(quad: Quad, h: InterfaceHandle) -> Self {
+        TcpStream {
+            quad,
+            h,
+            nonblocking: AtomicBool::new(false),
+            read_timeout: Mutex::new(None),
+            write_timeout: Mutex::new(None),
+        }
+    }
+
+    // like write, but everything queued up to and including buf is marked as urgent
+    pub fn send_urgent(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.queue(buf, true)
+    }
+
+    // whether read, write and flush return WouldBlock instead of waiting
+    pub fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+        self.nonblocking.store(on, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // how long read may wait before it gives up with WouldBlock, None is forever
+    pub fn set_read_timeout(&self, dur: Option<time::Duration>) -> io::Result<()> {
+        set_timeout(&self.read_timeout, dur)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<time::Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    // same for write and flush
+    pub fn set_write_timeout(&self, dur: Option<time::Duration>) -> io::Result<()> {
+        set_timeout(&self.write_timeout, dur)
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<time::Duration>> {
+        Ok(*self.write_timeout.lock().unwrap())
+    }
+
+    // puts as much of buf in the send queue as fits, waiting for room unless nonblocking
+    fn queue(&mut self, buf: &[u8], urgent: bool) -> io::Result<usize> {
+        let deadline = deadline(&self.write_timeout);
         let mut cm = self.h.manager.lock().unwrap();
+        loop {
+            if let Some(n) = self.try_queue(&mut cm, buf, urgent)? {
+                return Ok(n);
+            }
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(would_block());
+            }
+            cm = self.h.wait(Wait::Snd, cm, deadline)?;
+        }
+    }
+
+    // what read, write and flush do when they don't have to wait. None if they do.
+    fn try_read(&self, cm: &mut ConnectionManager, buf: &mut [u8]) -> io::Result<Option<usize>> {
         let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
             io::Error::new(
                 io::ErrorKind::ConnectionAborted,
@@ -231,22 +1096,75 @@ impl Write for TcpStream {
             )
         })?;
 
-        if c.unacked.len() >= SENQ_QEUEU_SIZE {
-            //TODO: block
-            return Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "too many buffers",
-            ));
+        if !c.incoming.is_empty() {
+            let n = c.consume(buf);
+            if c.window_update_due() {
+                cm.tick_soon(self.quad);
+                self.h.kick();
+            }
+            return Ok(Some(n));
+        }
+        if let Some(e) = c.error() {
+            return Err(e);
+        }
+        if c.is_rcv_closed() {
+            // no more data to read, and no need to block, because there won't ber anymore
+            return Ok(Some(0));
         };
+        Ok(None)
+    }
 
-        //TODO: detect FIN and return nread==0
-
-        let nwrite = std::cmp::min(buf.len(), SENQ_QEUEU_SIZE - c.unacked.len());
+    fn try_queue(
+        &self,
+        cm: &mut ConnectionManager,
+        buf: &[u8],
+        urgent: bool,
+    ) -> io::Result<Option<usize>> {
+        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",
+            )
+        })?;
+        if let Some(e) = c.error() {
+            return Err(e);
+        }
+        if c.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "stream was shut down for writing",
+            ));
+        }
+        if c.unacked.len() >= c.send_buffer() {
+            return Ok(None);
+        }
+        let nwrite = std::cmp::min(buf.len(), c.send_buffer() - c.unacked.len());
         c.unacked.extend(buf[..nwrite].iter());
-        Ok(nwrite)
+        if urgent {
+            c.mark_urgent();
+        }
+        cm.tick_soon(self.quad);
+        self.h.kick();
+        Ok(Some(nwrite))
     }
 
-    fn flush(&mut self) -> io::Result<()> {
+    fn try_flush(&self, cm: &mut ConnectionManager) -> io::Result<Option<()>> {
+        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",
+            )
+        })?;
+        if let Some(e) = c.error() {
+            return Err(e);
+        }
+        Ok(c.unacked.is_empty().then_some(()))
+    }
+
+    // how many bytes have to be read before the stream is past the urgent data the peer has
+    // sent, or None if there isn't any. the urgent data is delivered inline by read, so this
+    // is how to tell where it ends.
+    pub fn urgent_mark(&self) -> io::Result<Option<usize>> {
         let mut cm = self.h.manager.lock().unwrap();
         let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
             io::Error::new(
@@ -254,28 +1172,60 @@ impl Write for TcpStream {
                 "stream was terminated unexpectedly",
             )
         })?;
+        Ok(c.urgent_mark())
+    }
+
+    // like read, but never reads past the end of the urgent data
+    pub fn read_urgent(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = deadline(&self.read_timeout);
+        let mut cm = self.h.manager.lock().unwrap();
+        loop {
+            let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "stream was terminated unexpectedly",
+                )
+            })?;
+
+            let mark = c.urgent_mark().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "no urgent data pending")
+            })?;
 
-        if c.unacked.is_empty() {
-            Ok(())
-        } else {
-            // TODO: block
-            Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "too many bytes buffered",
-            ))
+            if !c.incoming.is_empty() {
+                let n = std::cmp::min(buf.len(), mark);
+                let n = c.consume(&mut buf[..n]);
+                if c.window_update_due() {
+                    cm.tick_soon(self.quad);
+                    self.h.kick();
+                }
+                return Ok(n);
+            }
+            if let Some(e) = c.error() {
+                return Err(e);
+            }
+            if c.is_rcv_closed() {
+                return Ok(0);
+            }
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(would_block());
+            }
+            cm = self.h.wait(Wait::Rcv, cm, deadline)?;
         }
     }
-}
-impl Drop for TcpStream {
-    fn drop(&mut self) {
+
+    // where the connection is in RFC 793's state diagram
+    pub fn state(&self) -> io::Result<tcp::State> {
         let cm = self.h.manager.lock().unwrap();
-        //TODO: _eventually_ remove sele.quad from cm.connections
-        //TODO: send FIN on cm.pending[quad]
+        let c = cm.connections.get(&self.quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",
+            )
+        })?;
+        Ok(c.state())
     }
-}
 
-impl TcpStream {
-    pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
+    pub fn shutdown(&self, _how: std::net::Shutdown) -> io::Result<()> {
         let mut cm = self.h.manager.lock().unwrap();
         let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
             io::Error::new(
@@ -283,41 +1233,188 @@ impl TcpStream {
                 "stream was terminated unexpectedly",
             )
         })?;
-        c.close()
+        c.close()?;
+        cm.tick_soon(self.quad);
+        self.h.kick();
+        Ok(())
     }
 }
 
 impl Drop for TcpListener {
     fn drop(&mut self) {
         let mut cm = self.h.manager.lock().unwrap();
-        let pending = cm
-            .pending
+        let l = cm
+            .listeners
             .remove(&self.port)
             .expect("port closed while listener still active");
-        for quad in pending {
-            //TODO: terminate cm.pending[quad]
-            unimplemented!();
+        for quad in l.syn_queue.iter().chain(l.accept_queue.iter()) {
+            // nobody is going to accept them now, so tell the peers right away rather than
+            // when they next talk to us. packet_loop removes them once the RST is out.
+            if let Some(c) = cm.connections.get_mut(quad) {
+                c.reset();
+                c.orphaned = true;
+                cm.tick_soon(*quad);
+            }
         }
+        drop(cm);
+        self.h.kick();
     }
 }
 
 impl TcpListener {
+    fn with_listener<T>(&self, f: impl FnOnce(&mut Listener) -> T) -> T {
+        let mut cm = self.h.manager.lock().unwrap();
+        f(cm.listeners
+            .get_mut(&self.port)
+            .expect("port closed while listener still active"))
+    }
+
+    // how many connections may be half-open before overflow (or SYN cookies) kicks in
+    pub fn set_syn_backlog(&mut self, n: usize) {
+        self.with_listener(|l| l.syn_backlog = n);
+    }
+
+    // what to do with connections when either queue is full
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.with_listener(|l| l.overflow = overflow);
+    }
+
+    // whether to answer SYNs with cookies instead of dropping or refusing them when the SYN
+    // queue is full. on by default.
+    pub fn set_syn_cookies(&mut self, on: bool) {
+        self.with_listener(|l| l.syn_cookies = on);
+    }
+
+    // whether accept returns WouldBlock instead of waiting for a connection
+    pub fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+        self.nonblocking.store(on, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // how long accept may wait for a connection before it gives up with WouldBlock, None is
+    // forever
+    pub fn set_accept_timeout(&self, dur: Option<time::Duration>) -> io::Result<()> {
+        set_timeout(&self.accept_timeout, dur)
+    }
+
+    pub fn accept_timeout(&self) -> io::Result<Option<time::Duration>> {
+        Ok(*self.accept_timeout.lock().unwrap())
+    }
+
     pub fn accept(&mut self) -> io::Result<TcpStream> {
+        let deadline = deadline(&self.accept_timeout);
         let mut cm = self.h.manager.lock().unwrap();
         loop {
-            if let Some(quad) = cm
-                .pending
+            if let Some(s) = self.try_accept(&mut cm) {
+                return Ok(s);
+            }
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(would_block());
+            }
+
+            cm = self.h.wait(Wait::Pending, cm, deadline)?;
+        }
+    }
+
+    fn try_accept(&self, cm: &mut ConnectionManager) -> Option<TcpStream> {
+        let quad = cm
+            .listeners
+            .get_mut(&self.port)
+            .expect("port closed while listener still active")
+            .accept_queue
+            .pop_front()?;
+        Some(TcpStream::new(quad, self.h.clone()))
+    }
+}
+
+pub struct UdpSocket {
+    port: u16,
+    h: InterfaceHandle,
+    nonblocking: AtomicBool,
+    read_timeout: Mutex<Option<time::Duration>>,
+    write_timeout: Mutex<Option<time::Duration>>,
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let mut cm = self.h.manager.lock().unwrap();
+        cm.udp.remove(&self.port);
+    }
+}
+
+impl UdpSocket {
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    // queues the datagram, packet_loop sends it on its next tick. if too much is queued
+    // already, waits for packet_loop to make room unless nonblocking.
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let deadline = deadline(&self.write_timeout);
+        let mut cm = self.h.manager.lock().unwrap();
+        let local = cm.local_addr_for(addr.ip()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "no local address for that address family",
+            )
+        })?;
+        let mtu = cm.config.mtu;
+        loop {
+            let s = cm
+                .udp
                 .get_mut(&self.port)
-                .expect("port closed while listener still active")
-                .pop_front()
-            {
-                return Ok(TcpStream {
-                    quad,
-                    h: self.h.clone(),
-                });
+                .expect("port closed while socket still active");
+            if s.queue(SocketAddr::new(local, self.port), addr, buf, mtu)? {
+                self.h.kick();
+                return Ok(buf.len());
+            }
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(would_block());
             }
+            cm = self.h.wait(Wait::Snd, cm, deadline)?;
+        }
+    }
+
+    // whether recv_from and send_to return WouldBlock instead of waiting
+    pub fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+        self.nonblocking.store(on, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // how long recv_from may wait before it gives up with WouldBlock, None is forever
+    pub fn set_read_timeout(&self, dur: Option<time::Duration>) -> io::Result<()> {
+        set_timeout(&self.read_timeout, dur)
+    }
 
-            cm = self.h.pending_var.wait(cm).unwrap();
+    pub fn read_timeout(&self) -> io::Result<Option<time::Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    // same for send_to
+    pub fn set_write_timeout(&self, dur: Option<time::Duration>) -> io::Result<()> {
+        set_timeout(&self.write_timeout, dur)
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<time::Duration>> {
+        Ok(*self.write_timeout.lock().unwrap())
+    }
+
+    // like std's, if buf is too small the rest of the datagram is lost
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let deadline = deadline(&self.read_timeout);
+        let mut cm = self.h.manager.lock().unwrap();
+        loop {
+            let s = cm
+                .udp
+                .get_mut(&self.port)
+                .expect("port closed while socket still active");
+            if let Some(r) = s.recv(buf) {
+                return Ok(r);
+            }
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(would_block());
+            }
+            cm = self.h.wait(Wait::Rcv, cm, deadline)?;
         }
     }
 }
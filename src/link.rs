@@ -0,0 +1,567 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use crate::clock::SharedClock;
+use crate::device::Device;
+use crate::pcap::{self, Direction};
+
+/*
+  The link layer
+
+  Some devices, like tun, hand us bare IP packets and take them the same way. Others, like tap,
+  carry Ethernet II frames, so we need a MAC address of our own and have to find out everyone
+  else's before we can send them anything: ARP (RFC 826) for IPv4, and for IPv6 (until we do
+  neighbor discovery) whatever MAC the peer last sent us a frame from.
+
+  Only hosts on our own network can be reached directly. Anything else goes to the gateway,
+  so that's who we ARP for:
+
+      dst on ipv4/prefix? --yes--> ARP for dst
+              |
+              no --> ARP for the gateway (or for dst, if there's no gateway)
+
+  Either way the rest of the stack only ever sees IP packets.
+*/
+
+pub(crate) const ETHERNET_HEADER_LEN: usize = 14;
+const BROADCAST: [u8; 6] = [0xff; 6];
+// how long we trust what we know about a neighbor before asking again
+const NEIGHBOR_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+// how long we wait for an ARP reply before asking again, and how often we ask
+const ARP_RETRY: time::Duration = time::Duration::from_secs(1);
+const ARP_TRIES: u32 = 3;
+// how many packets we hold on to per neighbor while we wait for it to answer, RFC 1122 2.3.2.2
+// asks for at least one
+const PENDING_LIMIT: usize = 3;
+// what an ARP packet for IPv4 over Ethernet looks like
+const ARP_LEN: usize = 28;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+pub(crate) struct Nic {
+    // a trait object rather than a type parameter, so an Interface is the same type whatever
+    // device it runs on (tun, tap, a virtual link, a replay, an impaired one of those) and the
+    // generics don't spread to everything that holds one. every send and recv is a syscall
+    // anyway, next to which the virtual call doesn't show.
+    iface: Box<dyn Device>,
+    // only for devices that carry Ethernet frames
+    ethernet: Option<Ethernet>,
+    // where to record the IP packets we send and receive, if anywhere. shared with the
+    // Interface so captures can be started and stopped while packet_loop runs.
+    pub(crate) capture: Capture,
+    // what the whole stack keeps time by. everything that has a timer gets to the NIC.
+    clock: SharedClock,
+}
+
+pub(crate) type Capture = Arc<Mutex<Option<pcap::Writer>>>;
+
+struct Ethernet {
+    mac: [u8; 6],
+    // the address we answer ARP requests for
+    ipv4: Ipv4Addr,
+    // how much of ipv4 is the network we're on
+    prefix: u8,
+    // the router for everything off our network
+    gateway: Option<Ipv4Addr>,
+    neighbors: HashMap<IpAddr, Neighbor>,
+}
+
+enum Neighbor {
+    Reachable {
+        mac: [u8; 6],
+        seen: time::Instant,
+    },
+    // we asked, but it hasn't answered yet
+    Incomplete {
+        asked_at: time::Instant,
+        tries: u32,
+        pending: VecDeque<Vec<u8>>,
+    },
+}
+
+impl Nic {
+    pub(crate) fn ip(iface: Box<dyn Device>, clock: SharedClock) -> Self {
+        Nic {
+            iface,
+            ethernet: None,
+            capture: Capture::default(),
+            clock,
+        }
+    }
+
+    pub(crate) fn ethernet(
+        iface: Box<dyn Device>,
+        mac: [u8; 6],
+        ipv4: Ipv4Addr,
+        prefix: u8,
+        gateway: Option<Ipv4Addr>,
+        clock: SharedClock,
+    ) -> Self {
+        Nic {
+            iface,
+            ethernet: Some(Ethernet {
+                mac,
+                ipv4,
+                prefix,
+                gateway,
+                neighbors: HashMap::new(),
+            }),
+            capture: Capture::default(),
+            clock,
+        }
+    }
+
+    pub(crate) fn now(&self) -> time::Instant {
+        self.clock.now()
+    }
+
+    // sends an IP packet. over Ethernet it may have to wait until we know where to send it.
+    pub(crate) fn send(&mut self, packet: &[u8]) -> io::Result<usize> {
+        let now = self.clock.now();
+        let Some(eth) = &mut self.ethernet else {
+            return self.transmit(packet);
+        };
+        let Ok(ip) = etherparse::IpSlice::from_slice(packet) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not an ip packet",
+            ));
+        };
+        let dst = ip.destination_addr();
+        // the neighbor it goes to, which isn't dst if it's off our network
+        let dst = match dst {
+            IpAddr::V4(a) => eth.next_hop(a).into(),
+            IpAddr::V6(_) => dst,
+        };
+        let mac = match multicast_mac(dst) {
+            Some(mac) => mac,
+            None => match eth.neighbors.get_mut(&dst) {
+                Some(Neighbor::Reachable { mac, .. }) => *mac,
+                Some(Neighbor::Incomplete { pending, .. }) => {
+                    if pending.len() < PENDING_LIMIT {
+                        pending.push_back(packet.to_vec());
+                    }
+                    return Ok(packet.len());
+                }
+                None => {
+                    let IpAddr::V4(dst) = dst else {
+                        // TODO: neighbor discovery
+                        eprintln!("don't know where {} is, dropping packet", dst);
+                        return Ok(packet.len());
+                    };
+                    eth.neighbors.insert(
+                        dst.into(),
+                        Neighbor::Incomplete {
+                            asked_at: now,
+                            tries: 1,
+                            pending: VecDeque::from([packet.to_vec()]),
+                        },
+                    );
+                    let request = eth.arp(ARP_REQUEST, [0; 6], dst);
+                    self.transmit(&request)?;
+                    return Ok(packet.len());
+                }
+            },
+        };
+        let frame = eth.frame(mac, ethertype(packet), packet);
+        self.transmit(&frame)?;
+        Ok(packet.len())
+    }
+
+    // hands a frame to the device. only now is a packet really sent, so this is where it goes
+    // into the capture, not while it's waiting for an ARP reply.
+    fn transmit(&mut self, frame: &[u8]) -> io::Result<usize> {
+        let n = self.iface.send(frame)?;
+        let packet = match self.ethernet {
+            None => Some(frame),
+            Some(_) => {
+                let ty = etherparse::EtherType(u16::from_be_bytes([frame[12], frame[13]]));
+                let ip = matches!(
+                    ty,
+                    etherparse::EtherType::IPV4 | etherparse::EtherType::IPV6
+                );
+                ip.then(|| &frame[ETHERNET_HEADER_LEN..])
+            }
+        };
+        if let Some(packet) = packet {
+            self.record(Direction::Outbound, packet);
+        }
+        Ok(n)
+    }
+
+    // receives the next IP packet into buf. over Ethernet that may turn out to be some other frame
+    // we deal with here, and then there's no packet.
+    pub(crate) fn recv<'a>(&mut self, buf: &'a mut [u8]) -> io::Result<Option<&'a [u8]>> {
+        let n = self.iface.recv(buf)?;
+        let now = self.clock.now();
+        let Some(eth) = &mut self.ethernet else {
+            self.record(Direction::Inbound, &buf[..n]);
+            return Ok(Some(&buf[..n]));
+        };
+        let Ok((header, payload)) = etherparse::Ethernet2Header::from_slice(&buf[..n]) else {
+            eprintln!("ignoring weird frame");
+            return Ok(None);
+        };
+        if header.destination != eth.mac && !is_multicast(header.destination) {
+            // the bridge floods frames for everyone
+            return Ok(None);
+        }
+        match header.ether_type {
+            etherparse::EtherType::IPV4 | etherparse::EtherType::IPV6 => {
+                if let Ok(ip) = etherparse::IpSlice::from_slice(payload) {
+                    // whatever sent it is where replies go, even if it's a router
+                    let to_us = header.destination == eth.mac;
+                    for frame in eth.learn(ip.source_addr(), header.source, to_us, now) {
+                        self.transmit(&frame)?;
+                    }
+                }
+                self.record(Direction::Inbound, &buf[ETHERNET_HEADER_LEN..n]);
+                Ok(Some(&buf[ETHERNET_HEADER_LEN..n]))
+            }
+            etherparse::EtherType::ARP => {
+                for frame in eth.on_arp(payload, now) {
+                    self.transmit(&frame)?;
+                }
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // asks again about neighbors that haven't answered, and forgets ones we haven't heard from
+    // in a while
+    pub(crate) fn on_tick(&mut self) -> io::Result<()> {
+        let now = self.clock.now();
+        let Some(eth) = &mut self.ethernet else {
+            return Ok(());
+        };
+        let mut requests = Vec::new();
+        eth.neighbors.retain(|ip, n| match n {
+            Neighbor::Reachable { seen, .. } => now.duration_since(*seen) < NEIGHBOR_TIMEOUT,
+            Neighbor::Incomplete {
+                asked_at, tries, ..
+            } => {
+                if now.duration_since(*asked_at) < ARP_RETRY {
+                    return true;
+                }
+                if *tries == ARP_TRIES {
+                    // TODO: ICMP host unreachable for what's pending
+                    eprintln!("{} isn't answering ARP requests", ip);
+                    return false;
+                }
+                *tries += 1;
+                *asked_at = now;
+                if let IpAddr::V4(ip) = ip {
+                    requests.push(*ip);
+                }
+                true
+            }
+        });
+        let requests: Vec<_> = requests
+            .into_iter()
+            .map(|ip| eth.arp(ARP_REQUEST, [0; 6], ip))
+            .collect();
+        for request in requests {
+            self.transmit(&request)?;
+        }
+        Ok(())
+    }
+
+    // when on_tick has to look at the neighbors again
+    pub(crate) fn next_timeout(&self) -> Option<time::Instant> {
+        let eth = self.ethernet.as_ref()?;
+        eth.neighbors
+            .values()
+            .map(|n| match n {
+                Neighbor::Reachable { seen, .. } => *seen + NEIGHBOR_TIMEOUT,
+                Neighbor::Incomplete { asked_at, .. } => *asked_at + ARP_RETRY,
+            })
+            .min()
+    }
+
+    fn record(&self, direction: Direction, packet: &[u8]) {
+        let mut capture = self.capture.lock().unwrap();
+        if let Some(w) = capture.as_mut() {
+            if let Err(e) = w.write(direction, packet, self.clock.now()) {
+                // better to lose the capture than the packet
+                eprintln!("stopping capture: {:?}", e);
+                *capture = None;
+            }
+        }
+    }
+}
+
+impl AsRawFd for Nic {
+    fn as_raw_fd(&self) -> RawFd {
+        self.iface.as_raw_fd()
+    }
+}
+
+impl Ethernet {
+    fn next_hop(&self, dst: Ipv4Addr) -> Ipv4Addr {
+        let mask = u32::MAX
+            .checked_shl(32u32.saturating_sub(self.prefix as u32))
+            .unwrap_or(0);
+        let on_link = (u32::from(dst) ^ u32::from(self.ipv4)) & mask == 0;
+        match self.gateway {
+            Some(gw) if !on_link && !dst.is_broadcast() => gw,
+            _ => dst,
+        }
+    }
+
+    // RFC 826 "Packet Reception". returns the frames to send in response.
+    fn on_arp(&mut self, arp: &[u8], now: time::Instant) -> Vec<Vec<u8>> {
+        if arp.len() < ARP_LEN || arp[..6] != [0, 1, 0x08, 0x00, 6, 4] {
+            // not IPv4 over Ethernet
+            return Vec::new();
+        }
+        let op = u16::from_be_bytes([arp[6], arp[7]]);
+        let sha: [u8; 6] = arp[8..14].try_into().unwrap();
+        let spa = Ipv4Addr::new(arp[14], arp[15], arp[16], arp[17]);
+        let tpa = Ipv4Addr::new(arp[24], arp[25], arp[26], arp[27]);
+
+        // update what we already know, but only add new entries for hosts that want to talk to us
+        let mut frames = self.learn(spa.into(), sha, tpa == self.ipv4, now);
+        if tpa == self.ipv4 && op == ARP_REQUEST {
+            frames.push(self.arp(ARP_REPLY, sha, spa));
+        }
+        frames
+    }
+
+    // remember that ip is at mac. returns whatever was waiting to be sent there.
+    fn learn(&mut self, ip: IpAddr, mac: [u8; 6], add: bool, now: time::Instant) -> Vec<Vec<u8>> {
+        if is_multicast(mac) {
+            return Vec::new();
+        }
+        let pending = match self.neighbors.get_mut(&ip) {
+            None if !add => return Vec::new(),
+            Some(Neighbor::Incomplete { pending, .. }) => std::mem::take(pending),
+            _ => VecDeque::new(),
+        };
+        self.neighbors
+            .insert(ip, Neighbor::Reachable { mac, seen: now });
+        pending
+            .into_iter()
+            .map(|p| self.frame(mac, ethertype(&p), &p))
+            .collect()
+    }
+
+    fn arp(&self, op: u16, tha: [u8; 6], tpa: Ipv4Addr) -> Vec<u8> {
+        let mut arp = Vec::with_capacity(ARP_LEN);
+        arp.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+        arp.extend_from_slice(&op.to_be_bytes());
+        arp.extend_from_slice(&self.mac);
+        arp.extend_from_slice(&self.ipv4.octets());
+        arp.extend_from_slice(&tha);
+        arp.extend_from_slice(&tpa.octets());
+        let dst = if op == ARP_REQUEST { BROADCAST } else { tha };
+        self.frame(dst, etherparse::EtherType::ARP, &arp)
+    }
+
+    fn frame(&self, dst: [u8; 6], ether_type: etherparse::EtherType, payload: &[u8]) -> Vec<u8> {
+        let header = etherparse::Ethernet2Header {
+            source: self.mac,
+            destination: dst,
+            ether_type,
+        };
+        let mut frame = Vec::with_capacity(ETHERNET_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&header.to_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+}
+
+fn ethertype(packet: &[u8]) -> etherparse::EtherType {
+    if packet[0] >> 4 == 6 {
+        etherparse::EtherType::IPV6
+    } else {
+        etherparse::EtherType::IPV4
+    }
+}
+
+fn is_multicast(mac: [u8; 6]) -> bool {
+    mac[0] & 1 == 1
+}
+
+// multicast and broadcast addresses map onto MACs without asking anyone
+fn multicast_mac(ip: IpAddr) -> Option<[u8; 6]> {
+    match ip {
+        IpAddr::V4(ip) if ip.is_broadcast() => Some(BROADCAST),
+        // RFC 1112 6.4
+        IpAddr::V4(ip) if ip.is_multicast() => {
+            let o = ip.octets();
+            Some([0x01, 0x00, 0x5e, o[1] & 0x7f, o[2], o[3]])
+        }
+        // RFC 2464 7
+        IpAddr::V6(ip) if ip.is_multicast() => {
+            let o = ip.octets();
+            Some([0x33, 0x33, o[12], o[13], o[14], o[15]])
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::device::virtual_link;
+    use crate::ip::IpHeader;
+
+    const MAC: [u8; 6] = [2, 0, 0, 0, 0, 1];
+    const PEER_MAC: [u8; 6] = [2, 0, 0, 0, 0, 2];
+    const US: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn captured(out: &Shared) -> Vec<Vec<u8>> {
+        let file = out.0.lock().unwrap();
+        pcap::read(&file)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.data)
+            .collect()
+    }
+
+    // a packet waiting for an ARP reply isn't sent yet, so it isn't captured yet either
+    #[test]
+    fn capture_what_is_sent() {
+        let (a, mut peer) = virtual_link().unwrap();
+        let clock = SharedClock::default();
+        let mut nic = Nic::ethernet(Box::new(a), MAC, US, 24, None, clock.clone());
+        let out = Shared::default();
+        let w = pcap::Writer::new(Box::new(out.clone()), &clock).unwrap();
+        *nic.capture.lock().unwrap() = Some(w);
+
+        let mut ip = IpHeader::new(US.into(), PEER.into(), etherparse::IpNumber::UDP);
+        ip.set_payload_len(4);
+        let mut packet = Vec::new();
+        ip.write(&mut packet).unwrap();
+        packet.extend_from_slice(b"data");
+        nic.send(&packet).unwrap();
+        assert!(captured(&out).is_empty());
+
+        let mut buf = [0u8; 1500];
+        let n = peer.recv(&mut buf).unwrap();
+        let (eth, _) = etherparse::Ethernet2Header::from_slice(&buf[..n]).unwrap();
+        assert_eq!(eth.ether_type, etherparse::EtherType::ARP);
+
+        // the reply gets the packet going
+        let mut reply = etherparse::Ethernet2Header {
+            source: PEER_MAC,
+            destination: MAC,
+            ether_type: etherparse::EtherType::ARP,
+        }
+        .to_bytes()
+        .to_vec();
+        reply.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+        reply.extend_from_slice(&ARP_REPLY.to_be_bytes());
+        reply.extend_from_slice(&PEER_MAC);
+        reply.extend_from_slice(&PEER.octets());
+        reply.extend_from_slice(&MAC);
+        reply.extend_from_slice(&US.octets());
+        peer.send(&reply).unwrap();
+        assert_eq!(nic.recv(&mut buf).unwrap(), None);
+
+        let n = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[ETHERNET_HEADER_LEN..n], &packet[..]);
+        assert_eq!(captured(&out), vec![packet.clone()]);
+
+        // from then on right away, and only IP packets
+        nic.send(&packet).unwrap();
+        assert_eq!(captured(&out).len(), 2);
+    }
+
+    // what's off our network goes through the gateway, so that's who we ask about it
+    #[test]
+    fn arp_for_the_gateway() {
+        const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 254);
+        let (a, mut peer) = virtual_link().unwrap();
+        let mut nic = Nic::ethernet(
+            Box::new(a),
+            MAC,
+            US,
+            24,
+            Some(GATEWAY),
+            SharedClock::default(),
+        );
+        let mut asked_for = |to: Ipv4Addr| {
+            let mut ip = IpHeader::new(US.into(), to.into(), etherparse::IpNumber::UDP);
+            ip.set_payload_len(0);
+            let mut packet = Vec::new();
+            ip.write(&mut packet).unwrap();
+            nic.send(&packet).unwrap();
+            let mut buf = [0u8; 1500];
+            let n = peer.recv(&mut buf).unwrap();
+            let (eth, arp) = etherparse::Ethernet2Header::from_slice(&buf[..n]).unwrap();
+            assert_eq!(eth.ether_type, etherparse::EtherType::ARP);
+            Ipv4Addr::new(arp[24], arp[25], arp[26], arp[27])
+        };
+        assert_eq!(asked_for(PEER), PEER);
+        assert_eq!(asked_for(Ipv4Addr::new(192, 0, 2, 1)), GATEWAY);
+        // we're already waiting to hear from the gateway
+        let mut ip = IpHeader::new(
+            US.into(),
+            Ipv4Addr::new(8, 8, 8, 8).into(),
+            etherparse::IpNumber::UDP,
+        );
+        ip.set_payload_len(0);
+        let mut packet = Vec::new();
+        ip.write(&mut packet).unwrap();
+        nic.send(&packet).unwrap();
+        assert!(peer.recv(&mut [0u8; 1500]).is_err());
+    }
+
+    // on a clock of our own, a capture starts at 0 and goes by that clock
+    #[test]
+    fn capture_by_the_clock() {
+        let (a, _peer) = virtual_link().unwrap();
+        let manual = crate::ManualClock::new();
+        let clock = SharedClock::new(manual.clone());
+        let mut nic = Nic::ip(Box::new(a), clock.clone());
+        let out = Shared::default();
+        let w = pcap::Writer::new(Box::new(out.clone()), &clock).unwrap();
+        *nic.capture.lock().unwrap() = Some(w);
+
+        let mut ip = IpHeader::new(US.into(), PEER.into(), etherparse::IpNumber::UDP);
+        ip.set_payload_len(0);
+        let mut packet = Vec::new();
+        ip.write(&mut packet).unwrap();
+        manual.advance(time::Duration::from_secs(3));
+        nic.send(&packet).unwrap();
+        manual.advance(time::Duration::from_millis(5));
+        nic.send(&packet).unwrap();
+
+        let file = out.0.lock().unwrap();
+        let times: Vec<_> = pcap::read(&file)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.time)
+            .collect();
+        assert_eq!(
+            times,
+            [
+                time::Duration::from_secs(3),
+                time::Duration::from_millis(3005)
+            ]
+        );
+    }
+}
@@ -1,14 +1,20 @@
 use std::io::{Read, Write};
+use std::net::Ipv4Addr;
 use std::{io, thread};
 
 fn main() -> io::Result<()> {
-    let mut i = rtcp::Interface::new()?;
+    // the kernel's end of tun0, we answer for everything else on those networks
+    let mut i = rtcp::Interface::builder()
+        .device_addr(Ipv4Addr::new(192, 168, 0, 1).into(), 24)
+        .device_addr("fd00::1".parse().unwrap(), 64)
+        .up()
+        .build()?;
     eprintln!("Interface created");
     let mut l1 = i.bind(8080)?;
     while let Ok(mut stream) = l1.accept() {
         eprintln!("got connection!");
         thread::spawn(move || {
-            stream.write(b"hello from tcp\n").unwrap();
+            stream.write_all(b"hello from tcp\n").unwrap();
             stream.shutdown(std::net::Shutdown::Write).unwrap();
             loop {
                 let mut buf = [0; 512];
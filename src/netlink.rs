@@ -0,0 +1,261 @@
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+
+use nix::net::if_::if_nametoindex;
+use nix::sys::socket::{
+    self, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType,
+};
+
+use crate::ip::Cidr;
+
+/*
+  Configuring the device (rtnetlink, RFC 3549)
+
+  What run.sh used to do with ip(8), over a NETLINK_ROUTE socket:
+
+      ip link set dev tun0 mtu 1500 up     --> RTM_NEWLINK, IFF_UP and IFLA_MTU
+      ip addr add 192.168.0.1/24 dev tun0  --> RTM_NEWADDR
+      ip route add 10.1.0.0/16 dev tun0    --> RTM_NEWROUTE
+
+  Every message is a header and a fixed struct followed by attributes, all in host byte order:
+
+      +--------+------+-------+-----+-----+   +-------------------+   +-----+------+------+
+      | len 32 |type16|flags16|seq32|pid32|   | ifinfo/ifaddr/rt  |   |len16|type16| data |...
+      +--------+------+-------+-----+-----+   +-------------------+   +-----+------+------+
+
+  We ask for an ack to every request, which comes back as an NLMSG_ERROR carrying 0 or -errno.
+  The addresses here are the kernel's end of the device, not ours. Whatever we set up is undone
+  in reverse once the Interface goes, for devices that outlive it. Needs CAP_NET_ADMIN.
+*/
+
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const IFLA_MTU: u16 = 4;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const IFF_UP: u32 = 0x1;
+const AF_UNSPEC: u8 = 0;
+const AF_INET: u8 = 2;
+const AF_INET6: u8 = 10;
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RTN_UNICAST: u8 = 1;
+
+// what to set up on the device, see InterfaceBuilder
+#[derive(Default)]
+pub(crate) struct Setup {
+    pub(crate) addrs: Vec<Cidr>,
+    pub(crate) routes: Vec<Cidr>,
+    pub(crate) up: bool,
+}
+
+impl Setup {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.addrs.is_empty() && self.routes.is_empty() && !self.up
+    }
+
+    // configures the device called name. if any of it fails, what was done so far is undone.
+    pub(crate) fn apply(&self, name: &str, mtu: usize) -> io::Result<Configured> {
+        let mut c = Configured {
+            nl: Netlink::open()?,
+            index: if_nametoindex(name)?,
+            addrs: Vec::new(),
+            routes: Vec::new(),
+            up: false,
+        };
+        // up first, the kernel won't route over a link that's down
+        if self.up {
+            c.nl.link(c.index, true, Some(mtu))?;
+            c.up = true;
+        }
+        for a in &self.addrs {
+            c.nl.addr(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL, c.index, a)?;
+            c.addrs.push(*a);
+        }
+        for r in &self.routes {
+            c.nl.route(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL, c.index, r)?;
+            c.routes.push(*r);
+        }
+        Ok(c)
+    }
+}
+
+// what we've set up on a device, torn down again on drop
+pub(crate) struct Configured {
+    nl: Netlink,
+    index: u32,
+    addrs: Vec<Cidr>,
+    routes: Vec<Cidr>,
+    up: bool,
+}
+
+impl Drop for Configured {
+    fn drop(&mut self) {
+        // if the device is gone already, so is everything on it
+        while let Some(r) = self.routes.pop() {
+            if let Err(e) = self.nl.route(RTM_DELROUTE, 0, self.index, &r) {
+                eprintln!("failed to remove route {:?} {:?}", r, e);
+            }
+        }
+        while let Some(a) = self.addrs.pop() {
+            if let Err(e) = self.nl.addr(RTM_DELADDR, 0, self.index, &a) {
+                eprintln!("failed to remove address {:?} {:?}", a, e);
+            }
+        }
+        if self.up {
+            // the MTU stays, it's no use to anyone without the link
+            if let Err(e) = self.nl.link(self.index, false, None) {
+                eprintln!("failed to take link down {:?}", e);
+            }
+        }
+    }
+}
+
+struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    fn open() -> io::Result<Self> {
+        let fd = socket::socket(
+            AddressFamily::Netlink,
+            SockType::Raw,
+            SockFlag::SOCK_CLOEXEC,
+            SockProtocol::NetlinkRoute,
+        )?;
+        // port 0 has the kernel pick one for us
+        socket::bind(fd.as_raw_fd(), &NetlinkAddr::new(0, 0))?;
+        Ok(Netlink { fd, seq: 0 })
+    }
+
+    // ip link set dev up/down, and mtu
+    fn link(&mut self, index: u32, up: bool, mtu: Option<usize>) -> io::Result<()> {
+        // struct ifinfomsg
+        let mut body = vec![AF_UNSPEC, 0];
+        body.extend(0u16.to_ne_bytes());
+        body.extend(index.to_ne_bytes());
+        body.extend((if up { IFF_UP } else { 0 }).to_ne_bytes());
+        // which flags to change
+        body.extend(IFF_UP.to_ne_bytes());
+        if let Some(mtu) = mtu {
+            attr(&mut body, IFLA_MTU, &(mtu as u32).to_ne_bytes());
+        }
+        self.request(RTM_NEWLINK, 0, &body)
+    }
+
+    // ip addr add/del
+    fn addr(&mut self, ty: u16, flags: u16, index: u32, a: &Cidr) -> io::Result<()> {
+        // struct ifaddrmsg
+        let mut body = vec![family(a.addr), a.prefix, 0, RT_SCOPE_UNIVERSE];
+        body.extend(index.to_ne_bytes());
+        let octets = octets(a.addr);
+        attr(&mut body, IFA_LOCAL, &octets);
+        attr(&mut body, IFA_ADDRESS, &octets);
+        self.request(ty, flags, &body)
+    }
+
+    // ip route add/del dev
+    fn route(&mut self, ty: u16, flags: u16, index: u32, r: &Cidr) -> io::Result<()> {
+        let scope = if r.addr.is_ipv4() {
+            RT_SCOPE_LINK
+        } else {
+            RT_SCOPE_UNIVERSE
+        };
+        // struct rtmsg
+        let mut body = vec![
+            family(r.addr),
+            r.prefix,
+            0,
+            0,
+            RT_TABLE_MAIN,
+            RTPROT_BOOT,
+            scope,
+            RTN_UNICAST,
+        ];
+        body.extend(0u32.to_ne_bytes());
+        attr(&mut body, RTA_DST, &octets(r.network()));
+        attr(&mut body, RTA_OIF, &index.to_ne_bytes());
+        self.request(ty, flags, &body)
+    }
+
+    // sends a request and waits for the kernel to ack it
+    fn request(&mut self, ty: u16, flags: u16, body: &[u8]) -> io::Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let mut msg = Vec::with_capacity(NLMSG_HDRLEN + body.len());
+        msg.extend(((NLMSG_HDRLEN + body.len()) as u32).to_ne_bytes());
+        msg.extend(ty.to_ne_bytes());
+        msg.extend((flags | NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+        msg.extend(self.seq.to_ne_bytes());
+        msg.extend(0u32.to_ne_bytes());
+        msg.extend_from_slice(body);
+        socket::send(self.fd.as_raw_fd(), &msg, MsgFlags::empty())?;
+
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = socket::recv(self.fd.as_raw_fd(), &mut buf, MsgFlags::empty())?;
+            let mut rest = &buf[..n];
+            while rest.len() >= NLMSG_HDRLEN {
+                let len = u32::from_ne_bytes(rest[0..4].try_into().unwrap()) as usize;
+                let ty = u16::from_ne_bytes(rest[4..6].try_into().unwrap());
+                let seq = u32::from_ne_bytes(rest[8..12].try_into().unwrap());
+                if len < NLMSG_HDRLEN || len > rest.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "truncated netlink message",
+                    ));
+                }
+                if ty == NLMSG_ERROR && seq == self.seq && len >= NLMSG_HDRLEN + 4 {
+                    let errno = i32::from_ne_bytes(rest[16..20].try_into().unwrap());
+                    if errno == 0 {
+                        return Ok(());
+                    }
+                    return Err(io::Error::from_raw_os_error(-errno));
+                }
+                // something else, like the answer to an earlier request
+                rest = &rest[std::cmp::min(align(len), rest.len())..];
+            }
+        }
+    }
+}
+
+// messages and attributes start at multiples of 4
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn attr(msg: &mut Vec<u8>, ty: u16, data: &[u8]) {
+    let len = 4 + data.len();
+    msg.extend((len as u16).to_ne_bytes());
+    msg.extend(ty.to_ne_bytes());
+    msg.extend_from_slice(data);
+    msg.resize(msg.len() + align(len) - len, 0);
+}
+
+fn family(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => AF_INET,
+        IpAddr::V6(_) => AF_INET6,
+    }
+}
+
+fn octets(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec(),
+    }
+}
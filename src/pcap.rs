@@ -0,0 +1,326 @@
+use std::io::{self, Write};
+use std::time;
+
+use crate::clock::SharedClock;
+
+/*
+  Packet captures in pcapng (draft-ietf-opsawg-pcapng)
+
+  A capture is a section header, one interface description and then an enhanced packet block
+  per packet:
+
+      +---------------------------------------------------+
+      |  block type  |  total length  |  body  |  total length  |
+      +---------------------------------------------------+
+
+  We capture at the IP layer, so the interface's link type is LINKTYPE_RAW and Wireshark finds
+  the IP version in the first nibble. Classic pcap can't tell which way a packet went, pcapng
+  has the epb_flags option for that.
+
+  Packets are stamped by the interface's clock. On the system's that's the wall clock, on any
+  other the capture starts at 0, so a run on a ManualClock captures the same file every time.
+
+  Captures to replay may come from anywhere though, so those we read in either format, with
+  whatever link type and timestamp resolution they were taken with.
+*/
+
+const SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+pub(crate) const LINKTYPE_ETHERNET: u16 = 1;
+pub(crate) const LINKTYPE_RAW: u16 = 101;
+pub(crate) const LINKTYPE_LINUX_SLL: u16 = 113;
+pub(crate) const LINKTYPE_IPV4: u16 = 228;
+pub(crate) const LINKTYPE_IPV6: u16 = 229;
+const OPT_END: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+pub(crate) struct Writer {
+    out: Box<dyn Write + Send>,
+    // when the capture started, by the interface's clock and since the epoch
+    start: time::Instant,
+    origin: time::Duration,
+}
+
+impl Writer {
+    pub(crate) fn new(mut out: Box<dyn Write + Send>, clock: &SharedClock) -> io::Result<Self> {
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        // version 1.0
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        // we don't know how long the section will be
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        out.write_all(&block(SECTION_HEADER, &shb))?;
+
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        // no snap length, we keep whole packets. timestamps are in microseconds, the default.
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        out.write_all(&block(INTERFACE_DESCRIPTION, &idb))?;
+        out.flush()?;
+        let origin = if clock.is_real() {
+            time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .unwrap_or_default()
+        } else {
+            time::Duration::ZERO
+        };
+        Ok(Writer {
+            out,
+            start: clock.now(),
+            origin,
+        })
+    }
+
+    pub(crate) fn write(
+        &mut self,
+        direction: Direction,
+        packet: &[u8],
+        now: time::Instant,
+    ) -> io::Result<()> {
+        let ts = (self.origin + now.saturating_duration_since(self.start)).as_micros() as u64;
+        let mut epb = Vec::with_capacity(32 + packet.len());
+        // the only interface in the section
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(ts as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(packet);
+        pad(&mut epb);
+
+        // the low two bits of epb_flags are the direction
+        let flags: u32 = match direction {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+        };
+        epb.extend_from_slice(&OPT_EPB_FLAGS.to_le_bytes());
+        epb.extend_from_slice(&4u16.to_le_bytes());
+        epb.extend_from_slice(&flags.to_le_bytes());
+        epb.extend_from_slice(&OPT_END.to_le_bytes());
+        epb.extend_from_slice(&0u16.to_le_bytes());
+
+        // one write per packet, so whatever is reading the file along never sees half a block
+        self.out.write_all(&block(ENHANCED_PACKET, &epb))?;
+        self.out.flush()
+    }
+}
+
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let len = (12 + body.len()) as u32;
+    let mut b = Vec::with_capacity(len as usize);
+    b.extend_from_slice(&kind.to_le_bytes());
+    b.extend_from_slice(&len.to_le_bytes());
+    b.extend_from_slice(body);
+    b.extend_from_slice(&len.to_le_bytes());
+    b
+}
+
+// everything in a block is aligned to 32 bits
+fn pad(b: &mut Vec<u8>) {
+    while !b.len().is_multiple_of(4) {
+        b.push(0);
+    }
+}
+
+// a packet read back from a capture
+pub(crate) struct Packet {
+    // since the epoch, going by the clock of whoever captured it
+    pub(crate) time: time::Duration,
+    pub(crate) linktype: u16,
+    pub(crate) data: Vec<u8>,
+}
+
+// reads all packets from a classic pcap or a pcapng capture. only enhanced packet blocks are
+// read from pcapng, which is what Wireshark and we write.
+pub(crate) fn read(file: &[u8]) -> io::Result<Vec<Packet>> {
+    let magic = file.get(..4).ok_or_else(truncated)?;
+    match magic {
+        [0x0a, 0x0d, 0x0d, 0x0a] => read_pcapng(file),
+        _ => read_pcap(file),
+    }
+}
+
+fn read_pcap(file: &[u8]) -> io::Result<Vec<Packet>> {
+    let header = file.get(..24).ok_or_else(truncated)?;
+    let (le, nanos) = match header[..4] {
+        [0xd4, 0xc3, 0xb2, 0xa1] => (true, false),
+        [0xa1, 0xb2, 0xc3, 0xd4] => (false, false),
+        [0x4d, 0x3c, 0xb2, 0xa1] => (true, true),
+        [0xa1, 0xb2, 0x3c, 0x4d] => (false, true),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a pcap file",
+            ))
+        }
+    };
+    let e = Endian(le);
+    let linktype = e.u32(header, 20) as u16;
+
+    let mut packets = Vec::new();
+    let mut at = 24;
+    while at < file.len() {
+        let record = file.get(at..at + 16).ok_or_else(truncated)?;
+        let secs = e.u32(record, 0) as u64;
+        let frac = e.u32(record, 4);
+        let len = e.u32(record, 8) as usize;
+        let data = file.get(at + 16..at + 16 + len).ok_or_else(truncated)?;
+        let time = if nanos {
+            time::Duration::new(secs, frac)
+        } else {
+            time::Duration::new(secs, 0) + time::Duration::from_micros(frac as u64)
+        };
+        packets.push(Packet {
+            time,
+            linktype,
+            data: data.to_vec(),
+        });
+        at += 16 + len;
+    }
+    Ok(packets)
+}
+
+fn read_pcapng(file: &[u8]) -> io::Result<Vec<Packet>> {
+    let mut e = Endian(true);
+    // link type and timestamp units of every interface in the current section
+    let mut interfaces: Vec<(u16, Resolution)> = Vec::new();
+    let mut packets = Vec::new();
+    let mut at = 0;
+    while at < file.len() {
+        let head = file.get(at..at + 12).ok_or_else(truncated)?;
+        if head[..4] == [0x0a, 0x0d, 0x0d, 0x0a] {
+            // a new section, which may well have been written on a machine of the other
+            // endianness
+            e = Endian(head[8..12] == BYTE_ORDER_MAGIC.to_le_bytes());
+            interfaces.clear();
+        }
+        let kind = e.u32(head, 0);
+        let len = e.u32(head, 4) as usize;
+        if len < 12 || !len.is_multiple_of(4) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad pcapng block length",
+            ));
+        }
+        let body = file.get(at + 8..at + len - 4).ok_or_else(truncated)?;
+        match kind {
+            INTERFACE_DESCRIPTION => {
+                if body.len() < 8 {
+                    return Err(truncated());
+                }
+                let linktype = e.u16(body, 0);
+                let resolution = options(e, body.get(8..).unwrap_or_default())
+                    .find(|(code, _)| *code == OPT_IF_TSRESOL)
+                    .and_then(|(_, v)| v.first().copied())
+                    .map_or(Resolution::Micros, Resolution::from_option);
+                interfaces.push((linktype, resolution));
+            }
+            ENHANCED_PACKET => {
+                if body.len() < 20 {
+                    return Err(truncated());
+                }
+                let interface = e.u32(body, 0) as usize;
+                let &(linktype, resolution) = interfaces.get(interface).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "packet for unknown interface")
+                })?;
+                let ts = (e.u32(body, 4) as u64) << 32 | e.u32(body, 8) as u64;
+                let caplen = e.u32(body, 12) as usize;
+                let data = body.get(20..20 + caplen).ok_or_else(truncated)?;
+                packets.push(Packet {
+                    time: resolution.duration(ts),
+                    linktype,
+                    data: data.to_vec(),
+                });
+            }
+            _ => {}
+        }
+        at += len;
+    }
+    Ok(packets)
+}
+
+const OPT_IF_TSRESOL: u16 = 9;
+
+#[derive(Clone, Copy)]
+enum Resolution {
+    Micros,
+    // 10^-n seconds
+    Decimal(u32),
+    // 2^-n seconds
+    Binary(u32),
+}
+
+impl Resolution {
+    fn from_option(v: u8) -> Self {
+        if v & 0x80 == 0 {
+            Resolution::Decimal(v as u32)
+        } else {
+            Resolution::Binary((v & 0x7f) as u32)
+        }
+    }
+
+    fn duration(self, ts: u64) -> time::Duration {
+        match self {
+            Resolution::Micros => time::Duration::from_micros(ts),
+            Resolution::Decimal(n) => {
+                let per_sec = 10u64.saturating_pow(n);
+                time::Duration::from_secs_f64(ts as f64 / per_sec as f64)
+            }
+            Resolution::Binary(n) => time::Duration::from_secs_f64(ts as f64 / 2f64.powi(n as i32)),
+        }
+    }
+}
+
+// the options at the end of a block body, as (code, value)
+fn options(e: Endian, mut b: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if b.len() < 4 {
+            return None;
+        }
+        let code = e.u16(b, 0);
+        let len = e.u16(b, 2) as usize;
+        if code == OPT_END || b.len() < 4 + len {
+            return None;
+        }
+        let value = &b[4..4 + len];
+        b = &b[std::cmp::min(b.len(), 4 + len.div_ceil(4) * 4)..];
+        Some((code, value))
+    })
+}
+
+#[derive(Clone, Copy)]
+struct Endian(bool);
+
+impl Endian {
+    fn u16(self, b: &[u8], at: usize) -> u16 {
+        let v = [b[at], b[at + 1]];
+        if self.0 {
+            u16::from_le_bytes(v)
+        } else {
+            u16::from_be_bytes(v)
+        }
+    }
+
+    fn u32(self, b: &[u8], at: usize) -> u32 {
+        let v = [b[at], b[at + 1], b[at + 2], b[at + 3]];
+        if self.0 {
+            u32::from_le_bytes(v)
+        } else {
+            u32::from_be_bytes(v)
+        }
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "capture is truncated")
+}
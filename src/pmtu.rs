@@ -0,0 +1,275 @@
+use std::time;
+
+/*
+  Path MTU discovery
+
+  We start out assuming the path takes packets as big as our own link and the peer's MSS allow,
+  set DF on everything, and shrink when a router sends back "fragmentation needed" or "packet too
+  big" (RFC 1191, RFC 8201).
+
+  Plenty of networks filter ICMP though, so we also do packetization layer PMTUD (RFC 8899):
+
+      BASE ---(black hole)---> SEARCH ---(probe acked/lost)---> SEARCH_COMPLETE
+        ^                        ^                                   |
+        |                        +----------(RAISE_TIMER)-----------+
+        +-----------------(full-sized segments keep getting lost)----+
+
+  A probe is a segment of real data sent larger than the current PMTU. If it's acked the path
+  takes packets that big, if it's lost MAX_PROBES times the search range ends below it.
+*/
+
+// RFC 8899 5.1.2
+const MAX_PROBES: u32 = 3;
+// how long a search result or an ICMP message is trusted before we look for a bigger PMTU again
+// (RFC 8899 5.1.1)
+const RAISE_TIMER: time::Duration = time::Duration::from_secs(600);
+// how many times in a row we retransmit without progress before we suspect a black hole
+const BLACK_HOLE_RETRIES: u32 = 3;
+// RFC 8899 5.1.2 recommends 1200 for IPv4, and IPv6 links have to take 1280 anyway
+pub(crate) const BASE_V4: usize = 1200;
+pub(crate) const BASE_V6: usize = 1280;
+// the least a router can talk us down to. an IPv4 path may really be smaller than the base, and
+// every IPv4 host has to take 576 (RFC 791). RFC 8899 would go down to 68, but then a forged
+// ICMP message could have us send segments of a few bytes.
+pub(crate) const MIN_V4: usize = 576;
+pub(crate) const MIN_V6: usize = 1280;
+
+pub(crate) struct Pmtu {
+    // the largest packet we know the path takes
+    current: usize,
+    // a router's word can take us below the base, but not below this
+    min: usize,
+    // what we fall back to when packets vanish without a word, most paths take it
+    base: usize,
+    // what our link and the peer can take, no point probing past it
+    max: usize,
+    // the largest packet that might still make it, so probes go into current+1..=high
+    high: usize,
+    probe: Option<Probe>,
+    // how many probes of the current size got lost
+    failures: u32,
+    // when we last stopped searching
+    searched_at: Option<time::Instant>,
+}
+
+struct Probe {
+    // the sequence number following the probe's data
+    end: u32,
+    size: usize,
+}
+
+impl Pmtu {
+    pub(crate) fn new(min: usize, base: usize, max: usize) -> Self {
+        Pmtu {
+            current: max,
+            min: std::cmp::min(min, max),
+            base: std::cmp::min(base, max),
+            max,
+            high: max,
+            probe: None,
+            failures: 0,
+            searched_at: None,
+        }
+    }
+
+    pub(crate) fn current(&self) -> usize {
+        self.current
+    }
+
+    // the size of the next probe to send, if it's time for one
+    pub(crate) fn probe_size(&mut self, now: time::Instant) -> Option<usize> {
+        if self.probe.is_some() {
+            return None;
+        }
+        if self.high <= self.current {
+            if self
+                .searched_at
+                .is_none_or(|t| now.duration_since(t) <= RAISE_TIMER)
+            {
+                return None;
+            }
+            self.high = self.max;
+            self.failures = 0;
+        }
+        Some(self.current + (self.high - self.current).div_ceil(2))
+    }
+
+    pub(crate) fn probe_sent(&mut self, end: u32, size: usize) {
+        self.probe = Some(Probe { end, size });
+    }
+
+    // everything before una has been acked
+    pub(crate) fn on_ack(&mut self, una: u32, now: time::Instant) {
+        let Some(p) = &self.probe else {
+            return;
+        };
+        if una.wrapping_sub(p.end) > 1 << 31 {
+            return;
+        }
+        self.current = p.size;
+        self.probe = None;
+        self.failures = 0;
+        self.search_done_if_converged(now);
+    }
+
+    // the retransmission timer went off for the retries-th time without progress
+    pub(crate) fn on_timeout(&mut self, retries: u32, now: time::Instant) {
+        if let Some(p) = self.probe.take() {
+            // we can't tell whether it was the probe that got lost, but it's the likely one
+            self.failures += 1;
+            if self.failures >= MAX_PROBES {
+                self.high = p.size - 1;
+                self.failures = 0;
+                self.search_done_if_converged(now);
+            }
+            return;
+        }
+        if retries < BLACK_HOLE_RETRIES || self.current <= self.base {
+            return;
+        }
+        // full-sized segments stopped getting through and nobody told us, start over
+        eprintln!(
+            "suspecting a PMTU black hole, falling back to {}",
+            self.base
+        );
+        self.current = self.base;
+        self.high = self.max;
+        self.failures = 0;
+    }
+
+    // a router told us it can only forward packets up to mtu. returns whether the PMTU went
+    // down.
+    pub(crate) fn on_too_big(&mut self, mtu: usize, now: time::Instant) -> bool {
+        if self.probe.as_ref().is_some_and(|p| p.size > mtu) {
+            self.probe = None;
+            self.failures = 0;
+        }
+        // RFC 8899 4.6.2: don't trust it to take us below the minimum or to raise the PMTU
+        let mtu = std::cmp::max(mtu, self.min);
+        self.high = std::cmp::min(self.high, mtu);
+        if mtu >= self.current {
+            self.search_done_if_converged(now);
+            return false;
+        }
+        self.current = mtu;
+        self.searched_at = Some(now);
+        true
+    }
+
+    fn search_done_if_converged(&mut self, now: time::Instant) {
+        if self.high <= self.current {
+            self.high = self.current;
+            self.searched_at = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what's left after a black hole: the base, and the whole range above it to search
+    fn searching(now: time::Instant) -> Pmtu {
+        let mut p = Pmtu::new(576, 1200, 1500);
+        assert_eq!(p.probe_size(now), None);
+        p.on_timeout(BLACK_HOLE_RETRIES, now);
+        assert_eq!(p.current(), 1200);
+        p
+    }
+
+    #[test]
+    fn probe_acked() {
+        let now = time::Instant::now();
+        let mut p = searching(now);
+        assert_eq!(p.probe_size(now), Some(1350));
+        p.probe_sent(100, 1350);
+        // one probe at a time
+        assert_eq!(p.probe_size(now), None);
+        p.on_ack(99, now);
+        assert_eq!(p.current(), 1200);
+        p.on_ack(100, now);
+        assert_eq!(p.current(), 1350);
+
+        // halving the range up to the max
+        assert_eq!(p.probe_size(now), Some(1425));
+        p.probe_sent(200, 1425);
+        p.on_ack(300, now);
+        assert_eq!(p.current(), 1425);
+        let mut size = p.probe_size(now).unwrap();
+        while size < 1500 {
+            p.probe_sent(400, size);
+            p.on_ack(400, now);
+            size = p.probe_size(now).unwrap();
+        }
+        p.probe_sent(400, 1500);
+        p.on_ack(400, now);
+        assert_eq!(p.current(), 1500);
+        assert_eq!(p.probe_size(now), None);
+    }
+
+    #[test]
+    fn probe_lost() {
+        let now = time::Instant::now();
+        let mut p = searching(now);
+        for _ in 0..MAX_PROBES {
+            assert_eq!(p.probe_size(now), Some(1350));
+            p.probe_sent(100, 1350);
+            p.on_timeout(1, now);
+        }
+        // it's the top of the range now
+        assert_eq!(p.current(), 1200);
+        assert_eq!(p.probe_size(now), Some(1275));
+    }
+
+    #[test]
+    fn black_hole() {
+        let now = time::Instant::now();
+        let mut p = Pmtu::new(576, 1200, 1500);
+        for retries in 1..BLACK_HOLE_RETRIES {
+            p.on_timeout(retries, now);
+            assert_eq!(p.current(), 1500);
+        }
+        p.on_timeout(BLACK_HOLE_RETRIES, now);
+        assert_eq!(p.current(), 1200);
+        // the base is as low as it goes
+        p.on_timeout(BLACK_HOLE_RETRIES + 1, now);
+        assert_eq!(p.current(), 1200);
+    }
+
+    #[test]
+    fn too_big() {
+        let now = time::Instant::now();
+        let mut p = Pmtu::new(576, 1200, 1500);
+        assert!(p.on_too_big(1400, now));
+        assert_eq!(p.current(), 1400);
+        // it can't raise the PMTU
+        assert!(!p.on_too_big(1450, now));
+        assert_eq!(p.current(), 1400);
+        // it can take it below the base, as the path may really be that small
+        assert!(p.on_too_big(1000, now));
+        assert_eq!(p.current(), 1000);
+        // but not below the minimum
+        assert!(p.on_too_big(68, now));
+        assert_eq!(p.current(), 576);
+        assert!(!p.on_too_big(500, now));
+        // and what a router told us stays, packets going missing don't raise it to the base
+        p.on_timeout(BLACK_HOLE_RETRIES, now);
+        assert_eq!(p.current(), 576);
+
+        // and it's trusted until the raise timer runs out
+        assert_eq!(p.probe_size(now + RAISE_TIMER), None);
+        let later = now + RAISE_TIMER + time::Duration::from_secs(1);
+        assert_eq!(p.probe_size(later), Some(1038));
+    }
+
+    // a probe that's too big for the router doesn't count as lost
+    #[test]
+    fn too_big_probe() {
+        let now = time::Instant::now();
+        let mut p = searching(now);
+        p.probe_sent(100, 1350);
+        assert!(!p.on_too_big(1300, now));
+        assert_eq!(p.current(), 1200);
+        assert_eq!(p.probe_size(now), Some(1250));
+    }
+}
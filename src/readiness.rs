@@ -0,0 +1,74 @@
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+/*
+  Readiness notifications
+
+  Sockets on an interface don't have file descriptors of their own, so an event loop can't
+  register them with epoll or mio. What it can register is one fd per interface that turns
+  readable whenever any socket on it may have become readable, writable or acceptable:
+
+      packet_loop --byte--> [ pair ] --> Readiness --> epoll/mio
+
+  It's level-triggered and says nothing about which socket it was, so the loop clears it and
+  then tries its (nonblocking) sockets until they return WouldBlock. Whatever happens after the
+  clear makes it readable again, so no wakeup gets lost in between.
+*/
+
+pub(crate) struct Notifier {
+    tx: UnixStream,
+    rx: UnixStream,
+}
+
+impl Notifier {
+    pub(crate) fn new() -> io::Result<Self> {
+        let (tx, rx) = UnixStream::pair()?;
+        tx.set_nonblocking(true)?;
+        rx.set_nonblocking(true)?;
+        Ok(Notifier { tx, rx })
+    }
+
+    pub(crate) fn notify(&self) {
+        match (&self.tx).write(&[1]) {
+            // full means there's plenty to wake up to already
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => eprintln!("readiness notification failed {:?}", e),
+            Ok(_) => {}
+        }
+    }
+
+    pub(crate) fn watch(&self) -> io::Result<Readiness> {
+        Ok(Readiness(self.rx.try_clone()?))
+    }
+}
+
+// an fd that's readable when something happened on the interface it came from
+pub struct Readiness(UnixStream);
+
+impl Readiness {
+    // drains the notifications, call before going through the sockets
+    pub fn clear(&self) -> io::Result<()> {
+        let mut buf = [0u8; 256];
+        loop {
+            match (&self.0).read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl AsRawFd for Readiness {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl AsFd for Readiness {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
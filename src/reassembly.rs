@@ -0,0 +1,311 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::Ipv4Addr;
+use std::time;
+
+/*
+  IPv4 reassembly (RFC 791 3.2)
+
+  Fragments are collected per (src, dst, protocol, id), ordered by their offset. Once the last
+  fragment has arrived and there are no holes left, the payloads are glued back together.
+
+  RFC 791 lets later fragments overwrite earlier ones where they overlap, which has made for a
+  long line of IDS evasion tricks, so like RFC 5722 does for IPv6 we throw the whole datagram
+  away instead. Exact duplicates are fine, those are just retransmissions. A poisoned datagram
+  stays around until it times out, so the rest of its fragments get dropped too.
+
+  When there are too many bytes or too many datagrams, the oldest go first.
+*/
+
+// how long we hold on to an incomplete datagram, what Linux does
+const TIMEOUT: time::Duration = time::Duration::from_secs(30);
+// the most fragment bytes we keep around across all datagrams
+const MAX_BYTES: usize = 4 << 20;
+// no one sends datagrams in this many pieces
+const MAX_FRAGMENTS: usize = 64;
+// how many datagrams we keep track of at once, poisoned ones included. those hold no bytes,
+// but a stream of bad fragments with fresh ids would pile them up otherwise.
+const MAX_DATAGRAMS: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+struct Key {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: etherparse::IpNumber,
+    id: u16,
+}
+
+struct Datagram {
+    started: time::Instant,
+    // payload of each fragment by its offset
+    fragments: BTreeMap<usize, Vec<u8>>,
+    // known once the fragment without MF shows up
+    len: Option<usize>,
+    // something was wrong with it, so any more fragments are dropped until it times out
+    poisoned: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct Reassembly {
+    datagrams: HashMap<Key, Datagram>,
+    // the same datagrams, oldest first, for what to expire or throw out next
+    by_age: BTreeSet<(time::Instant, Key)>,
+    bytes: usize,
+}
+
+impl Reassembly {
+    // takes the fragment and returns the whole payload once the datagram is complete
+    pub(crate) fn on_fragment(
+        &mut self,
+        ip: &etherparse::Ipv4HeaderSlice,
+        payload: &[u8],
+        now: time::Instant,
+    ) -> Option<Vec<u8>> {
+        self.expire(now);
+        let key = Key {
+            src: ip.source_addr(),
+            dst: ip.destination_addr(),
+            protocol: ip.protocol(),
+            id: ip.identification(),
+        };
+        let offset = ip.fragments_offset().value() as usize * 8;
+        let more = ip.more_fragments();
+        let end = offset + payload.len();
+
+        let bad = payload.is_empty()
+            // the whole datagram has to fit in a total length
+            || end + ip.ihl() as usize * 4 > u16::MAX as usize
+            // everything but the last fragment has to be a multiple of 8 bytes long
+            || (more && !payload.len().is_multiple_of(8));
+        if bad {
+            eprintln!("dropping bad fragment of {:?}", key);
+            return None;
+        }
+
+        self.make_room(&key, payload.len());
+        let d = self.datagrams.entry(key).or_insert_with(|| {
+            self.by_age.insert((now, key));
+            Datagram {
+                started: now,
+                fragments: BTreeMap::new(),
+                len: None,
+                poisoned: false,
+            }
+        });
+        if d.poisoned {
+            return None;
+        }
+
+        if d.fragments.get(&offset).is_some_and(|f| f[..] == *payload) {
+            // retransmitted
+            return None;
+        }
+        let overlaps = d
+            .fragments
+            .range(..end)
+            .next_back()
+            .is_some_and(|(&o, f)| o + f.len() > offset);
+        let past_end = d.len.is_some_and(|len| end > len || (!more && end != len))
+            || (!more
+                && d.fragments
+                    .last_key_value()
+                    .is_some_and(|(&o, f)| o + f.len() > end));
+        if overlaps || past_end || d.fragments.len() >= MAX_FRAGMENTS {
+            eprintln!("dropping datagram {:?} with inconsistent fragments", key);
+            self.bytes -= d.fragments.values().map(Vec::len).sum::<usize>();
+            d.fragments.clear();
+            d.poisoned = true;
+            return None;
+        }
+        if !more {
+            d.len = Some(end);
+        }
+        d.fragments.insert(offset, payload.to_vec());
+        self.bytes += payload.len();
+
+        let len = d.len?;
+        let mut next = 0;
+        for (&o, f) in &d.fragments {
+            if o != next {
+                // still a hole
+                return None;
+            }
+            next = o + f.len();
+        }
+        debug_assert_eq!(next, len);
+
+        let d = self.remove(&key).unwrap();
+        let mut whole = Vec::with_capacity(len);
+        for f in d.fragments.into_values() {
+            whole.extend(f);
+        }
+        Some(whole)
+    }
+
+    // forget about datagrams that took too long to arrive
+    pub(crate) fn expire(&mut self, now: time::Instant) {
+        while let Some(&(started, key)) = self.by_age.first() {
+            if now.duration_since(started) < TIMEOUT {
+                return;
+            }
+            // TODO: ICMP time exceeded if we had the first fragment (RFC 792)
+            self.remove(&key);
+        }
+    }
+
+    // when expire next has something to throw out
+    pub(crate) fn next_expiry(&self) -> Option<time::Instant> {
+        self.by_age.first().map(|&(started, _)| started + TIMEOUT)
+    }
+
+    // throw out the oldest datagrams until another n bytes fit, and another datagram if key
+    // is a new one
+    fn make_room(&mut self, key: &Key, n: usize) {
+        loop {
+            let full = self.bytes + n > MAX_BYTES
+                || (self.datagrams.len() >= MAX_DATAGRAMS && !self.datagrams.contains_key(key));
+            if !full {
+                return;
+            }
+            let Some(&(_, oldest)) = self.by_age.first() else {
+                return;
+            };
+            self.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Datagram> {
+        let d = self.datagrams.remove(key)?;
+        self.by_age.remove(&(d.started, *key));
+        self.bytes -= d.fragments.values().map(Vec::len).sum::<usize>();
+        Some(d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(
+        r: &mut Reassembly,
+        id: u16,
+        offset: usize,
+        more: bool,
+        payload: &[u8],
+        now: time::Instant,
+    ) -> Option<Vec<u8>> {
+        let mut h = etherparse::Ipv4Header::new(
+            payload.len() as u16,
+            64,
+            etherparse::IpNumber::UDP,
+            [10, 0, 0, 2],
+            [10, 0, 0, 1],
+        )
+        .unwrap();
+        h.identification = id;
+        h.more_fragments = more;
+        h.fragment_offset = etherparse::IpFragOffset::try_new((offset / 8) as u16).unwrap();
+        let bytes = h.to_bytes();
+        let ip = etherparse::Ipv4HeaderSlice::from_slice(&bytes).unwrap();
+        r.on_fragment(&ip, payload, now)
+    }
+
+    fn data(n: usize) -> Vec<u8> {
+        (0..n).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn in_order() {
+        let mut r = Reassembly::default();
+        let now = time::Instant::now();
+        let d = data(40);
+        assert_eq!(fragment(&mut r, 1, 0, true, &d[..16], now), None);
+        assert_eq!(fragment(&mut r, 1, 16, true, &d[16..32], now), None);
+        assert_eq!(fragment(&mut r, 1, 32, false, &d[32..], now), Some(d));
+        assert!(r.datagrams.is_empty() && r.by_age.is_empty());
+        assert_eq!(r.bytes, 0);
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut r = Reassembly::default();
+        let now = time::Instant::now();
+        let d = data(40);
+        assert_eq!(fragment(&mut r, 1, 32, false, &d[32..], now), None);
+        assert_eq!(fragment(&mut r, 1, 0, true, &d[..16], now), None);
+        // retransmitted, which is fine
+        assert_eq!(fragment(&mut r, 1, 0, true, &d[..16], now), None);
+        // another datagram in between
+        assert_eq!(fragment(&mut r, 2, 0, true, &d[..8], now), None);
+        assert_eq!(fragment(&mut r, 1, 16, true, &d[16..32], now), Some(d));
+        assert_eq!(r.bytes, 8);
+    }
+
+    // overlapping fragments throw out the whole datagram, and whatever else comes for it
+    #[test]
+    fn overlap_poisons() {
+        let mut r = Reassembly::default();
+        let now = time::Instant::now();
+        let d = data(40);
+        assert_eq!(fragment(&mut r, 1, 0, true, &d[..16], now), None);
+        assert_eq!(fragment(&mut r, 1, 8, true, &d[8..24], now), None);
+        assert_eq!(r.bytes, 0);
+        assert_eq!(fragment(&mut r, 1, 16, true, &d[16..32], now), None);
+        assert_eq!(fragment(&mut r, 1, 32, false, &d[32..], now), None);
+        assert_eq!(r.bytes, 0);
+
+        // so does a last fragment that isn't where the end was
+        assert_eq!(fragment(&mut r, 2, 32, false, &d[32..], now), None);
+        assert_eq!(fragment(&mut r, 2, 24, false, &d[24..32], now), None);
+        assert_eq!(fragment(&mut r, 2, 0, true, &d[..24], now), None);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut r = Reassembly::default();
+        let start = time::Instant::now();
+        let d = data(40);
+        fragment(&mut r, 1, 0, true, &d[..16], start);
+        let later = start + time::Duration::from_secs(10);
+        fragment(&mut r, 2, 0, true, &d[..16], later);
+        assert_eq!(r.next_expiry(), Some(start + TIMEOUT));
+
+        r.expire(start + TIMEOUT);
+        assert_eq!(r.datagrams.len(), 1);
+        assert_eq!(r.next_expiry(), Some(later + TIMEOUT));
+        // what's left of the first one starts over
+        let done = start + TIMEOUT;
+        assert_eq!(fragment(&mut r, 1, 16, false, &d[16..], done), None);
+        assert_eq!(fragment(&mut r, 2, 16, false, &d[16..], done), Some(d));
+        r.expire(done + TIMEOUT);
+        assert!(r.datagrams.is_empty() && r.by_age.is_empty());
+        assert_eq!(r.bytes, 0);
+        assert_eq!(r.next_expiry(), None);
+    }
+
+    // poisoned datagrams count too, and the oldest goes first
+    #[test]
+    fn cap_evicts_oldest() {
+        let mut r = Reassembly::default();
+        let start = time::Instant::now();
+        let d = data(16);
+        for id in 0..MAX_DATAGRAMS as u16 {
+            let now = start + time::Duration::from_millis(id as u64);
+            fragment(&mut r, id, 0, true, &d[..8], now);
+            // poisoned
+            fragment(&mut r, id, 0, true, &d, now);
+        }
+        assert_eq!(r.datagrams.len(), MAX_DATAGRAMS);
+        assert_eq!(r.bytes, 0);
+
+        let now = start + time::Duration::from_secs(1);
+        fragment(&mut r, 5000, 0, true, &d[..8], now);
+        assert_eq!(r.datagrams.len(), MAX_DATAGRAMS);
+        assert_eq!(r.by_age.len(), MAX_DATAGRAMS);
+        assert!(!r.datagrams.keys().any(|k| k.id == 0));
+        // the next oldest is still poisoned, the one that's gone isn't anymore
+        assert_eq!(fragment(&mut r, 1, 0, true, &d[..8], now), None);
+        assert_eq!(fragment(&mut r, 1, 8, false, &d[8..], now), None);
+        assert_eq!(fragment(&mut r, 0, 0, true, &d[..8], now), None);
+        assert_eq!(fragment(&mut r, 0, 8, false, &d[8..], now), Some(d));
+    }
+}
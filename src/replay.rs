@@ -0,0 +1,154 @@
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+use crate::device::Device;
+use crate::pcap;
+
+/*
+  Replaying captures
+
+  Once started, a feeder thread pushes what one peer sent in a capture through a socket pair into the
+  device, optionally waiting as long between packets as the peer did, while everything the
+  stack sends back is kept for the caller to look at:
+
+      capture --> feeder --> ReplayDevice --> packet_loop
+                                    |
+                  Replay::sent <----+
+
+  Link-layer headers are stripped on the way in, so the device carries bare IP packets and
+  goes with Interface::with_device whatever the capture was taken on.
+*/
+
+// length of the Linux "cooked" header that captures on the any device have
+const LINUX_SLL_LEN: usize = 16;
+
+pub struct ReplayDevice {
+    link: UnixDatagram,
+    sent: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+// the caller's side of a replay
+pub struct Replay {
+    sent: Arc<Mutex<Vec<Vec<u8>>>>,
+    // when and what to feed, until start
+    packets: Vec<(time::Duration, Vec<u8>)>,
+    feed: Option<UnixDatagram>,
+    timing: bool,
+    feeder: Option<thread::JoinHandle<()>>,
+}
+
+// sets up a replay of the packets peer sent in the pcap or pcapng capture at path. nothing
+// is fed to the device until Replay::start, so there's time to bind whatever the capture
+// talks to.
+pub fn replay(
+    path: impl AsRef<Path>,
+    peer: IpAddr,
+    timing: bool,
+) -> io::Result<(ReplayDevice, Replay)> {
+    let packets: Vec<_> = pcap::read(&std::fs::read(path)?)?
+        .into_iter()
+        .filter_map(|p| Some((p.time, ip_packet(p.linktype, &p.data)?.to_vec())))
+        .filter(|(_, ip)| {
+            etherparse::IpSlice::from_slice(ip).is_ok_and(|ip| ip.source_addr() == peer)
+        })
+        .collect();
+
+    let (feed, link) = UnixDatagram::pair()?;
+    let sent = Arc::<Mutex<Vec<Vec<u8>>>>::default();
+    Ok((
+        ReplayDevice {
+            link,
+            sent: sent.clone(),
+        },
+        Replay {
+            sent,
+            packets,
+            feed: Some(feed),
+            timing,
+            feeder: None,
+        },
+    ))
+}
+
+impl Replay {
+    // starts feeding packets to the device. with timing, packets are spaced out like they were
+    // in the capture, otherwise they go in as fast as the stack takes them.
+    pub fn start(&mut self) {
+        let Some(feed) = self.feed.take() else {
+            // already started
+            return;
+        };
+        let packets = std::mem::take(&mut self.packets);
+        let timing = self.timing;
+        self.feeder = Some(thread::spawn(move || {
+            let start = time::Instant::now();
+            let first = packets.first().map(|p| p.0).unwrap_or_default();
+            for (at, packet) in packets {
+                if timing {
+                    let due = start + at.saturating_sub(first);
+                    thread::sleep(due.saturating_duration_since(time::Instant::now()));
+                }
+                if feed.send(&packet).is_err() {
+                    // the interface is gone
+                    return;
+                }
+            }
+        }));
+    }
+
+    // blocks until every packet has been handed to the stack, which may not have gotten
+    // around to all of them yet
+    pub fn wait(&mut self) {
+        if let Some(feeder) = self.feeder.take() {
+            feeder.join().unwrap();
+        }
+    }
+
+    // the IP packets the stack has sent so far, in order
+    pub fn sent(&self) -> Vec<Vec<u8>> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl Device for ReplayDevice {
+    fn send(&mut self, frame: &[u8]) -> io::Result<usize> {
+        self.sent.lock().unwrap().push(frame.to_vec());
+        Ok(frame.len())
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.link.recv(buf)
+    }
+}
+
+impl AsRawFd for ReplayDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.link.as_raw_fd()
+    }
+}
+
+// the IP packet in a captured frame, if it is one
+fn ip_packet(linktype: u16, frame: &[u8]) -> Option<&[u8]> {
+    let (ethertype, payload) = match linktype {
+        pcap::LINKTYPE_RAW | pcap::LINKTYPE_IPV4 | pcap::LINKTYPE_IPV6 => return Some(frame),
+        pcap::LINKTYPE_ETHERNET => {
+            let (eth, payload) = etherparse::Ethernet2Header::from_slice(frame).ok()?;
+            (eth.ether_type, payload)
+        }
+        pcap::LINKTYPE_LINUX_SLL => {
+            let header = frame.get(..LINUX_SLL_LEN)?;
+            let ethertype = u16::from_be_bytes([header[14], header[15]]);
+            (ethertype.into(), &frame[LINUX_SLL_LEN..])
+        }
+        _ => return None,
+    };
+    match ethertype {
+        etherparse::EtherType::IPV4 | etherparse::EtherType::IPV6 => Some(payload),
+        _ => None,
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time;
+
+use crate::Quad;
+
+/*
+  SYN cookies
+
+  When a listener has too many half-open connections we stop keeping any state for new SYNs
+  and instead encode what we need to know into the ISS of our SYN-ACK:
+
+       31      27 26    24 23                                0
+      +----------+--------+-----------------------------------+
+      |  t mod 32|  mss   |    hash(secret, quad, irs, t)     |
+      +----------+--------+-----------------------------------+
+
+  t is a counter that ticks every 64 seconds, and mss is an index into MSS_TABLE. A peer that
+  really completes the handshake echoes ISS+1 back in its ACK, and from that we can rebuild the
+  connection.
+*/
+
+// the MSS values we can encode, the peer gets the largest one that doesn't exceed what it asked
+// for. the smallest is Linux's TCP_MIN_MSS, so a peer that asks for next to nothing doesn't get
+// more than it can take.
+const MSS_TABLE: [u16; 8] = [88, 216, 536, 1220, 1300, 1400, 1440, 1460];
+const COUNTER_PERIOD: time::Duration = time::Duration::from_secs(64);
+
+pub(crate) struct SynCookies {
+    secret: RandomState,
+    epoch: time::Instant,
+}
+
+impl Default for SynCookies {
+    fn default() -> Self {
+        SynCookies {
+            secret: RandomState::new(),
+            epoch: time::Instant::now(),
+        }
+    }
+}
+
+impl SynCookies {
+    pub(crate) fn generate(&self, quad: &Quad, irs: u32, mss: u16, now: time::Instant) -> u32 {
+        let t = self.counter(now);
+        let m = MSS_TABLE.iter().rposition(|&m| m <= mss).unwrap_or(0) as u32;
+        (t % 32) << 27 | m << 24 | self.hash(quad, irs, t)
+    }
+
+    // returns the MSS encoded in the cookie if the cookie is one we handed out to this quad
+    // during the last two counter periods
+    pub(crate) fn check(
+        &self,
+        quad: &Quad,
+        irs: u32,
+        cookie: u32,
+        now: time::Instant,
+    ) -> Option<u16> {
+        let now = self.counter(now);
+        let t = (0..2)
+            .filter_map(|age| now.checked_sub(age))
+            .find(|t| t % 32 == cookie >> 27)?;
+        if self.hash(quad, irs, t) != cookie & 0x00ff_ffff {
+            return None;
+        }
+        Some(MSS_TABLE[(cookie >> 24 & 0b111) as usize])
+    }
+
+    // whether a cookie handed out at the given time could still come back to us
+    pub(crate) fn may_be_valid(&self, sent_at: time::Instant, now: time::Instant) -> bool {
+        now.duration_since(sent_at) < 2 * COUNTER_PERIOD
+    }
+
+    fn counter(&self, now: time::Instant) -> u32 {
+        (now.duration_since(self.epoch).as_secs() / COUNTER_PERIOD.as_secs()) as u32
+    }
+
+    fn hash(&self, quad: &Quad, irs: u32, t: u32) -> u32 {
+        let mut h = self.secret.build_hasher();
+        quad.hash(&mut h);
+        irs.hash(&mut h);
+        t.hash(&mut h);
+        h.finish() as u32 & 0x00ff_ffff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    fn quad(port: u16) -> Quad {
+        Quad {
+            src: (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), port),
+            dst: (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 8080),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let c = SynCookies::default();
+        let now = c.epoch + time::Duration::from_secs(1000);
+        let cookie = c.generate(&quad(40000), 77, 1460, now);
+        assert_eq!(c.check(&quad(40000), 77, cookie, now), Some(1460));
+
+        // it's only good for the connection it was made for
+        assert_eq!(c.check(&quad(40001), 77, cookie, now), None);
+        assert_eq!(c.check(&quad(40000), 78, cookie, now), None);
+        assert_eq!(c.check(&quad(40000), 77, cookie ^ 1, now), None);
+        // a different secret makes different cookies
+        assert_eq!(
+            SynCookies::default().check(&quad(40000), 77, cookie, now),
+            None
+        );
+    }
+
+    // a cookie is good for the counter period it was made in and the one after
+    #[test]
+    fn expiry() {
+        let c = SynCookies::default();
+        let sent = c.epoch + COUNTER_PERIOD * 10;
+        let cookie = c.generate(&quad(40000), 77, 536, sent);
+        let later = |d: time::Duration| c.check(&quad(40000), 77, cookie, sent + d);
+        assert_eq!(
+            later(COUNTER_PERIOD - time::Duration::from_secs(1)),
+            Some(536)
+        );
+        assert_eq!(later(COUNTER_PERIOD), Some(536));
+        assert_eq!(later(2 * COUNTER_PERIOD), None);
+        // nor does it come back 32 periods later, when t mod 32 is the same again
+        assert_eq!(later(32 * COUNTER_PERIOD), None);
+
+        assert!(c.may_be_valid(sent, sent + COUNTER_PERIOD));
+        assert!(!c.may_be_valid(sent, sent + 2 * COUNTER_PERIOD));
+    }
+
+    // the peer gets the largest MSS in the table that isn't more than it asked for
+    #[test]
+    fn mss_table() {
+        let c = SynCookies::default();
+        let now = c.epoch;
+        let mss = |asked: u16| {
+            let cookie = c.generate(&quad(40000), 77, asked, now);
+            c.check(&quad(40000), 77, cookie, now).unwrap()
+        };
+        for m in MSS_TABLE {
+            assert_eq!(mss(m), m);
+        }
+        assert_eq!(mss(1459), 1440);
+        assert_eq!(mss(9000), 1460);
+        assert_eq!(mss(600), 536);
+        assert_eq!(mss(1024), 536);
+        assert_eq!(mss(100), 88);
+        // less than anything in the table still gets the smallest
+        assert_eq!(mss(40), 88);
+    }
+}
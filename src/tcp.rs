@@ -1,11 +1,73 @@
 use std::{
     collections::{BTreeMap, VecDeque},
     io::{self, Write},
-    time, u32, u8, usize,
+    time,
 };
 
 use bitflags::bitflags;
 
+use crate::ip::{self, IpHeader};
+use crate::link::Nic;
+use crate::pmtu::{self, Pmtu};
+use crate::Quad;
+
+// how many received bytes we're willing to hold on to until the application reads them
+const RECV_QUEUE_SIZE: usize = 1024;
+// and how many we hold on to until the peer has acked them
+const SEND_QUEUE_SIZE: usize = 1024;
+// what we may assume the peer can take if its SYN carries no MSS option (RFC 1122 4.2.2.6)
+const DEFAULT_MSS: u16 = 536;
+// the least we take the peer's MSS to be, Linux's TCP_MIN_MSS
+const MIN_MSS: u16 = 88;
+// the retransmission timeout until we've timed a round trip (RFC 6298 2.1)
+const INITIAL_RTO: time::Duration = time::Duration::from_secs(1);
+// and the least it gets from the round trips we time (RFC 6298 2.4)
+const MIN_RTO: time::Duration = time::Duration::from_secs(1);
+// how often we resend our SYN-ACK before giving up on a half-open connection, and our SYN before
+// giving up on connecting, as Linux does
+const SYN_ACK_RETRIES: u32 = 5;
+const SYN_RETRIES: u32 = 6;
+// how long we stay in TIME-WAIT, 2*MSL with Linux's idea of an MSL
+const TIME_WAIT: time::Duration = time::Duration::from_secs(60);
+// how long we keep retransmitting without hearing back before giving up on the connection, R2 in
+// RFC 1122 4.2.3.5
+const USER_TIMEOUT: time::Duration = time::Duration::from_secs(100);
+// how long we hold back a small segment to avoid silly window syndrome before sending it
+// anyway, RFC 1122 4.2.3.4 asks for 0.1 to 1 second
+const SWS_OVERRIDE: time::Duration = time::Duration::from_millis(200);
+// RFC 6298 2.5: however far the RTO backs off, it stops at a minute
+const MAX_RTO: time::Duration = time::Duration::from_secs(60);
+
+// what an interface's connections go by. the defaults are the constants above, an
+// InterfaceBuilder can change any of them.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Config {
+    // the largest packet we send
+    pub(crate) mtu: usize,
+    pub(crate) recv_buffer: usize,
+    pub(crate) send_buffer: usize,
+    pub(crate) initial_rto: time::Duration,
+    pub(crate) syn_retries: u32,
+    pub(crate) syn_ack_retries: u32,
+    pub(crate) time_wait: time::Duration,
+    pub(crate) user_timeout: time::Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            mtu: ip::MTU,
+            recv_buffer: RECV_QUEUE_SIZE,
+            send_buffer: SEND_QUEUE_SIZE,
+            initial_rto: INITIAL_RTO,
+            syn_retries: SYN_RETRIES,
+            syn_ack_retries: SYN_ACK_RETRIES,
+            time_wait: TIME_WAIT,
+            user_timeout: USER_TIMEOUT,
+        }
+    }
+}
+
 bitflags! {
     pub(crate) struct Available: u8 {
     const READ = 0b00000001;
@@ -13,70 +75,98 @@ bitflags! {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum State {
-    //Closed,
+    Closed,
     //Listen,
+    SynSent,
     SyncRcvd,
     Estab,
     FinWait1,
     FinWait2,
+    CloseWait,
     Closing,
+    LastAck,
     TimeWait,
 }
 
+#[allow(dead_code)]
 impl State {
     fn is_synchorized(&self) -> bool {
-        match *self {
-            State::SyncRcvd => false,
-            State::Estab | State::FinWait1 | State::TimeWait | State::FinWait2 => true,
-            _ => unimplemented!(),
-        }
+        !matches!(*self, State::Closed | State::SynSent | State::SyncRcvd)
     }
 
     fn have_sent_fin(&self) -> bool {
-        match *self {
-            State::SyncRcvd | State::Estab => false,
-            State::FinWait1 | State::TimeWait | State::FinWait2 => true,
-            _ => unimplemented!(),
-        }
+        matches!(
+            *self,
+            State::FinWait1 | State::FinWait2 | State::Closing | State::LastAck | State::TimeWait
+        )
     }
 }
 
 pub struct Connection {
     state: State,
+    quad: Quad,
+    // we connected rather than accepted
+    active: bool,
     send: SendSequenceSpace,
     recv: RecvSequenceSpace,
-    ip: etherparse::Ipv4Header,
+    ip: IpHeader,
     tcp: etherparse::TcpHeader,
     timers: Timers,
+    config: Config,
+    // how big our packets may be, which together with the peer's MSS decides our segment size
+    pmtu: Pmtu,
 
     pub(crate) incoming: VecDeque<u8>,
     pub(crate) unacked: VecDeque<u8>,
     pub(crate) closed: bool,
     closed_at: Option<u32>,
+    // why the connection was aborted, reported by every read and write from then on
+    error: Option<io::ErrorKind>,
+    // the last ICMP error we didn't act on, reported instead of a plain timeout if the
+    // connection times out (RFC 1122 4.2.3.9)
+    soft_error: Option<io::ErrorKind>,
+    // nobody will read from or write to it anymore, so it can go once it's closed
+    pub(crate) orphaned: bool,
+    // whether to send a RST and close on the next tick
+    reset: bool,
 }
 
 struct Timers {
     send_times: BTreeMap<u32, time::Instant>,
-    srtt: f64,
+    // smoothed round trip time and its variation, in seconds, none until we've timed one
+    srtt: Option<f64>,
+    rttvar: f64,
+    syn_ack_retries: u32,
+    // when we entered TIME-WAIT
+    time_wait: Option<time::Instant>,
+    // when we started retransmitting without getting anything acked
+    retransmitting_since: Option<time::Instant>,
+    // and how many times we've done so
+    retransmissions: u32,
+    // since when send_new has been holding back a segment too small to send
+    sws_held: Option<time::Instant>,
 }
 
 impl Connection {
     pub(crate) fn is_rcv_closed(&self) -> bool {
-        if let State::TimeWait = self.state {
-            // TODO:any state after rvc FIN, so also CLOSE-WAIT, LAST-ACK, CLOSED, CLOSING
-            true
-        } else {
-            false
-        }
+        // any state after we've received a FIN
+        matches!(
+            self.state,
+            State::CloseWait | State::LastAck | State::Closing | State::TimeWait | State::Closed
+        )
     }
 
     fn availability(&self) -> Available {
         let mut a = Available::empty();
-        if self.is_rcv_closed() || !self.incoming.is_empty() {
+        if self.is_rcv_closed() || !self.incoming.is_empty() || self.error.is_some() {
             a |= Available::READ;
         }
-        // TODO: set Available::Write
+        // a write would go through, or fail right away
+        if self.unacked.len() < self.config.send_buffer || self.error.is_some() {
+            a |= Available::WRITE;
+        }
         a
     }
 }
@@ -99,12 +189,14 @@ struct SendSequenceSpace {
     nxt: u32,
     // send window
     wnd: u16,
-    // send urgent pointer
-    up: bool,
+    // largest window the peer has ever offered us
+    max_wnd: u16,
+    // send urgent pointer, the sequence number of the octet following the urgent data
+    up: Option<u32>,
     //segment sequence number used for last window update
-    wl1: usize,
+    wl1: u32,
     // segment acknowledgment number used for last window update
-    wl2: usize,
+    wl2: u32,
     // initial send sequence number
     iss: u32,
 }
@@ -126,85 +218,172 @@ struct RecvSequenceSpace {
     nxt: u32,
     // receive window
     wnd: u16,
-    // receive urgent pointer
-    up: bool,
+    // receive urgent pointer, the sequence number of the octet following the urgent data
+    up: Option<u32>,
     // initial receive sequence number
+    #[allow(dead_code)]
     irs: u32,
 }
 
 impl Connection {
-    pub fn accept<'a>(
-        nic: &mut tun_tap::Iface,
-        iph: etherparse::Ipv4HeaderSlice<'a>,
+    pub(crate) fn accept<'a>(
+        nic: &mut Nic,
+        q: &Quad,
         tcph: etherparse::TcpHeaderSlice<'a>,
-        data: &'a [u8],
+        _data: &'a [u8],
+        iss: u32,
+        config: &Config,
     ) -> io::Result<Option<Self>> {
-        eprintln!("came to accept");
         if !tcph.syn() {
             // only SYN packet expected
             return Ok(None);
         }
-        let iss = 0;
-        let wnd = 1024;
-        let mut c = Connection {
+        let irs = tcph.sequence_number();
+        let mss = peer_mss(&tcph);
+        let mut c = Connection::new(
+            q,
+            config,
+            State::SyncRcvd,
+            iss,
+            irs,
+            tcph.window_size(),
+            mss,
+        );
+        c.send_syn(nic)?;
+
+        Ok(Some(c))
+    }
+
+    // an active open. the SYN goes out on the next tick.
+    pub(crate) fn connect(q: &Quad, config: &Config, iss: u32) -> Self {
+        // we find out about the peer's window and MSS from its SYN
+        let mut c = Connection::new(q, config, State::SynSent, iss, 0, 0, DEFAULT_MSS);
+        c.active = true;
+        c
+    }
+
+    // our SYN, or SYN-ACK if we've seen the peer's
+    fn send_syn(&mut self, nic: &mut Nic) -> io::Result<()> {
+        self.tcp.syn = true;
+        self.tcp.ack = !matches!(self.state, State::SynSent);
+        self.tcp
+            .set_options(&[etherparse::TcpOptionElement::MaximumSegmentSize(max_mss(
+                &self.ip,
+                self.config.mtu,
+            ))])
+            .expect("MSS option always fits");
+        self.send.nxt = self.send.iss;
+        self.write(nic, self.send.iss, 0)?;
+        Ok(())
+    }
+
+    // rebuild a connection we handed a SYN cookie to from the ACK that completes its handshake
+    pub(crate) fn from_cookie(
+        q: &Quad,
+        config: &Config,
+        tcph: etherparse::TcpHeaderSlice,
+        iss: u32,
+        mss: u16,
+    ) -> Self {
+        let irs = tcph.sequence_number().wrapping_sub(1);
+        let mut c = Connection::new(q, config, State::Estab, iss, irs, tcph.window_size(), mss);
+        // the ACK we're looking at acks our SYN
+        c.send.una = iss.wrapping_add(1);
+        c.send.nxt = iss.wrapping_add(1);
+        c.tcp.ack = true;
+        c
+    }
+
+    fn new(
+        q: &Quad,
+        config: &Config,
+        state: State,
+        iss: u32,
+        irs: u32,
+        peer_wnd: u16,
+        mss: u16,
+    ) -> Self {
+        let wnd = config.recv_buffer as u16;
+        // the quad is from the peer's point of view, so we send from its dst to its src
+        let mut ip = IpHeader::new(q.dst.0, q.src.0, etherparse::IpNumber::TCP);
+        // routers should tell us when our packets are too big rather than fragment them
+        ip.set_dont_fragment();
+        let pmtu = initial_pmtu(&ip, config.mtu, mss);
+        Connection {
+            quad: *q,
+            active: false,
             timers: Timers {
                 send_times: Default::default(),
-                srtt: time::Duration::from_secs(1 * 60).as_secs_f64(),
+                srtt: None,
+                rttvar: 0.0,
+                syn_ack_retries: 0,
+                time_wait: None,
+                retransmitting_since: None,
+                retransmissions: 0,
+                sws_held: None,
             },
-            state: State::SyncRcvd,
+            config: *config,
+            state,
+            pmtu,
             send: SendSequenceSpace {
                 iss,
-                wnd,
+                wnd: peer_wnd,
+                max_wnd: peer_wnd,
                 una: iss,
                 nxt: iss,
-                up: false,
-                wl1: 0,
+                up: None,
+                wl1: irs,
                 wl2: 0,
             },
             recv: RecvSequenceSpace {
-                nxt: tcph.sequence_number() + 1,
-                wnd: tcph.window_size(),
-                irs: tcph.sequence_number(),
-                up: false,
+                nxt: irs.wrapping_add(1),
+                wnd,
+                irs,
+                up: None,
             },
-            ip: etherparse::Ipv4Header::new(
-                0,
-                64,
-                etherparse::IpNumber::TCP,
-                [
-                    iph.destination()[0],
-                    iph.destination()[1],
-                    iph.destination()[2],
-                    iph.destination()[3],
-                ],
-                [
-                    iph.source()[0],
-                    iph.source()[1],
-                    iph.source()[2],
-                    iph.source()[3],
-                ],
-            )
-            .unwrap(),
-            tcp: etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), iss, wnd),
+            ip,
+            tcp: etherparse::TcpHeader::new(q.dst.1, q.src.1, iss, wnd),
             incoming: Default::default(),
             unacked: Default::default(),
             closed_at: None,
             closed: false,
-        };
-
-        c.tcp.syn = true;
-        c.tcp.ack = true;
-        eprintln!("stuck here");
-        c.write(nic, c.send.nxt, 0)?;
+            error: None,
+            soft_error: None,
+            orphaned: false,
+            reset: false,
+        }
+    }
 
-        Ok(Some(c))
+    fn write(&mut self, nic: &mut Nic, seq: u32, limit: usize) -> io::Result<usize> {
+        self.write_sized(nic, seq, limit, self.mss() as usize)
     }
 
-    fn write(&mut self, nic: &mut tun_tap::Iface, seq: u32, mut limit: usize) -> io::Result<usize> {
-        let mut buf = [0u8; 1500];
-        eprintln!("arrive to write");
+    // like write, but with a segment size of our choosing
+    fn write_sized(
+        &mut self,
+        nic: &mut Nic,
+        seq: u32,
+        mut limit: usize,
+        mss: usize,
+    ) -> io::Result<usize> {
+        let mut buf = vec![0u8; self.config.mtu];
         self.tcp.sequence_number = seq;
         self.tcp.acknowledgment_number = self.recv.nxt;
+        self.tcp.window_size = self.recv_window();
+        match self.send.up {
+            Some(up) if wrapping_lt(seq, up) => {
+                // RFC 6093: the pointer is the offset of the octet following the urgent data.
+                // if that's more than 64k out, point as far as we can, it'll be set again
+                // once we get closer
+                self.tcp.urg = true;
+                self.tcp.urgent_pointer =
+                    std::cmp::min(up.wrapping_sub(seq), u16::MAX as u32) as u16;
+            }
+            _ => {
+                self.tcp.urg = false;
+                self.tcp.urgent_pointer = 0;
+            }
+        }
         // TODO: return +1 for SYN/FIN
         // we need to special-case the two "virtual" bytes SYN and FIN
         let mut offset = seq.wrapping_sub(self.send.una) as usize;
@@ -218,31 +397,32 @@ impl Connection {
             }
         }
         let (mut h, mut t) = self.unacked.as_slices();
+        // our SYN occupies a sequence number but no byte in unacked
+        let offset = std::cmp::min(offset, h.len() + t.len());
         if h.len() >= offset {
             h = &h[offset..];
         } else {
             let skipped = h.len();
             h = &[];
-            eprintln!("{}", offset - skipped);
             t = &t[(offset - skipped)..];
         }
         let max_data = std::cmp::min(limit, h.len() + t.len());
+        let max_data = std::cmp::min(max_data, mss);
 
         let size = std::cmp::min(
             buf.len(),
-            self.tcp.header_len() + self.ip.header_len() as usize + max_data,
+            self.tcp.header_len() + self.ip.header_len() + max_data,
         );
-        self.ip
-            .set_payload_len(size - self.ip.header_len() as usize);
+        self.ip.set_payload_len(size - self.ip.header_len());
 
         let bufl = buf.len();
         //write out th headers
         let mut unwritten = &mut buf[..];
-        self.ip.write(&mut unwritten);
+        self.ip.write(&mut unwritten)?;
         let ip_header_ends_at = bufl - unwritten.len();
         //postpone writing the TCP header because we need the payload as one contiguous slice to
         //calculate the tcp checksum
-        unwritten = &mut unwritten[self.tcp.header_len() as usize..];
+        unwritten = &mut unwritten[self.tcp.header_len()..];
         let tcp_header_ends_at = bufl - unwritten.len();
         let payload_bytes = {
             let mut written = 0;
@@ -261,20 +441,23 @@ impl Connection {
 
         // had to calculate checksum, kernel does not do this(WTF?)
         self.tcp.checksum = self
-            .tcp
-            .calc_checksum_ipv4(&self.ip, &buf[tcp_header_ends_at..payloadh_ends_at])
-            .expect("Failed");
+            .ip
+            .tcp_checksum(&self.tcp, &buf[tcp_header_ends_at..payloadh_ends_at]);
 
         let mut tcp_header_buf = &mut buf[ip_header_ends_at..tcp_header_ends_at];
-        self.tcp.write(&mut tcp_header_buf);
-        let next_seq = seq.wrapping_add(payload_bytes as u32);
+        self.tcp.write(&mut tcp_header_buf)?;
+        let mut next_seq = seq.wrapping_add(payload_bytes as u32);
+        // SYN and FIN take up a sequence number each, but retransmitting them must not move
+        // SND.NXT any further
         if self.tcp.syn {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
+            next_seq = next_seq.wrapping_add(1);
             self.tcp.syn = false;
+            // MSS is only allowed on the SYN
+            self.tcp.options = Default::default();
         }
 
         if self.tcp.fin {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
+            next_seq = next_seq.wrapping_add(1);
             self.tcp.fin = false;
         }
 
@@ -282,100 +465,278 @@ impl Connection {
             self.send.nxt = next_seq;
         }
 
-        self.timers.send_times.insert(seq, time::Instant::now());
+        // only what takes up sequence space gets acked, and so timed. a bare ACK would look
+        // like it's waiting for one forever.
+        if next_seq != seq {
+            self.timers.send_times.insert(seq, nic.now());
+        }
         nic.send(&buf[..payloadh_ends_at])?;
         Ok(payload_bytes)
     }
 
-    fn send_rst(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
+    // RFC 793 ABORT: <SEQ=SND.NXT><CTL=RST>
+    fn send_rst(&mut self, nic: &mut Nic) -> io::Result<()> {
         self.tcp.rst = true;
-        // TODO : fix seq number
-        //    If the incoming segment has an ACK field, the reset takes its
-        //    sequence number from the ACK field of the segment, otherwise the
-        //    reset has sequence number zero and the ACK field is set to the sum
-        //    of the sequence number and segment length of the incoming segment.
-        //    The connection remains in the same state.
-        //
-        //  TODO:
-        //   3. If the connection is in a synchronized state (ESTABLISHED,
-        //      FIN-WAIT-1, FIN-WAIT-2, CLOSE-WAIT, CLOSING, LAST-ACK, TIME-WAIT),
-        //       any unacceptable segment (out of window sequence number or
-        //      unacceptible acknowledgment number) must elicit only an empty
-        //      acknowledgment segment containing the current send-sequence number
-        //      and an acknowledgment indicating the next sequence number expected
-        //      to be received, and the connection remains in the same state.
-        self.tcp.sequence_number = 0;
-        self.tcp.acknowledgment_number = 0;
-        self.write(nic, self.send.nxt, 0);
-        Ok(())
+        let r = self.write(nic, self.send.nxt, 0);
+        self.tcp.rst = false;
+        r.map(drop)
+    }
+
+    // the connection is done for, the peer gets a RST on the next tick
+    pub(crate) fn reset(&mut self) {
+        self.reset = true;
     }
 
-    pub(crate) fn on_tick<'a>(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
-        if let State::FinWait2 | State::TimeWait = self.state {
+    pub(crate) fn on_tick(&mut self, nic: &mut Nic) -> io::Result<()> {
+        let now = nic.now();
+        if self.reset {
+            self.reset = false;
+            if !self.is_closed() {
+                self.state = State::Closed;
+                self.send_rst(nic)?;
+            }
+            return Ok(());
+        }
+        if let State::SynSent | State::SyncRcvd = self.state {
+            let retries = if let State::SynSent = self.state {
+                self.config.syn_retries
+            } else {
+                self.config.syn_ack_retries
+            };
+            match self.timers.send_times.get(&self.send.iss) {
+                // connect() only queued us up
+                None => self.send_syn(nic)?,
+                Some(&t) if now.duration_since(t) >= self.syn_rto() => {
+                    if self.timers.syn_ack_retries == retries {
+                        // the handshake is never going to complete
+                        self.abort(self.soft_error.unwrap_or(io::ErrorKind::TimedOut));
+                    } else {
+                        self.timers.syn_ack_retries += 1;
+                        self.send_syn(nic)?;
+                    }
+                }
+                Some(_) => {}
+            }
             return Ok(());
         }
 
-        let nunacked = self
-            .closed_at
-            .unwrap_or(self.send.nxt)
-            .wrapping_sub(self.send.una);
-        let unsent = self.unacked.len() as u32 - nunacked;
+        if let State::TimeWait = self.state {
+            if self
+                .timers
+                .time_wait
+                .is_some_and(|t| now.duration_since(t) >= self.config.time_wait)
+            {
+                self.state = State::Closed;
+            }
+            return Ok(());
+        }
+
+        if let State::Closed = self.state {
+            return Ok(());
+        }
+
+        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+            if self.window_update_due() {
+                // the application has read enough that the peer should hear about it
+                self.write(nic, self.send.nxt, 0)?;
+            }
+        }
+
+        if let State::FinWait2 = self.state {
+            return Ok(());
+        }
 
         let waited_for = self
             .timers
             .send_times
             .range(self.send.una..)
             .next()
-            .map(|t| t.1.elapsed());
+            .map(|(_, &t)| now.duration_since(t));
 
-        let should_retransmit = if let Some(waited_for) = waited_for {
-            waited_for > time::Duration::from_secs(1)
-                && waited_for.as_secs_f64() > 1.5 * self.timers.srtt
-        } else {
-            false
-        };
+        let should_retransmit = waited_for.is_some_and(|w| w >= self.rto());
 
         if should_retransmit {
-            let resend = std::cmp::min(self.unacked.len() as u32, self.send.wnd as u32);
-            if resend < self.send.wnd as u32 && self.closed {
+            let since = *self.timers.retransmitting_since.get_or_insert(now);
+            if now.duration_since(since) > self.config.user_timeout {
+                self.abort(self.soft_error.unwrap_or(io::ErrorKind::TimedOut));
+                return Ok(());
+            }
+            self.timers.retransmissions += 1;
+            self.pmtu.on_timeout(self.timers.retransmissions, now);
+
+            // with the window shut, this is a window probe, and one byte does
+            let wnd = std::cmp::max(self.send.wnd, 1);
+            let resend = std::cmp::min(self.unacked.len() as u32, wnd as u32);
+            if resend < wnd as u32 && resend <= self.mss() as u32 && self.closed {
                 //can we include FIN?
                 self.tcp.fin = true;
                 self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32))
             }
 
-            eprintln!("on tick write");
             self.write(nic, self.send.una, resend as usize)?;
         } else {
-            // we should send new data, and space in the window
-            if unsent == 0 && self.closed_at.is_some() {
-                return Ok(());
+            // a segment at a time, for as long as there's anything we may send
+            loop {
+                let before = (self.send.nxt, self.closed_at);
+                self.send_new(nic, now)?;
+                if (self.send.nxt, self.closed_at) == before {
+                    break;
+                }
+            }
+        }
+        // if FIN, enter FIN-WAIT-1
+        Ok(())
+    }
+
+    // when on_tick next has something to do, if nothing else happens until then. on_tick
+    // sends whatever it can right away, so this is only ever about timers.
+    pub(crate) fn next_timeout(&self) -> Option<time::Instant> {
+        match self.state {
+            State::SynSent | State::SyncRcvd => self
+                .timers
+                .send_times
+                .get(&self.send.iss)
+                .map(|&t| t + self.syn_rto()),
+            State::TimeWait => self.timers.time_wait.map(|t| t + self.config.time_wait),
+            State::Closed | State::FinWait2 => None,
+            _ => {
+                let rto = self
+                    .timers
+                    .send_times
+                    .range(self.send.una..)
+                    .next()
+                    .map(|(_, &t)| t + self.rto());
+                let sws = self.timers.sws_held.map(|t| t + SWS_OVERRIDE);
+                match (rto, sws) {
+                    (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+                    (a, b) => a.or(b),
+                }
+            }
+        }
+    }
+
+    // RFC 6298 2: SRTT + 4*RTTVAR, and doubled for every time it ran out since we last got an
+    // ACK (5.5). the clock is fine enough that G doesn't matter next to MIN_RTO.
+    fn rto(&self) -> time::Duration {
+        let rto = match self.timers.srtt {
+            None => self.config.initial_rto,
+            Some(srtt) => std::cmp::max(
+                MIN_RTO,
+                time::Duration::from_secs_f64((srtt + 4.0 * self.timers.rttvar).min(1e9)),
+            ),
+        };
+        let backoff = 1u32
+            .checked_shl(self.timers.retransmissions)
+            .unwrap_or(u32::MAX);
+        std::cmp::min(rto.saturating_mul(backoff), MAX_RTO)
+    }
+
+    // RFC 6298 2.2 and 2.3
+    fn sample_rtt(&mut self, r: f64) {
+        let t = &mut self.timers;
+        match t.srtt {
+            None => {
+                t.srtt = Some(r);
+                t.rttvar = r / 2.0;
+            }
+            Some(srtt) => {
+                t.rttvar = 0.75 * t.rttvar + 0.25 * (srtt - r).abs();
+                t.srtt = Some(0.875 * srtt + 0.125 * r);
+            }
+        }
+    }
+
+    // doubled for every SYN or SYN-ACK we had to send again
+    fn syn_rto(&self) -> time::Duration {
+        let backoff = 1u32
+            .checked_shl(self.timers.syn_ack_retries)
+            .unwrap_or(u32::MAX);
+        std::cmp::min(self.config.initial_rto.saturating_mul(backoff), MAX_RTO)
+    }
+
+    fn send_new(&mut self, nic: &mut Nic, now: time::Instant) -> io::Result<()> {
+        let nunacked = self
+            .closed_at
+            .unwrap_or(self.send.nxt)
+            .wrapping_sub(self.send.una);
+        let unsent = (self.unacked.len() as u32).saturating_sub(nunacked);
+
+        // we should send new data, and space in the window
+        if unsent == 0 && (self.closed_at.is_some() || !self.closed) {
+            self.timers.sws_held = None;
+            return Ok(());
+        }
+
+        let allowed = (self.send.wnd as u32).saturating_sub(nunacked);
+        if allowed == 0 {
+            self.timers.sws_held = None;
+            if self.send.wnd == 0 && nunacked == 0 {
+                // RFC 1122 4.2.2.17: the window update that opens it again may get lost,
+                // so we probe with a byte past it. the retransmission timer takes it from
+                // there.
+                if unsent > 0 {
+                    self.write(nic, self.send.nxt, 1)?;
+                } else if self.closed && self.closed_at.is_none() {
+                    // all that's left is our FIN, and it probes just as well
+                    self.tcp.fin = true;
+                    self.closed_at = Some(self.send.nxt);
+                    self.write(nic, self.send.nxt, 0)?;
+                }
             }
+            return Ok(());
+        }
 
-            let allowed = self.send.wnd as u32 - nunacked;
-            if allowed == 0 {
+        let send = std::cmp::min(unsent, allowed);
+        // RFC 1122 4.2.3.4: don't dribble small segments into a small window. only send if
+        // we can fill a whole segment, if it's all we've got, or if it's at least half of
+        // the biggest window the peer has ever offered.
+        // urgent data is always pushed out right away.
+        if let Some(size) = self.pmtu.probe_size(now) {
+            // RFC 8899: probe with real data, so only when there's enough of it to fill a
+            // packet of the size we want to try
+            let probe_mss = size - self.ip.header_len() - etherparse::TcpHeader::MIN_LEN;
+            if send as usize >= probe_mss {
+                let seq = self.send.nxt;
+                let n = self.write_sized(nic, seq, probe_mss, probe_mss)?;
+                self.pmtu.probe_sent(seq.wrapping_add(n as u32), size);
                 return Ok(());
             }
+        }
 
-            let send = std::cmp::min(unsent, allowed);
-            if send < allowed && self.closed && self.closed_at.is_none() {
-                self.tcp.fin = true;
-                self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32))
+        let mss = self.mss() as u32;
+        if send < mss
+            && send < unsent
+            && send < self.send.max_wnd as u32 / 2
+            && self.send.up.is_none()
+        {
+            // but not forever, the override timer sends it after all
+            let held = *self.timers.sws_held.get_or_insert(now);
+            if now.duration_since(held) < SWS_OVERRIDE {
+                return Ok(());
             }
+        }
+        self.timers.sws_held = None;
 
-            eprintln!("mayber here on tick");
-            self.write(nic, self.send.nxt, send as usize)?;
+        if send < allowed && send <= mss && self.closed && self.closed_at.is_none() {
+            self.tcp.fin = true;
+            self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32))
         }
-        // if FIN, enter FIN-WAIT-1
+
+        self.write(nic, self.send.nxt, send as usize)?;
         Ok(())
     }
 
     pub(crate) fn on_packet<'a>(
         &mut self,
-        nic: &mut tun_tap::Iface,
-        iph: etherparse::Ipv4HeaderSlice<'a>,
+        nic: &mut Nic,
         tcph: etherparse::TcpHeaderSlice<'a>,
         data: &'a [u8],
     ) -> io::Result<Available> {
+        if let State::SynSent = self.state {
+            self.on_syn_sent(nic, tcph, data)?;
+            return Ok(self.availability());
+        }
+
         //valid seq numb check
         // valid segment check. okay if it acks at least one byte, which means that at least one of
         // the following is true:
@@ -408,41 +769,46 @@ impl Connection {
               >0      >0     RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
                           or RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND */
             if self.recv.wnd == 0 {
-                if seqn != self.recv.wnd as u32 {
-                    false
-                } else {
-                    true
-                }
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend) {
-                false
+                seqn == self.recv.nxt
             } else {
-                true
+                is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
             }
+        } else if self.recv.wnd == 0 {
+            false
         } else {
-            if self.recv.wnd == 0 {
-                false
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
-                && !is_between_wrapped(
+            is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
+                || is_between_wrapped(
                     self.recv.nxt.wrapping_sub(1),
                     seqn.wrapping_add(slen - 1),
                     wend,
                 )
-            {
-                false
-            } else {
-                true
-            }
         };
         if !okay {
-            self.write(nic, self.send.nxt, 0);
+            if !tcph.rst() {
+                self.write(nic, self.send.nxt, 0)?;
+            }
             return Ok(self.availability());
         }
         // TODO: if not acceptable send ACK
         // // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
 
+        if tcph.rst() {
+            match self.state {
+                // we got here through a passive open, so there's nothing to go back to but the
+                // listener the connection came from
+                State::SyncRcvd if !self.active => self.state = State::Closed,
+                State::SyncRcvd => self.abort(io::ErrorKind::ConnectionRefused),
+                // nobody is left to tell
+                State::TimeWait => self.state = State::Closed,
+                _ => self.abort(io::ErrorKind::ConnectionReset),
+            }
+            return Ok(self.availability());
+        }
+
         if !tcph.ack() {
             if tcph.syn() {
-                assert!(data.is_empty());
+                // RFC 793 allows data on a SYN, but like in on_syn_sent we ack right past it
+                // and have the peer send it again
                 self.recv.nxt = seqn.wrapping_add(1);
             }
             return Ok(self.availability());
@@ -470,16 +836,16 @@ impl Connection {
 
         let ackn = tcph.acknowledgment_number();
         if let State::SyncRcvd = self.state {
-            eprintln!("sync");
             if is_between_wrapped(
                 self.send.una.wrapping_sub(1),
                 ackn,
                 self.send.nxt.wrapping_add(1),
             ) {
-                eprintln!("else sync");
                 // must have ACKed our syn since we detected at least one ACKed byte, and we have
                 // only sent one byte (the SYN)
                 self.state = State::Estab;
+                self.send.wnd = tcph.window_size();
+                self.send.max_wnd = self.send.wnd;
             } else {
                 //TODO: <SEQ=SEG.ACK><CTL=RST>
             }
@@ -489,87 +855,353 @@ impl Connection {
                  return Ok(());
              }
         */
-        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            if is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
+        if let State::Estab
+        | State::FinWait1
+        | State::FinWait2
+        | State::CloseWait
+        | State::Closing
+        | State::LastAck = self.state
+        {
+            // SND.UNA =< SEG.ACK =< SND.NXT. one that doesn't ack anything new may still open
+            // the window.
+            let advanced = ackn != self.send.una;
+            if is_between_wrapped(
+                self.send.una.wrapping_sub(1),
+                ackn,
+                self.send.nxt.wrapping_add(1),
+            ) && advanced
+            {
                 if !self.unacked.is_empty() {
-                    let data_s = if self.send.una == self.send.iss {
-                        // send.una hasn't been updated yet with ACK for our SYN, so data starts
-                        // beyond it
-                        self.send.una.wrapping_add(1)
-                    } else {
-                        self.send.una
-                    };
-
-                    let acked_data_end =
-                        std::cmp::min(ackn.wrapping_sub(data_s) as usize, self.unacked.len());
+                    let acked_data_end = std::cmp::min(
+                        ackn.wrapping_sub(self.data_start()) as usize,
+                        self.unacked.len(),
+                    );
                     self.unacked.drain(..acked_data_end);
 
-                    let old = std::mem::replace(&mut self.timers.send_times, BTreeMap::new());
+                    let old = std::mem::take(&mut self.timers.send_times);
 
                     let una = self.send.una;
-                    let mut srtt = &mut self.timers.srtt;
-
+                    let mut latest = None;
+                    let mut last_acked = None;
                     self.timers
                         .send_times
                         .extend(old.into_iter().filter_map(|(seq, sent)| {
-                            if is_between_wrapped(una, seq, ackn) {
-                                *srtt = 0.8 * *srtt + (1.0 - 0.8) * sent.elapsed().as_secs_f64();
+                            // everything sent from una on that the ACK covers
+                            if is_between_wrapped(una.wrapping_sub(1), seq, ackn) {
+                                latest = std::cmp::max(latest, Some(sent));
+                                last_acked = Some(sent);
                                 None
                             } else {
                                 Some((seq, sent))
                             }
                         }));
+                    // an ACK that ends inside a segment leaves the rest of it out there, and
+                    // the retransmission timer has to keep running for it
+                    if let Some(sent) = last_acked {
+                        if ackn != self.send.nxt && !self.timers.send_times.contains_key(&ackn) {
+                            self.timers.send_times.insert(ackn, sent);
+                        }
+                    }
+                    // one sample per ACK, from the last segment it covers. not if we had to
+                    // resend, there's no telling which copy it acks (Karn's algorithm).
+                    if let (Some(sent), 0) = (latest, self.timers.retransmissions) {
+                        self.sample_rtt(nic.now().duration_since(sent).as_secs_f64());
+                    }
                 }
                 self.send.una = ackn;
+                // the peer is still there, whatever the network told us before
+                self.timers.retransmitting_since = None;
+                self.timers.retransmissions = 0;
+                self.soft_error = None;
+                self.pmtu.on_ack(self.send.una, nic.now());
+                if let Some(up) = self.send.up {
+                    if !wrapping_lt(self.send.una, up) {
+                        // all of the urgent data made it across
+                        self.send.up = None;
+                    }
+                }
+            }
+            if is_between_wrapped(
+                self.send.una.wrapping_sub(1),
+                ackn,
+                self.send.nxt.wrapping_add(1),
+            ) {
+                // only take window updates from segments that are newer than the last one we
+                // took it from, so a reordered old segment can't shrink the window again
+                if wrapping_lt(self.send.wl1, seqn)
+                    || (self.send.wl1 == seqn && !wrapping_lt(ackn, self.send.wl2))
+                {
+                    if self.send.wnd == 0 && tcph.window_size() > 0 && ackn != self.send.nxt {
+                        // our window probe was turned away, and whatever we send next would
+                        // land past the byte that's missing. start over from there instead of
+                        // waiting for it to time out.
+                        self.send.nxt = self.send.una;
+                        self.closed_at = None;
+                    }
+                    self.send.wnd = tcph.window_size();
+                    self.send.max_wnd = std::cmp::max(self.send.max_wnd, self.send.wnd);
+                    self.send.wl1 = seqn;
+                    self.send.wl2 = ackn;
+                }
             }
             // TODO: if unacked empty and waiting flush, notify
-            // TODO: update window
         }
-        if let State::FinWait1 = self.state {
-            if let Some(closed_at) = self.closed_at {
-                if self.send.una == closed_at.wrapping_add(1) {
-                    //our FIN has been ACKed
-                    self.state = State::FinWait2;
+        let fin_acked = self
+            .closed_at
+            .is_some_and(|closed_at| self.send.una == closed_at.wrapping_add(1));
+        if fin_acked {
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.enter_time_wait(nic.now()),
+                State::LastAck => {
+                    // all done
+                    self.state = State::Closed;
+                    return Ok(self.availability());
+                }
+                _ => {}
+            }
+        }
+
+        if tcph.urg() {
+            if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+                // RFC 6093: the pointer is the offset of the octet following the urgent data.
+                // the urgent data itself stays inline, we only remember where it ends.
+                let up = seqn.wrapping_add(tcph.urgent_pointer() as u32);
+                let newer = match self.recv.up {
+                    Some(cur) => wrapping_lt(cur, up),
+                    None => wrapping_lt(self.read_up_to(), up),
+                };
+                if newer {
+                    self.recv.up = Some(up);
                 }
             }
         }
 
         if !data.is_empty() {
             if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-                let mut unread_data_at = (self.recv.nxt - seqn) as usize;
-                if unread_data_at > data.len() {
-                    assert_eq!(unread_data_at, data.len() + 1);
-                    unread_data_at = 0;
+                // we have no out-of-order queue, so anything that doesn't start at or before
+                // RCV.NXT is dropped (and re-ACKed below)
+                let unread_data_at = self.recv.nxt.wrapping_sub(seqn) as usize;
+                if unread_data_at < data.len() {
+                    // never take more than we advertised, the rest will be retransmitted
+                    let nread = std::cmp::min(data.len() - unread_data_at, self.recv.wnd as usize);
+                    self.incoming
+                        .extend(&data[unread_data_at..(unread_data_at + nread)]);
+                    self.recv.nxt = self.recv.nxt.wrapping_add(nread as u32);
+                    // the right edge of the window stays put, so it shrinks by what we took
+                    self.recv.wnd -= nread as u16;
                 }
-                self.incoming.extend(&data[unread_data_at..]);
-                self.recv.nxt = seqn.wrapping_add(data.len() as u32);
 
                 // TODO: mabye just tick topiggyback on data
                 self.write(nic, self.send.nxt, 0)?;
             }
         }
 
-        if tcph.fin() {
-            match self.state {
-                State::FinWait2 => {
-                    self.recv.nxt = self.recv.nxt.wrapping_add(1);
+        // the FIN is only ours to take once everything before it has arrived, which it won't
+        // have if we dropped some of the data above
+        if tcph.fin() && seqn.wrapping_add(data.len() as u32) == self.recv.nxt {
+            // a retransmitted FIN is already behind RCV.NXT, and got re-ACKed as unacceptable
+            if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+                self.recv.nxt = self.recv.nxt.wrapping_add(1);
+                self.write(nic, self.send.nxt, 0)?;
+                match self.state {
+                    State::Estab => self.state = State::CloseWait,
+                    State::FinWait1 => self.state = State::Closing,
                     // we're done with the connection!
-                    self.write(nic, self.send.nxt, 0)?;
-                    self.state = State::TimeWait;
+                    _ => self.enter_time_wait(nic.now()),
                 }
-                _ => unimplemented!(),
             }
         }
         Ok(self.availability())
     }
 
+    // RFC 793 "SYN-SENT STATE" under SEGMENT ARRIVES
+    fn on_syn_sent<'a>(
+        &mut self,
+        nic: &mut Nic,
+        tcph: etherparse::TcpHeaderSlice<'a>,
+        data: &'a [u8],
+    ) -> io::Result<()> {
+        let ackn = tcph.acknowledgment_number();
+        // SND.UNA =< SEG.ACK =< SND.NXT, and it has to ack our SYN
+        let acceptable =
+            tcph.ack() && is_between_wrapped(self.send.iss, ackn, self.send.nxt.wrapping_add(1));
+        if tcph.ack() && !acceptable {
+            if !tcph.rst() {
+                send_reset(nic, &self.quad, tcph, data)?;
+            }
+            return Ok(());
+        }
+        if tcph.rst() {
+            if acceptable {
+                self.abort(io::ErrorKind::ConnectionRefused);
+            }
+            return Ok(());
+        }
+        if !tcph.syn() {
+            return Ok(());
+        }
+
+        // we only now find out about the peer
+        let seqn = tcph.sequence_number();
+        self.recv.irs = seqn;
+        // TODO: data on the SYN, we ack right past it and have the peer send it again
+        self.recv.nxt = seqn.wrapping_add(1);
+        self.send.wnd = tcph.window_size();
+        self.send.max_wnd = self.send.wnd;
+        self.send.wl1 = seqn;
+        self.send.wl2 = ackn;
+        self.pmtu = initial_pmtu(&self.ip, self.config.mtu, peer_mss(&tcph));
+        self.timers.syn_ack_retries = 0;
+
+        if acceptable {
+            self.send.una = ackn;
+            self.state = State::Estab;
+            self.tcp.ack = true;
+            self.write(nic, self.send.nxt, 0)?;
+        } else {
+            // both ends connected at once, so we answer its SYN like a listener would, except
+            // that a reset now means the connection was refused
+            self.state = State::SyncRcvd;
+            self.send_syn(nic)?;
+        }
+        Ok(())
+    }
+
+    fn enter_time_wait(&mut self, now: time::Instant) {
+        self.state = State::TimeWait;
+        self.timers.time_wait = Some(now);
+    }
+
+    // RFC 5927: hard errors abort a connection that's still being set up. anything else, or
+    // anything once the connection is synchronized, might be transient, so we just remember it.
+    pub(crate) fn on_icmp_error(&mut self, seq: u32, hard: bool, kind: io::ErrorKind) {
+        // RFC 5927 4.1: a real error has to be about a segment that's still in flight, anyone
+        // else could be guessing
+        if !is_between_wrapped(self.send.una.wrapping_sub(1), seq, self.send.nxt) {
+            return;
+        }
+        match self.state {
+            State::SynSent | State::SyncRcvd if hard => self.abort(kind),
+            State::Closed => {}
+            _ => self.soft_error = Some(kind),
+        }
+    }
+
+    // a router couldn't forward one of our segments because it's only good for mtu bytes
+    pub(crate) fn on_icmp_too_big(
+        &mut self,
+        nic: &mut Nic,
+        seq: u32,
+        mtu: usize,
+    ) -> io::Result<()> {
+        if !is_between_wrapped(self.send.una.wrapping_sub(1), seq, self.send.nxt) {
+            return Ok(());
+        }
+        if let State::Closed | State::SynSent | State::SyncRcvd = self.state {
+            // our SYN or SYN-ACK carries no data, so it was small enough anyway
+            return Ok(());
+        }
+        if !self.pmtu.on_too_big(mtu, nic.now()) {
+            return Ok(());
+        }
+        // RFC 1191: whatever was in flight was too big and got dropped, resend all of it in
+        // pieces right away rather than wait for the retransmission timer
+        let fin_sent = self
+            .closed_at
+            .is_some_and(|fin| wrapping_lt(fin, self.send.nxt));
+        let end = self.closed_at.unwrap_or(self.send.nxt);
+        let mut seq = self.send.una;
+        while wrapping_lt(seq, end) {
+            let left = end.wrapping_sub(seq) as usize;
+            if fin_sent && left <= self.mss() as usize {
+                self.tcp.fin = true;
+            }
+            let n = self.write(nic, seq, left)?;
+            if n == 0 {
+                break;
+            }
+            seq = seq.wrapping_add(n as u32);
+        }
+        Ok(())
+    }
+
+    fn abort(&mut self, kind: io::ErrorKind) {
+        self.state = State::Closed;
+        self.error = Some(kind);
+    }
+
+    // the error the connection was aborted with, if it was
+    pub(crate) fn error(&self) -> Option<io::Error> {
+        self.error.map(io::Error::from)
+    }
+
+    pub(crate) fn consume(&mut self, buf: &mut [u8]) -> usize {
+        let mut nread = 0;
+        let (head, tail) = self.incoming.as_slices();
+        let hread = std::cmp::min(buf.len(), head.len());
+        buf[..hread].copy_from_slice(&head[..hread]);
+        nread += hread;
+        let tread = std::cmp::min(buf.len() - nread, tail.len());
+        buf[hread..(hread + tread)].copy_from_slice(&tail[..tread]);
+        nread += tread;
+        drop(self.incoming.drain(..nread));
+
+        if let Some(up) = self.recv.up {
+            if !wrapping_lt(self.read_up_to(), up) {
+                // the application has read past the urgent data
+                self.recv.up = None;
+            }
+        }
+        nread
+    }
+
+    // how many more bytes the application has to read to get past the urgent data the peer
+    // has sent us, if there is any. some of them may not have arrived yet.
+    pub(crate) fn urgent_mark(&self) -> Option<usize> {
+        self.recv
+            .up
+            .map(|up| up.wrapping_sub(self.read_up_to()) as usize)
+    }
+
+    pub(crate) fn mark_urgent(&mut self) {
+        // everything queued so far is urgent
+        self.send.up = Some(self.data_start().wrapping_add(self.unacked.len() as u32));
+    }
+
+    // sequence number of the first byte in incoming
+    fn read_up_to(&self) -> u32 {
+        self.recv.nxt.wrapping_sub(self.incoming.len() as u32)
+    }
+
+    // sequence number of the first byte in unacked
+    fn data_start(&self) -> u32 {
+        if self.send.una == self.send.iss {
+            // send.una hasn't been updated yet with ACK for our SYN, so data starts beyond it
+            self.send.una.wrapping_add(1)
+        } else {
+            self.send.una
+        }
+    }
+
     pub fn close(&mut self) -> io::Result<()> {
+        if self.closed {
+            // our FIN is already queued or sent, closing again changes nothing
+            return Ok(());
+        }
         self.closed = true;
         match self.state {
             State::SyncRcvd | State::Estab => {
                 self.state = State::FinWait1;
             }
-            State::FinWait1 | State::FinWait2 => {}
+            State::CloseWait => {
+                self.state = State::LastAck;
+            }
+            State::SynSent => {
+                // nothing has been sent but our SYN, so there's nothing to finish
+                self.state = State::Closed;
+            }
+            State::FinWait1 | State::FinWait2 | State::Closing | State::LastAck => {}
             _ => {
                 return Err(io::Error::new(
                     io::ErrorKind::NotConnected,
@@ -581,6 +1213,116 @@ impl Connection {
     }
 }
 
+impl Connection {
+    // a passive open that hasn't completed yet
+    pub(crate) fn is_half_open(&self) -> bool {
+        !self.active && matches!(self.state, State::SyncRcvd)
+    }
+
+    // an active open that hasn't completed yet
+    pub(crate) fn is_connecting(&self) -> bool {
+        self.active && matches!(self.state, State::SynSent | State::SyncRcvd)
+    }
+
+    pub(crate) fn state(&self) -> State {
+        self.state
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed)
+    }
+
+    // how much the application may queue up for sending
+    pub(crate) fn send_buffer(&self) -> usize {
+        self.config.send_buffer
+    }
+
+    // the largest segment we send, what fits in a packet on the path and what the peer takes
+    fn mss(&self) -> u16 {
+        (self.pmtu.current() - self.ip.header_len() - etherparse::TcpHeader::MIN_LEN) as u16
+    }
+
+    // RFC 1122 4.2.3.3: only move the right edge of the window we advertise once the
+    // application has freed up a sizable chunk of the receive queue. otherwise a slow reader
+    // makes us offer a trickle of tiny windows, and the peer will dutifully fill each of them
+    // with a tiny segment.
+    fn recv_window(&mut self) -> u16 {
+        if self.window_update_due() {
+            self.recv.wnd = (self.config.recv_buffer - self.incoming.len()) as u16;
+        }
+        self.recv.wnd
+    }
+
+    pub(crate) fn window_update_due(&self) -> bool {
+        let free = self.config.recv_buffer.saturating_sub(self.incoming.len());
+        let threshold = std::cmp::min(self.config.recv_buffer / 2, self.mss() as usize);
+        free.saturating_sub(self.recv.wnd as usize) >= threshold
+    }
+}
+
+// reset generation (RFC 793) for a segment that doesn't belong to any connection
+pub(crate) fn send_reset<'a>(
+    nic: &mut Nic,
+    q: &Quad,
+    tcph: etherparse::TcpHeaderSlice<'a>,
+    data: &'a [u8],
+) -> io::Result<()> {
+    let mut tcp = etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), 0, 0);
+    tcp.rst = true;
+    if tcph.ack() {
+        // <SEQ=SEG.ACK><CTL=RST>
+        tcp.sequence_number = tcph.acknowledgment_number();
+    } else {
+        // <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>
+        let mut slen = data.len() as u32;
+        if tcph.syn() {
+            slen += 1;
+        }
+        if tcph.fin() {
+            slen += 1;
+        }
+        tcp.ack = true;
+        tcp.acknowledgment_number = tcph.sequence_number().wrapping_add(slen);
+    }
+    let mut ip = IpHeader::new(q.dst.0, q.src.0, etherparse::IpNumber::TCP);
+    ip.set_payload_len(tcp.header_len());
+    tcp.checksum = ip.tcp_checksum(&tcp, &[]);
+
+    let mut buf = Vec::with_capacity(ip.header_len() + tcp.header_len());
+    ip.write(&mut buf)?;
+    tcp.write(&mut buf)?;
+    nic.send(&buf)?;
+    Ok(())
+}
+
+// the peer's MSS is the biggest segment it will take, so no path MTU beyond it matters
+fn initial_pmtu(ip: &IpHeader, mtu: usize, mss: u16) -> Pmtu {
+    let headers = ip.header_len() + etherparse::TcpHeader::MIN_LEN;
+    let (min, base) = match ip {
+        IpHeader::V4(_) => (pmtu::MIN_V4, pmtu::BASE_V4),
+        IpHeader::V6(_) => (pmtu::MIN_V6, pmtu::BASE_V6),
+    };
+    Pmtu::new(min, base, std::cmp::min(mtu, mss as usize + headers))
+}
+
+// the largest payload that fits in a packet alongside option-less IP and TCP headers
+fn max_mss(ip: &IpHeader, mtu: usize) -> u16 {
+    (mtu - ip.header_len() - etherparse::TcpHeader::MIN_LEN) as u16
+}
+
+// the MSS the peer asked for in its SYN. a tiny one would have us send a flood of tiny
+// segments, or none at all, so like Linux we don't go below MIN_MSS whatever it says.
+pub(crate) fn peer_mss(tcph: &etherparse::TcpHeaderSlice) -> u16 {
+    let mss = tcph
+        .options_iterator()
+        .find_map(|o| match o {
+            Ok(etherparse::TcpOptionElement::MaximumSegmentSize(mss)) => Some(mss),
+            _ => None,
+        })
+        .unwrap_or(DEFAULT_MSS);
+    std::cmp::max(mss, MIN_MSS)
+}
+
 fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
     // TCP determines if a data segment is "old" or "new" by testing
     //   whether its sequence number is within 2**31 bytes of the left edge
@@ -588,9 +1330,587 @@ fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
     //   insure that new data is never mistakenly considered old and vice-
     //   versa, the left edge of the sender's window has to be at most
     //   2**31 away from the right edge of the receiver's window.
-    lhs.wrapping_sub(rhs) > 2 ^ 31
+    lhs.wrapping_sub(rhs) > 1 << 31
 }
 
 fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
     wrapping_lt(start, x) && wrapping_lt(x, end)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::clock::{ManualClock, SharedClock};
+    use crate::device::{virtual_link, Device, VirtualDevice};
+
+    const ISS: u32 = 1000;
+    const IRS: u32 = 5000;
+
+    // an established connection, with the peer's end of the link to see what it sends, on a
+    // clock that only moves when we say so
+    struct Harness {
+        c: Connection,
+        nic: Nic,
+        peer: VirtualDevice,
+        clock: ManualClock,
+    }
+
+    const QUAD: Quad = Quad {
+        src: (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 40000),
+        dst: (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 8080),
+    };
+
+    impl Harness {
+        fn new(config: Config, peer_wnd: u16) -> Self {
+            let ack = header(IRS.wrapping_add(1), ISS.wrapping_add(1), peer_wnd);
+            let tcph = etherparse::TcpHeaderSlice::from_slice(&ack).unwrap();
+            Harness::with(Connection::from_cookie(
+                &QUAD,
+                &config,
+                tcph,
+                ISS,
+                DEFAULT_MSS,
+            ))
+        }
+
+        // an active open, which hasn't sent its SYN yet
+        fn connecting(config: Config) -> Self {
+            Harness::with(Connection::connect(&QUAD, &config, ISS))
+        }
+
+        fn with(c: Connection) -> Self {
+            let (a, peer) = virtual_link().unwrap();
+            let clock = ManualClock::new();
+            let nic = Nic::ip(Box::new(a), SharedClock::new(clock.clone()));
+            Harness {
+                c,
+                nic,
+                peer,
+                clock,
+            }
+        }
+
+        // the peer sends us an ACK, with data if there's any
+        fn segment(&mut self, seq: u32, ack: u32, wnd: u16, data: &[u8]) {
+            let h = header(seq, ack, wnd);
+            let tcph = etherparse::TcpHeaderSlice::from_slice(&h).unwrap();
+            self.c.on_packet(&mut self.nic, tcph, data).unwrap();
+        }
+
+        fn tick(&mut self) {
+            self.c.on_tick(&mut self.nic).unwrap();
+        }
+
+        // everything we've sent since last time
+        fn sent(&mut self) -> Vec<(etherparse::TcpHeader, Vec<u8>)> {
+            let mut out = Vec::new();
+            let mut buf = [0u8; 1 << 16];
+            while let Ok(n) = self.peer.recv(&mut buf) {
+                let p = etherparse::SlicedPacket::from_ip(&buf[..n]).unwrap();
+                let Some(etherparse::TransportSlice::Tcp(t)) = p.transport else {
+                    panic!("not a TCP segment: {:?}", p);
+                };
+                out.push((t.to_header(), t.payload().to_vec()));
+            }
+            out
+        }
+    }
+
+    fn header(seq: u32, ack: u32, wnd: u16) -> Vec<u8> {
+        let mut h = etherparse::TcpHeader::new(40000, 8080, seq, wnd);
+        h.ack = true;
+        h.acknowledgment_number = ack;
+        h.to_bytes().to_vec()
+    }
+
+    // the window we advertise only opens again once the application has read a sizable chunk
+    #[test]
+    fn recv_window_opens_in_chunks() {
+        let mut h = Harness::new(Config::default(), 1024);
+        let start = IRS.wrapping_add(1);
+        h.segment(start, ISS + 1, 1024, &[1; 1000]);
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.acknowledgment_number, start + 1000);
+        assert_eq!(sent[0].0.window_size, 24);
+
+        // min(half the buffer, one MSS) is what it takes
+        let mut buf = [0u8; 400];
+        assert_eq!(h.c.consume(&mut buf), 400);
+        assert!(!h.c.window_update_due());
+        assert_eq!(h.c.recv_window(), 24);
+
+        assert_eq!(h.c.consume(&mut buf[..200]), 200);
+        assert!(h.c.window_update_due());
+        h.tick();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.window_size, 624);
+        assert!(!h.c.window_update_due());
+    }
+
+    // a small segment that SWS avoidance holds back still goes out once the override timer
+    // runs out
+    #[test]
+    fn sws_override() {
+        let mut h = Harness::new(Config::default(), 1000);
+        h.c.unacked.extend([7; 1200]);
+        h.tick();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1.len(), DEFAULT_MSS as usize);
+
+        // what's left of the window is less than a segment and less than half of it
+        let now = h.nic.now();
+        assert_eq!(h.c.next_timeout(), Some(now + SWS_OVERRIDE));
+        h.clock.advance(SWS_OVERRIDE / 2);
+        h.tick();
+        assert!(h.sent().is_empty());
+
+        h.clock.advance(SWS_OVERRIDE / 2);
+        h.tick();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1.len(), 1000 - DEFAULT_MSS as usize);
+        assert_eq!(sent[0].0.sequence_number, ISS + 1 + DEFAULT_MSS as u32);
+        assert_ne!(h.c.next_timeout(), Some(h.nic.now() + SWS_OVERRIDE));
+    }
+
+    // sequence numbers compare within 2^31 of each other, across the wrap
+    #[test]
+    fn wrapping_lt_compares_half_the_space() {
+        assert!(wrapping_lt(1, 2));
+        assert!(!wrapping_lt(2, 1));
+        assert!(!wrapping_lt(1, 1));
+        assert!(wrapping_lt(u32::MAX, 0));
+        assert!(!wrapping_lt(0, u32::MAX));
+        // 2 ^ 31 is 29 in Rust, which took anything more than 29 ahead for behind
+        assert!(!wrapping_lt(100, 0));
+        assert!(wrapping_lt(0, (1 << 31) - 1));
+        // exactly half way around is neither
+        assert!(!wrapping_lt(0, 1 << 31));
+        assert!(!wrapping_lt(1 << 31, 0));
+        assert!(is_between_wrapped(u32::MAX - 5, 3, 10));
+        assert!(!is_between_wrapped(u32::MAX - 5, 10, 10));
+    }
+
+    // RFC 5927 4.1: an ICMP error only counts if it's about a segment still in flight
+    #[test]
+    fn icmp_error_sequence_check() {
+        let mut h = Harness::new(Config::default(), 1024);
+        let una = ISS + 1;
+        // nothing in flight, nothing to be about
+        h.c.on_icmp_error(una, false, io::ErrorKind::HostUnreachable);
+        assert_eq!(h.c.soft_error, None);
+
+        h.c.unacked.extend([1; 100]);
+        h.tick();
+        h.c.on_icmp_error(una + 100, false, io::ErrorKind::HostUnreachable);
+        h.c.on_icmp_error(una - 1, false, io::ErrorKind::HostUnreachable);
+        assert_eq!(h.c.soft_error, None);
+        h.c.on_icmp_error(una + 99, false, io::ErrorKind::HostUnreachable);
+        assert_eq!(h.c.soft_error, Some(io::ErrorKind::HostUnreachable));
+
+        // once synchronized, even a hard error is only remembered
+        h.c.on_icmp_error(una, true, io::ErrorKind::ConnectionRefused);
+        assert_eq!(h.c.state(), State::Estab);
+        assert_eq!(h.c.soft_error, Some(io::ErrorKind::ConnectionRefused));
+    }
+
+    // while connecting, a hard error aborts and a soft one only shows up if it times out
+    #[test]
+    fn icmp_error_while_connecting() {
+        let mut h = Harness::connecting(Config::default());
+        h.tick();
+        assert!(h.sent()[0].0.syn);
+
+        h.c.on_icmp_error(ISS, false, io::ErrorKind::NetworkUnreachable);
+        assert_eq!(h.c.state(), State::SynSent);
+        // not about our SYN
+        h.c.on_icmp_error(ISS + 1, true, io::ErrorKind::ConnectionRefused);
+        assert_eq!(h.c.state(), State::SynSent);
+
+        h.c.on_icmp_error(ISS, true, io::ErrorKind::ConnectionRefused);
+        assert!(h.c.is_closed());
+        assert_eq!(
+            h.c.error().map(|e| e.kind()),
+            Some(io::ErrorKind::ConnectionRefused)
+        );
+
+        let mut h = Harness::connecting(Config {
+            syn_retries: 0,
+            ..Config::default()
+        });
+        h.tick();
+        h.c.on_icmp_error(ISS, false, io::ErrorKind::NetworkUnreachable);
+        h.clock.advance(INITIAL_RTO);
+        h.tick();
+        assert_eq!(
+            h.c.error().map(|e| e.kind()),
+            Some(io::ErrorKind::NetworkUnreachable)
+        );
+    }
+
+    // whatever the peer asks for, we don't go below MIN_MSS
+    #[test]
+    fn peer_mss_clamped() {
+        let syn = |mss: Option<u16>| {
+            let mut h = etherparse::TcpHeader::new(40000, 8080, IRS, 1024);
+            h.syn = true;
+            if let Some(mss) = mss {
+                h.set_options(&[etherparse::TcpOptionElement::MaximumSegmentSize(mss)])
+                    .unwrap();
+            }
+            h.to_bytes().to_vec()
+        };
+        let mss = |mss| peer_mss(&etherparse::TcpHeaderSlice::from_slice(&syn(mss)).unwrap());
+        assert_eq!(mss(None), DEFAULT_MSS);
+        assert_eq!(mss(Some(1460)), 1460);
+        assert_eq!(mss(Some(88)), 88);
+        assert_eq!(mss(Some(10)), MIN_MSS);
+        assert_eq!(mss(Some(0)), MIN_MSS);
+
+        let mut h = Harness::connecting(Config::default());
+        let s = syn(Some(0));
+        let tcph = etherparse::TcpHeaderSlice::from_slice(&s).unwrap();
+        let c = Connection::accept(&mut h.nic, &QUAD, tcph, &[], ISS, &Config::default());
+        assert_eq!(c.unwrap().unwrap().mss(), MIN_MSS);
+    }
+
+    // SND.UNA =< SEG.ACK: an ACK that acks nothing new still opens the window
+    #[test]
+    fn duplicate_ack_opens_window() {
+        let mut h = Harness::new(Config::default(), 0);
+        h.segment(IRS + 1, ISS + 1, 1024, &[]);
+        h.c.unacked.extend([1; 100]);
+        h.tick();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1.len(), 100);
+    }
+
+    // RFC 1122 4.2.2.17: with the window shut, a byte at a time goes out to find out when it
+    // opens again
+    #[test]
+    fn zero_window_probe() {
+        let mut h = Harness::new(Config::default(), 0);
+        h.c.unacked.extend([1; 100]);
+        h.tick();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.sequence_number, ISS + 1);
+        assert_eq!(sent[0].1.len(), 1);
+        // one probe at a time
+        h.tick();
+        assert!(h.sent().is_empty());
+
+        // the retransmission timer sends it again
+        h.clock.advance(h.c.rto());
+        h.tick();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.sequence_number, ISS + 1);
+        assert_eq!(sent[0].1.len(), 1);
+
+        // until the peer takes it and opens the window
+        h.segment(IRS + 1, ISS + 2, 1024, &[]);
+        h.tick();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.sequence_number, ISS + 2);
+        assert_eq!(sent[0].1.len(), 99);
+    }
+
+    // a bare ACK takes up no sequence space, so nothing ever acks it and it isn't timed
+    #[test]
+    fn bare_ack_not_timed() {
+        let mut h = Harness::new(Config::default(), 1024);
+        h.segment(IRS + 1, ISS + 1, 1024, b"hello");
+        assert_eq!(h.sent().len(), 1);
+        assert!(h.c.timers.send_times.is_empty());
+        assert_eq!(h.c.next_timeout(), None);
+
+        h.c.unacked.extend(b"world");
+        h.tick();
+        assert_eq!(h.sent().len(), 1);
+        assert_eq!(h.c.timers.send_times.len(), 1);
+        assert!(h.c.next_timeout().is_some());
+    }
+
+    // with only the FIN left to send, a shut window gets probed with that
+    #[test]
+    fn zero_window_fin_probe() {
+        let mut h = Harness::new(Config::default(), 0);
+        h.c.close().unwrap();
+        h.tick();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].0.fin);
+        assert_eq!(sent[0].0.sequence_number, ISS + 1);
+        assert!(sent[0].1.is_empty());
+        h.tick();
+        assert!(h.sent().is_empty());
+
+        h.clock.advance(h.c.rto());
+        h.tick();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].0.fin);
+
+        // turned away, so it goes again as soon as the window opens
+        h.segment(IRS + 1, ISS + 1, 1024, &[]);
+        h.tick();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].0.fin);
+        assert_eq!(sent[0].0.sequence_number, ISS + 1);
+
+        h.segment(IRS + 1, ISS + 2, 1024, &[]);
+        assert!(matches!(h.c.state(), State::FinWait2));
+    }
+
+    // an ACK for part of a segment, like a late one for a probe byte, leaves the rest of it
+    // timed
+    #[test]
+    fn partial_ack_keeps_timer() {
+        let mut h = Harness::new(Config::default(), 1024);
+        h.c.unacked.extend([1; 100]);
+        h.tick();
+        assert_eq!(h.sent().len(), 1);
+
+        h.segment(IRS + 1, ISS + 11, 1024, &[]);
+        assert_eq!(h.c.next_timeout(), Some(h.nic.now() + h.c.rto()));
+        h.clock.advance(h.c.rto());
+        h.tick();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.sequence_number, ISS + 11);
+        assert_eq!(sent[0].1.len(), 90);
+    }
+
+    // a window update that doesn't take our probe byte means the peer dropped it, so we start
+    // over from SND.UNA instead of sending past the hole
+    #[test]
+    fn refused_probe_rewinds() {
+        let mut h = Harness::new(Config::default(), 0);
+        h.c.unacked.extend([1; 100]);
+        h.tick();
+        assert_eq!(h.sent()[0].1.len(), 1);
+
+        h.segment(IRS + 1, ISS + 1, 1024, &[]);
+        h.tick();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.sequence_number, ISS + 1);
+        assert_eq!(sent[0].1.len(), 100);
+    }
+
+    // the segment starting at SND.UNA is the first one an ACK covers, and it gets timed too
+    #[test]
+    fn rtt_sample_from_una() {
+        let mut h = Harness::new(Config::default(), 1024);
+        assert_eq!(h.c.timers.srtt, None);
+        h.c.unacked.extend([1; 100]);
+        h.tick();
+        assert_eq!(h.sent().len(), 1);
+
+        h.clock.advance(time::Duration::from_millis(100));
+        h.segment(IRS + 1, ISS + 101, 1024, &[]);
+        assert!(h.c.timers.send_times.is_empty());
+        assert_eq!(h.c.timers.srtt, Some(0.1));
+        assert!((h.c.timers.rttvar - 0.05).abs() < 1e-9);
+
+        // and from then on, RFC 6298 2.3
+        h.c.unacked.extend([1; 100]);
+        h.tick();
+        h.clock.advance(time::Duration::from_millis(300));
+        h.segment(IRS + 1, ISS + 201, 1024, &[]);
+        assert!((h.c.timers.rttvar - (0.75 * 0.05 + 0.25 * 0.2)).abs() < 1e-9);
+        assert!((h.c.timers.srtt.unwrap() - (0.875 * 0.1 + 0.125 * 0.3)).abs() < 1e-9);
+    }
+
+    // an ACK for something we sent twice can't be timed (Karn's algorithm)
+    #[test]
+    fn no_rtt_sample_after_retransmit() {
+        let mut h = Harness::new(Config::default(), 1024);
+        h.c.unacked.extend([1; 100]);
+        h.tick();
+        h.clock.advance(INITIAL_RTO);
+        h.tick();
+        assert_eq!(h.sent().len(), 2);
+
+        h.clock.advance(time::Duration::from_millis(10));
+        h.segment(IRS + 1, ISS + 101, 1024, &[]);
+        assert_eq!(h.c.timers.srtt, None);
+    }
+
+    // a tick sends everything the window has room for, not a segment at a time
+    #[test]
+    fn whole_window_per_tick() {
+        let mss = DEFAULT_MSS as usize;
+        let mut h = Harness::new(Config::default(), 4 * DEFAULT_MSS);
+        h.c.unacked.extend(vec![1; 5 * mss]);
+        h.tick();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 4);
+        for (i, (tcph, data)) in sent.iter().enumerate() {
+            assert_eq!(tcph.sequence_number, ISS + 1 + (i * mss) as u32);
+            assert_eq!(data.len(), mss);
+        }
+        // the rest waits for the window to open
+        h.tick();
+        assert!(h.sent().is_empty());
+    }
+
+    // the retransmission timer goes off once the segment has waited the RTO, not a tick later
+    #[test]
+    fn retransmit_at_rto() {
+        let mut h = Harness::new(Config::default(), 1024);
+        h.c.unacked.extend([1; 100]);
+        h.tick();
+        assert_eq!(h.sent().len(), 1);
+        let rto = h.c.rto();
+        assert_eq!(h.c.next_timeout(), Some(h.nic.now() + rto));
+
+        h.clock.advance(rto - time::Duration::from_millis(1));
+        h.tick();
+        assert!(h.sent().is_empty());
+
+        h.clock.advance(time::Duration::from_millis(1));
+        h.tick();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.sequence_number, ISS + 1);
+        assert_eq!(sent[0].1.len(), 100);
+    }
+
+    // the RTO is SRTT + 4*RTTVAR, but never less than a second
+    #[test]
+    fn rto_floor() {
+        let mut h = Harness::new(Config::default(), 1024);
+        assert_eq!(h.c.rto(), INITIAL_RTO);
+        h.c.timers.srtt = Some(0.1);
+        h.c.timers.rttvar = 0.05;
+        assert_eq!(h.c.rto(), time::Duration::from_secs(1));
+        h.c.timers.srtt = Some(2.0);
+        h.c.timers.rttvar = 0.25;
+        assert_eq!(h.c.rto(), time::Duration::from_secs(3));
+
+        // a fast peer still gets its second
+        h.c.timers.srtt = Some(0.1);
+        h.c.timers.rttvar = 0.05;
+        h.c.unacked.extend([1; 100]);
+        h.tick();
+        assert_eq!(h.sent().len(), 1);
+        h.clock.advance(time::Duration::from_millis(999));
+        h.tick();
+        assert!(h.sent().is_empty());
+        h.clock.advance(time::Duration::from_millis(1));
+        h.tick();
+        assert_eq!(h.sent().len(), 1);
+    }
+
+    // backing off doesn't overflow, however many times the SYN has to go out again
+    #[test]
+    fn syn_rto_capped() {
+        let config = Config {
+            syn_retries: u32::MAX,
+            ..Config::default()
+        };
+        let mut h = Harness::connecting(config);
+        h.tick();
+        assert_eq!(h.sent().len(), 1);
+        assert_eq!(h.c.syn_rto(), INITIAL_RTO);
+
+        h.c.timers.syn_ack_retries = 40;
+        assert_eq!(h.c.syn_rto(), MAX_RTO);
+        h.clock.advance(MAX_RTO);
+        h.tick();
+        assert_eq!(h.sent().len(), 1);
+        assert_eq!(h.c.next_timeout(), Some(h.nic.now() + MAX_RTO));
+
+        // nor does an RTO the round trips would put past it
+        h.c.timers.srtt = Some(1e6);
+        assert_eq!(h.c.rto(), MAX_RTO);
+    }
+
+    // every time the RTO runs out it doubles, up to MAX_RTO, until something gets acked
+    #[test]
+    fn rto_backoff() {
+        let config = Config {
+            user_timeout: time::Duration::from_secs(1000),
+            ..Config::default()
+        };
+        let mut h = Harness::new(config, 1024);
+        h.c.unacked.extend([1; 100]);
+        h.tick();
+        assert_eq!(h.sent().len(), 1);
+        for secs in [1, 2, 4, 8, 16, 32, 60, 60] {
+            let rto = time::Duration::from_secs(secs);
+            assert_eq!(h.c.rto(), rto);
+            h.clock.advance(rto - time::Duration::from_millis(1));
+            h.tick();
+            assert!(h.sent().is_empty());
+            h.clock.advance(time::Duration::from_millis(1));
+            h.tick();
+            assert_eq!(h.sent().len(), 1);
+        }
+        h.segment(IRS + 1, ISS + 101, 1024, &[]);
+        assert_eq!(h.c.rto(), INITIAL_RTO);
+    }
+
+    // a SYN with data on it, in the middle of a simultaneous open, is unusual but no reason
+    // to fall over
+    #[test]
+    fn syn_data_while_opening() {
+        let syn = |seq: u32| {
+            let mut h = etherparse::TcpHeader::new(40000, 8080, seq, 1024);
+            h.syn = true;
+            h.to_bytes().to_vec()
+        };
+        let mut h = Harness::connecting(Config::default());
+        h.tick();
+        assert_eq!(h.sent().len(), 1);
+        let s = syn(IRS);
+        let tcph = etherparse::TcpHeaderSlice::from_slice(&s).unwrap();
+        h.c.on_packet(&mut h.nic, tcph, b"early").unwrap();
+        assert!(matches!(h.c.state(), State::SyncRcvd));
+
+        let s = syn(IRS.wrapping_add(1));
+        let tcph = etherparse::TcpHeaderSlice::from_slice(&s).unwrap();
+        h.c.on_packet(&mut h.nic, tcph, b"early").unwrap();
+        assert!(matches!(h.c.state(), State::SyncRcvd));
+        // none of it was taken
+        assert_eq!(h.c.consume(&mut [0u8; 16]), 0);
+    }
+
+    // a router dropping what's in flight as too big gets all of it again, in smaller pieces,
+    // and a path below the base is believed
+    #[test]
+    fn too_big_resends_flight() {
+        let config = Config::default();
+        let ack = header(IRS.wrapping_add(1), ISS.wrapping_add(1), u16::MAX);
+        let tcph = etherparse::TcpHeaderSlice::from_slice(&ack).unwrap();
+        let mut h = Harness::with(Connection::from_cookie(&QUAD, &config, tcph, ISS, 1460));
+        let flight: Vec<u8> = (0..3 * 1460).map(|i| i as u8).collect();
+        h.c.unacked.extend(&flight);
+        h.tick();
+        assert_eq!(h.sent().len(), 3);
+
+        h.c.on_icmp_too_big(&mut h.nic, ISS + 1, 1000).unwrap();
+        let sent = h.sent();
+        assert_eq!(sent.len(), 5);
+        let mut seq = ISS + 1;
+        let mut resent = Vec::new();
+        for (tcph, data) in &sent {
+            assert_eq!(tcph.sequence_number, seq);
+            assert!(data.len() <= 1000 - 40);
+            seq += data.len() as u32;
+            resent.extend_from_slice(data);
+        }
+        assert_eq!(resent, flight);
+        assert_eq!(h.c.mss(), 960);
+    }
+}
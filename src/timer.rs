@@ -0,0 +1,58 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time;
+
+use crate::Quad;
+
+/*
+  Connection timers
+
+  Every connection has at most one deadline, the earliest of whatever it's waiting for, be it
+  a retransmission, a window probe, the SYN it has to send again, or the end of TIME-WAIT.
+  They're kept in order, so a tick only gets to the connections that are due:
+
+      deadlines: quad --> when        queue: (when, quad), earliest first
+                                             |
+      tick: pop everything up to now --------+--> on_tick --> next_timeout --> back in
+
+  Whatever else happens to a connection, a packet for it or the application writing to it,
+  makes it due right away, and it goes back in with its next deadline once it's been ticked.
+  A connection that's gone by the time its deadline comes up is skipped.
+*/
+
+#[derive(Default)]
+pub(crate) struct TimerQueue {
+    deadlines: HashMap<Quad, time::Instant>,
+    queue: BTreeSet<(time::Instant, Quad)>,
+}
+
+impl TimerQueue {
+    // ticks quad at at, or earlier if it's due earlier already
+    pub(crate) fn schedule(&mut self, quad: Quad, at: time::Instant) {
+        if let Some(&old) = self.deadlines.get(&quad) {
+            if old <= at {
+                return;
+            }
+            self.queue.remove(&(old, quad));
+        }
+        self.deadlines.insert(quad, at);
+        self.queue.insert((at, quad));
+    }
+
+    // takes out every connection that's due by now
+    pub(crate) fn due(&mut self, now: time::Instant) -> Vec<Quad> {
+        let mut due = Vec::new();
+        while let Some(&(at, quad)) = self.queue.first() {
+            if at > now {
+                break;
+            }
+            self.queue.pop_first();
+            self.deadlines.remove(&quad);
+            due.push(quad);
+        }
+        due
+    }
+
+    pub(crate) fn next(&self) -> Option<time::Instant> {
+        self.queue.first().map(|&(at, _)| at)
+    }
+}
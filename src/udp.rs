@@ -0,0 +1,238 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::ip::IpHeader;
+use crate::link::Nic;
+
+// how many bytes of datagrams we hold for a socket until the application reads them
+const RECV_QUEUE_SIZE: usize = 64 * 1024;
+// and how many until packet_loop gets around to sending them
+const SEND_QUEUE_SIZE: usize = 64 * 1024;
+
+#[derive(Default)]
+pub(crate) struct Socket {
+    // datagrams and who sent them, oldest first
+    incoming: VecDeque<(SocketAddr, Vec<u8>)>,
+    queued: usize,
+    // datagrams to send, from and to where
+    outgoing: VecDeque<(SocketAddr, SocketAddr, Vec<u8>)>,
+    unsent: usize,
+}
+
+impl Socket {
+    // returns false if there was no room for it
+    pub(crate) fn deliver(&mut self, from: SocketAddr, data: &[u8]) -> bool {
+        if self.queued + data.len() > RECV_QUEUE_SIZE {
+            return false;
+        }
+        self.queued += data.len();
+        self.incoming.push_back((from, data.to_vec()));
+        true
+    }
+
+    // like recv_from on a real socket, whatever doesn't fit in buf is lost
+    pub(crate) fn recv(&mut self, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        let (from, data) = self.incoming.pop_front()?;
+        self.queued -= data.len();
+        let n = std::cmp::min(buf.len(), data.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Some((n, from))
+    }
+
+    // false if there's no room for it yet
+    pub(crate) fn queue(
+        &mut self,
+        from: SocketAddr,
+        to: SocketAddr,
+        data: &[u8],
+        mtu: usize,
+    ) -> io::Result<bool> {
+        let headers = match to {
+            SocketAddr::V4(_) => etherparse::Ipv4Header::MIN_LEN,
+            SocketAddr::V6(_) => etherparse::Ipv6Header::LEN,
+        } + etherparse::UdpHeader::LEN;
+        // we don't fragment, so a datagram has to fit in one packet
+        if headers + data.len() > mtu {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram too large",
+            ));
+        }
+        if self.unsent + data.len() > SEND_QUEUE_SIZE {
+            return Ok(false);
+        }
+        self.unsent += data.len();
+        self.outgoing.push_back((from, to, data.to_vec()));
+        Ok(true)
+    }
+
+    pub(crate) fn has_unsent(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+
+    pub(crate) fn on_tick(&mut self, nic: &mut Nic) -> io::Result<()> {
+        while let Some((from, to, data)) = self.outgoing.pop_front() {
+            self.unsent -= data.len();
+            send(nic, from, to, &data)?;
+        }
+        Ok(())
+    }
+}
+
+// the header and payload of a datagram, if its length and checksum check out
+pub(crate) fn parse(
+    src: IpAddr,
+    dst: IpAddr,
+    data: &[u8],
+) -> Option<(etherparse::UdpHeaderSlice<'_>, &[u8])> {
+    let udph = match etherparse::UdpHeaderSlice::from_slice(data) {
+        Ok(udph) => udph,
+        Err(e) => {
+            eprintln!("ignoring weird udp packets {:?}", e);
+            return None;
+        }
+    };
+    let len = udph.length() as usize;
+    if len < etherparse::UdpHeader::LEN || len > data.len() {
+        eprintln!("ignoring udp packet with bad length");
+        return None;
+    }
+    // anything past the length UDP claims is padding
+    let payload = &data[etherparse::UdpHeader::LEN..len];
+    let header = udph.to_header();
+    let expected = match (src, dst) {
+        // the checksum is optional over IPv4
+        (IpAddr::V4(_), IpAddr::V4(_)) if udph.checksum() == 0 => Ok(0),
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.calc_checksum_ipv4_raw(src.octets(), dst.octets(), payload)
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            header.calc_checksum_ipv6_raw(src.octets(), dst.octets(), payload)
+        }
+        _ => return None,
+    };
+    if expected.ok() != Some(udph.checksum()) {
+        eprintln!("ignoring udp packet with bad checksum");
+        return None;
+    }
+    Some((udph, payload))
+}
+
+fn send(nic: &mut Nic, from: SocketAddr, to: SocketAddr, payload: &[u8]) -> io::Result<()> {
+    let mut ip = IpHeader::new(from.ip(), to.ip(), etherparse::IpNumber::UDP);
+    let mut udp = etherparse::UdpHeader {
+        source_port: from.port(),
+        destination_port: to.port(),
+        length: (etherparse::UdpHeader::LEN + payload.len()) as u16,
+        checksum: 0,
+    };
+    ip.set_payload_len(etherparse::UdpHeader::LEN + payload.len());
+    udp.checksum = ip.udp_checksum(&udp, payload);
+
+    let mut buf = Vec::with_capacity(ip.header_len() + etherparse::UdpHeader::LEN + payload.len());
+    ip.write(&mut buf)?;
+    udp.write(&mut buf)?;
+    buf.extend_from_slice(payload);
+    nic.send(&buf)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    const SRC: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const DST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn datagram(src: IpAddr, dst: IpAddr, payload: &[u8]) -> Vec<u8> {
+        let mut ip = IpHeader::new(src, dst, etherparse::IpNumber::UDP);
+        ip.set_payload_len(etherparse::UdpHeader::LEN + payload.len());
+        let mut udp = etherparse::UdpHeader {
+            source_port: 5000,
+            destination_port: 53,
+            length: (etherparse::UdpHeader::LEN + payload.len()) as u16,
+            checksum: 0,
+        };
+        udp.checksum = ip.udp_checksum(&udp, payload);
+        let mut buf = udp.to_bytes().to_vec();
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    fn payload_of(src: IpAddr, dst: IpAddr, data: &[u8]) -> Option<Vec<u8>> {
+        parse(src, dst, data).map(|(_, p)| p.to_vec())
+    }
+
+    #[test]
+    fn checksum() {
+        let (src, dst) = (SRC.into(), DST.into());
+        let mut d = datagram(src, dst, b"hello");
+        assert_eq!(payload_of(src, dst, &d).unwrap(), b"hello");
+        // it covers the addresses too
+        let other = Ipv4Addr::new(10, 0, 0, 3).into();
+        assert_eq!(payload_of(src, other, &d), None);
+
+        d[8] ^= 1;
+        assert_eq!(payload_of(src, dst, &d), None);
+        // but over IPv4 there doesn't have to be one
+        d[6..8].copy_from_slice(&[0, 0]);
+        assert_eq!(payload_of(src, dst, &d).unwrap(), b"iello");
+
+        let src: IpAddr = "fd00::2".parse::<Ipv6Addr>().unwrap().into();
+        let dst: IpAddr = "fd00::1".parse::<Ipv6Addr>().unwrap().into();
+        let mut d = datagram(src, dst, b"hello");
+        assert_eq!(payload_of(src, dst, &d).unwrap(), b"hello");
+        // over IPv6 there does (RFC 8200 8.1)
+        d[6..8].copy_from_slice(&[0, 0]);
+        assert_eq!(payload_of(src, dst, &d), None);
+        // and the addresses have to be of the same family
+        assert_eq!(payload_of(SRC.into(), dst, &d), None);
+    }
+
+    #[test]
+    fn length() {
+        let (src, dst) = (SRC.into(), DST.into());
+        let d = datagram(src, dst, b"hello");
+        // padding past the UDP length isn't part of the payload
+        let mut padded = d.clone();
+        padded.extend([0; 4]);
+        assert_eq!(payload_of(src, dst, &padded).unwrap(), b"hello");
+
+        // a length longer than what we got
+        assert_eq!(payload_of(src, dst, &d[..d.len() - 1]), None);
+        // or shorter than the header
+        let mut short = d.clone();
+        short[4..6].copy_from_slice(&4u16.to_be_bytes());
+        assert_eq!(payload_of(src, dst, &short), None);
+        // or no header at all
+        assert_eq!(payload_of(src, dst, &d[..6]), None);
+    }
+
+    // we don't fragment, so a datagram has to fit in one packet
+    #[test]
+    fn queue_fits_mtu() {
+        let mut s = Socket::default();
+        let from = SocketAddr::new(DST.into(), 53);
+        let to = SocketAddr::new(SRC.into(), 5000);
+        assert!(s.queue(from, to, &[0; 1472], 1500).unwrap());
+        let err = s.queue(from, to, &[0; 1473], 1500).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    // a full queue takes nothing more until what's in it has been sent
+    #[test]
+    fn queue_full() {
+        let mut s = Socket::default();
+        let from = SocketAddr::new(DST.into(), 53);
+        let to = SocketAddr::new(SRC.into(), 5000);
+        let mut queued = 0;
+        while s.queue(from, to, &[0; 1000], 1500).unwrap() {
+            queued += 1000;
+        }
+        assert!(queued <= SEND_QUEUE_SIZE && queued + 1000 > SEND_QUEUE_SIZE);
+        assert!(s.has_unsent());
+    }
+}
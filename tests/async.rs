@@ -0,0 +1,221 @@
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake};
+use std::thread;
+
+// about the least an executor can be: poll, and park until woken
+struct Unpark(thread::Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(f: F) -> F::Output {
+    let waker = Arc::new(Unpark(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    let mut f = pin!(f);
+    loop {
+        if let Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+            return v;
+        }
+        thread::park();
+    }
+}
+
+#[test]
+fn echo() {
+    let (a, b) = rtcp::virtual_link().unwrap();
+    let mut client = rtcp::Interface::with_device(a).unwrap();
+    client.add_local_addr(Ipv4Addr::new(10, 0, 0, 1).into());
+    let mut server = rtcp::Interface::with_device(b).unwrap();
+    server.add_local_addr(Ipv4Addr::new(10, 0, 0, 2).into());
+
+    let mut l = server.bind(8080).unwrap();
+    let echo = thread::spawn(move || {
+        block_on(async {
+            let mut s = l.accept_async().await.unwrap();
+            let mut buf = [0u8; 512];
+            loop {
+                let n = s.read_async(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                let mut sent = 0;
+                while sent < n {
+                    sent += s.write_async(&buf[sent..n]).await.unwrap();
+                }
+            }
+            s.flush_async().await.unwrap();
+            s.shutdown(std::net::Shutdown::Write).unwrap();
+            s
+        })
+    });
+
+    // more than fits in the send queue, so writes have to wait for acks
+    let msg: Vec<u8> = (0..8 * 1024).map(|i| i as u8).collect();
+    let back = block_on(async {
+        let mut s = client
+            .connect_async(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 8080))
+            .await
+            .unwrap();
+        let mut back = Vec::new();
+        let mut buf = [0u8; 512];
+        for chunk in msg.chunks(1024) {
+            let mut sent = 0;
+            while sent < chunk.len() {
+                sent += s.write_async(&chunk[sent..]).await.unwrap();
+            }
+            s.flush_async().await.unwrap();
+            // read the echo as we go, or both ends would sit on full queues
+            let want = back.len() + chunk.len();
+            while back.len() < want {
+                let n = s.read_async(&mut buf).await.unwrap();
+                assert_ne!(n, 0);
+                back.extend_from_slice(&buf[..n]);
+            }
+        }
+        s.shutdown(std::net::Shutdown::Write).unwrap();
+        loop {
+            let n = s.read_async(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            back.extend_from_slice(&buf[..n]);
+        }
+        back
+    });
+    assert_eq!(back, msg);
+    drop(echo.join().unwrap());
+}
+
+#[test]
+fn connect_refused() {
+    let (a, b) = rtcp::virtual_link().unwrap();
+    let mut client = rtcp::Interface::with_device(a).unwrap();
+    client.add_local_addr(Ipv4Addr::new(10, 0, 0, 1).into());
+    let _server = rtcp::Interface::with_device(b).unwrap();
+
+    let err =
+        block_on(client.connect_async(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 8080)))
+            .err()
+            .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+}
+
+// counts how often it's been woken
+#[derive(Default)]
+struct Count(std::sync::atomic::AtomicUsize);
+
+impl Wake for Count {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+impl Count {
+    fn get(&self) -> usize {
+        self.0.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+// data for one stream wakes the task reading it, not the one reading the other
+#[test]
+fn wakes_only_its_own_task() {
+    use std::io::{Read, Write};
+
+    let (a, b) = rtcp::virtual_link().unwrap();
+    let mut client = rtcp::Interface::with_device(a).unwrap();
+    client.add_local_addr(Ipv4Addr::new(10, 0, 0, 1).into());
+    let mut server = rtcp::Interface::with_device(b).unwrap();
+    server.add_local_addr(Ipv4Addr::new(10, 0, 0, 2).into());
+
+    let mut l = server.bind(8080).unwrap();
+    let addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 8080);
+    let mut first = client.connect(addr).unwrap();
+    let mut their_first = l.accept().unwrap();
+    let mut second = client.connect(addr).unwrap();
+    let mut their_second = l.accept().unwrap();
+
+    let count = Arc::new(Count::default());
+    let waker = count.clone().into();
+    let mut buf = [0u8; 16];
+    assert!(first
+        .poll_read(&mut Context::from_waker(&waker), &mut buf)
+        .is_pending());
+
+    their_second.write_all(b"second").unwrap();
+    second.read_exact(&mut buf[..6]).unwrap();
+    assert_eq!(&buf[..6], b"second");
+    assert_eq!(count.get(), 0);
+
+    their_first.write_all(b"first").unwrap();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while count.get() == 0 {
+        assert!(std::time::Instant::now() < deadline, "never woken");
+        thread::sleep(std::time::Duration::from_millis(1));
+    }
+    assert_eq!(count.get(), 1);
+    first.read_exact(&mut buf[..5]).unwrap();
+    assert_eq!(&buf[..5], b"first");
+}
+
+// close waits for what was written to be acked before it sends the FIN
+#[cfg(feature = "futures-io")]
+#[test]
+fn close_flushes() {
+    use std::io::Read;
+
+    let (a, b) = rtcp::virtual_link().unwrap();
+    // room for more than the peer's window, so it can't all be acked until the peer reads
+    let mut client = rtcp::Interface::builder()
+        .send_buffer(4 * 1024)
+        .build_with_device(a)
+        .unwrap();
+    client.add_local_addr(Ipv4Addr::new(10, 0, 0, 1).into());
+    let mut server = rtcp::Interface::with_device(b).unwrap();
+    server.add_local_addr(Ipv4Addr::new(10, 0, 0, 2).into());
+
+    let mut l = server.bind(8080).unwrap();
+    let (go, wait) = std::sync::mpsc::channel();
+    let peer = thread::spawn(move || {
+        let mut s = l.accept().unwrap();
+        wait.recv().unwrap();
+        let mut got = Vec::new();
+        s.read_to_end(&mut got).unwrap();
+        got
+    });
+
+    let msg: Vec<u8> = (0..2 * 1024).map(|i| i as u8).collect();
+    let mut s = client
+        .connect(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 8080))
+        .unwrap();
+    block_on(async {
+        let mut sent = 0;
+        while sent < msg.len() {
+            sent += s.write_async(&msg[sent..]).await.unwrap();
+        }
+    });
+    let waker = Arc::new(Count::default()).into();
+    let close = futures_io::AsyncWrite::poll_close(pin!(&mut s), &mut Context::from_waker(&waker));
+    assert!(close.is_pending());
+
+    go.send(()).unwrap();
+    block_on(std::future::poll_fn(|cx| {
+        futures_io::AsyncWrite::poll_close(pin!(&mut s), cx)
+    }))
+    .unwrap();
+    assert_eq!(peer.join().unwrap(), msg);
+    // the peer's FIN too, so both sides are done
+    assert_eq!(s.read(&mut [0]).unwrap(), 0);
+    assert_eq!(s.state().unwrap(), rtcp::tcp::State::TimeWait);
+    // closing again changes nothing
+    block_on(std::future::poll_fn(|cx| {
+        futures_io::AsyncWrite::poll_close(pin!(&mut s), cx)
+    }))
+    .unwrap();
+    s.shutdown(std::net::Shutdown::Write).unwrap();
+}
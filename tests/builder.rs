@@ -0,0 +1,88 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr};
+use std::{thread, time};
+
+fn v4(last: u8) -> IpAddr {
+    Ipv4Addr::new(10, 0, 0, last).into()
+}
+
+// a connection to the address the server was given goes through, one to its neighbor on the
+// same link is dropped without even a RST
+#[test]
+fn only_takes_packets_for_us() {
+    let (a, b) = rtcp::virtual_link().unwrap();
+    let mut client = rtcp::Interface::builder()
+        .addr(v4(1), 24)
+        .initial_rto(time::Duration::from_millis(50))
+        .syn_retries(1)
+        .build_with_device(a)
+        .unwrap();
+    let mut server = rtcp::Interface::builder()
+        .addr(v4(2), 24)
+        .build_with_device(b)
+        .unwrap();
+
+    let mut l = server.bind(8080).unwrap();
+    let accepted = thread::spawn(move || l.accept().map(drop));
+
+    let err = client.connect(SocketAddr::new(v4(3), 8080)).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+    client.connect(SocketAddr::new(v4(2), 8080)).unwrap();
+    accepted.join().unwrap().unwrap();
+}
+
+// small packets and small buffers still get everything across
+#[test]
+fn small_mtu_and_buffers() {
+    let (a, b) = rtcp::virtual_link().unwrap();
+    let mut client = rtcp::Interface::builder()
+        .addr(v4(1), 24)
+        .mtu(576)
+        .send_buffer(300)
+        .build_with_device(a)
+        .unwrap();
+    let mut server = rtcp::Interface::builder()
+        .addr(v4(2), 24)
+        .mtu(576)
+        .recv_buffer(700)
+        .build_with_device(b)
+        .unwrap();
+
+    let mut l = server.bind(8080).unwrap();
+    let receiver = thread::spawn(move || {
+        let mut s = l.accept().unwrap();
+        let mut got = Vec::new();
+        s.read_to_end(&mut got).unwrap();
+        got
+    });
+
+    let data: Vec<u8> = (0..16 * 1024u32).map(|i| (i * 13) as u8).collect();
+    let mut s = client.connect(SocketAddr::new(v4(2), 8080)).unwrap();
+    s.write_all(&data).unwrap();
+    s.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(receiver.join().unwrap(), data);
+}
+
+#[test]
+fn rejects_nonsense() {
+    let invalid = |b: rtcp::InterfaceBuilder| {
+        let (a, _b) = rtcp::virtual_link().unwrap();
+        let err = b.build_with_device(a).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    };
+    invalid(rtcp::Interface::builder().addr(v4(1), 33));
+    invalid(rtcp::Interface::builder().mtu(100));
+    invalid(rtcp::Interface::builder().mtu(100_000));
+    invalid(
+        rtcp::Interface::builder()
+            .mtu(1000)
+            .addr("fd00::1".parse().unwrap(), 64),
+    );
+    invalid(rtcp::Interface::builder().recv_buffer(1 << 16));
+    invalid(rtcp::Interface::builder().send_buffer(0));
+    invalid(rtcp::Interface::builder().initial_rto(time::Duration::ZERO));
+    invalid(rtcp::Interface::builder().syn_retries(100));
+    invalid(rtcp::Interface::builder().tap([2, 0, 0, 0, 0, 1]));
+    invalid(rtcp::Interface::builder().gateway(std::net::Ipv4Addr::new(10, 0, 0, 254)));
+}
@@ -0,0 +1,127 @@
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{io, thread, time};
+
+use rtcp::tcp::State;
+
+mod common;
+
+// lets the test cut the link in one direction, and counts what goes out
+struct Switch {
+    dev: rtcp::VirtualDevice,
+    cut: Arc<AtomicBool>,
+    sent: Arc<AtomicUsize>,
+}
+
+impl rtcp::Device for Switch {
+    fn send(&mut self, frame: &[u8]) -> io::Result<usize> {
+        self.sent.fetch_add(1, Ordering::SeqCst);
+        if self.cut.load(Ordering::SeqCst) {
+            return Ok(frame.len());
+        }
+        self.dev.send(frame)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.dev.recv(buf)
+    }
+}
+
+impl AsRawFd for Switch {
+    fn as_raw_fd(&self) -> RawFd {
+        self.dev.as_raw_fd()
+    }
+}
+
+struct Pair {
+    clock: rtcp::ManualClock,
+    cut: Arc<AtomicBool>,
+    sent: Arc<AtomicUsize>,
+    client: rtcp::TcpStream,
+    server: rtcp::TcpStream,
+    // dropped last, so the streams can still talk to them
+    _interfaces: (rtcp::Interface, rtcp::Interface),
+}
+
+// two stacks on one manual clock, connected to each other. the client's side of the link can
+// be cut.
+fn pair() -> Pair {
+    let clock = rtcp::ManualClock::new();
+    let (a, b) = rtcp::virtual_link().unwrap();
+    let cut = Arc::new(AtomicBool::new(false));
+    let sent = Arc::new(AtomicUsize::new(0));
+    let a = Switch {
+        dev: a,
+        cut: cut.clone(),
+        sent: sent.clone(),
+    };
+    let (mut client, mut server) = common::addressed(
+        rtcp::Interface::with_device_and_clock(a, clock.clone()).unwrap(),
+        rtcp::Interface::with_device_and_clock(b, clock.clone()).unwrap(),
+    );
+
+    let mut l = server.bind(8080).unwrap();
+    let accept = thread::spawn(move || l.accept().unwrap());
+    let c = client.connect(common::server_addr(8080)).unwrap();
+    Pair {
+        clock,
+        cut,
+        sent,
+        client: c,
+        server: accept.join().unwrap(),
+        _interfaces: (client, server),
+    }
+}
+
+// the clock stands still, but the stack still ticks in real time. wait for it to get there.
+fn eventually(what: &str, mut f: impl FnMut() -> bool) {
+    let deadline = time::Instant::now() + time::Duration::from_secs(5);
+    while !f() {
+        assert!(time::Instant::now() < deadline, "never {}", what);
+        thread::sleep(time::Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn retransmits_when_the_clock_says_so() {
+    let mut p = pair();
+    p.cut.store(true, Ordering::SeqCst);
+    let before = p.sent.load(Ordering::SeqCst);
+    p.client.write_all(b"lost").unwrap();
+    eventually("sent the data", || p.sent.load(Ordering::SeqCst) > before);
+    p.cut.store(false, Ordering::SeqCst);
+
+    // no time passes, so nothing is retransmitted however long we wait
+    let sent = p.sent.load(Ordering::SeqCst);
+    thread::sleep(time::Duration::from_millis(50));
+    assert_eq!(p.sent.load(Ordering::SeqCst), sent);
+
+    // nothing has been timed yet, so the RTO is the initial second
+    p.clock.advance(time::Duration::from_secs(1));
+    let mut buf = [0u8; 4];
+    p.server.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"lost");
+}
+
+#[test]
+fn time_wait_lasts_until_the_clock_runs_out() {
+    let mut p = pair();
+    p.client.shutdown(Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    p.server.read_to_end(&mut rest).unwrap();
+    p.server.shutdown(Shutdown::Write).unwrap();
+
+    eventually("entered TIME-WAIT", || {
+        p.client.state().unwrap() == State::TimeWait
+    });
+    thread::sleep(time::Duration::from_millis(50));
+    assert_eq!(p.client.state().unwrap(), State::TimeWait);
+
+    p.clock.advance(time::Duration::from_secs(61));
+    eventually("left TIME-WAIT", || {
+        p.client.state().unwrap() == State::Closed
+    });
+}
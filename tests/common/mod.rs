@@ -0,0 +1,37 @@
+// each test only uses some of this
+#![allow(dead_code)]
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+/*
+  What the integration tests share: a client and a server stack on either end of a link.
+
+      client 10.0.0.1 <--- link ---> server 10.0.0.2
+*/
+
+pub const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+pub const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+// where to reach the server on port
+pub fn server_addr(port: u16) -> SocketAddr {
+    SocketAddr::new(SERVER.into(), port)
+}
+
+// a client and a server on the two ends of an in-memory link
+pub fn pair() -> (rtcp::Interface, rtcp::Interface) {
+    let (a, b) = rtcp::virtual_link().unwrap();
+    addressed(
+        rtcp::Interface::with_device(a).unwrap(),
+        rtcp::Interface::with_device(b).unwrap(),
+    )
+}
+
+// gives two interfaces on a link of the caller's their addresses
+pub fn addressed(
+    mut client: rtcp::Interface,
+    mut server: rtcp::Interface,
+) -> (rtcp::Interface, rtcp::Interface) {
+    client.add_local_addr(CLIENT.into());
+    server.add_local_addr(SERVER.into());
+    (client, server)
+}
@@ -0,0 +1,178 @@
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{thread, time};
+
+use rtcp::Device;
+
+// pushes n numbered frames through an impaired device and returns the numbers that came out
+// the other end
+fn numbers_through(config: rtcp::Impairments, n: u32) -> Vec<u32> {
+    let (a, mut b) = rtcp::virtual_link().unwrap();
+    let mut a = rtcp::Impaired::new(a, config).unwrap();
+    for i in 0..n {
+        a.send(&i.to_be_bytes()).unwrap();
+        // don't overrun the link
+        thread::sleep(time::Duration::from_micros(200));
+    }
+    thread::sleep(time::Duration::from_millis(50));
+    let mut got = Vec::new();
+    let mut buf = [0u8; 4];
+    loop {
+        match b.recv(&mut buf) {
+            Ok(_) => got.push(u32::from_be_bytes(buf)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return got,
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+}
+
+#[test]
+fn same_seed_same_fate() {
+    let config = rtcp::Impairments {
+        seed: 42,
+        outbound: rtcp::Impairment {
+            burst_loss: Some(rtcp::GilbertElliott {
+                p: 0.1,
+                r: 0.3,
+                loss_good: 0.01,
+                loss_bad: 0.8,
+            }),
+            duplicate: 0.05,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let first = numbers_through(config.clone(), 200);
+    assert!(first.len() < 200, "nothing was lost");
+    assert_eq!(first, numbers_through(config, 200));
+}
+
+#[test]
+fn reorders_within_depth() {
+    let config = rtcp::Impairments {
+        seed: 7,
+        outbound: rtcp::Impairment {
+            reorder: 3,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let got = numbers_through(config, 100);
+    let mut sorted = got.clone();
+    sorted.sort();
+    assert_eq!(sorted, (0..100).collect::<Vec<_>>());
+    assert_ne!(got, sorted, "nothing was reordered");
+    // nothing overtakes more than three packets that went in before it
+    for (i, &n) in got.iter().enumerate() {
+        assert!(n as usize <= i + 3, "{} came out at {}", n, i);
+    }
+}
+
+// a whole connection over a link that loses, duplicates, reorders and delays packets both ways
+#[test]
+fn tcp_survives() {
+    let bad = rtcp::Impairment {
+        loss: 0.05,
+        duplicate: 0.05,
+        reorder: 2,
+        latency: time::Duration::from_millis(1),
+        jitter: time::Duration::from_millis(1),
+        rate: Some(10_000_000),
+        ..Default::default()
+    };
+    let config = rtcp::Impairments {
+        seed: 1,
+        inbound: bad.clone(),
+        outbound: bad,
+    };
+
+    // retransmission timeouts are seconds long, so speed time up a hundredfold
+    let clock = rtcp::ManualClock::new();
+    let done = Arc::new(AtomicBool::new(false));
+    let ticker = {
+        let clock = clock.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                clock.advance(time::Duration::from_millis(100));
+                thread::sleep(time::Duration::from_millis(1));
+            }
+        })
+    };
+
+    let (a, b) = rtcp::virtual_link().unwrap();
+    let a = rtcp::Impaired::new(a, config).unwrap();
+    let mut client = rtcp::Interface::with_device_and_clock(a, clock.clone()).unwrap();
+    client.add_local_addr(Ipv4Addr::new(10, 0, 0, 1).into());
+    let mut server = rtcp::Interface::with_device_and_clock(b, clock).unwrap();
+    server.add_local_addr(Ipv4Addr::new(10, 0, 0, 2).into());
+
+    let mut l = server.bind(8080).unwrap();
+    let receiver = thread::spawn(move || {
+        let mut s = l.accept().unwrap();
+        let mut got = Vec::new();
+        s.read_to_end(&mut got).unwrap();
+        got
+    });
+
+    let data: Vec<u8> = (0..8192u32).map(|i| (i * 7) as u8).collect();
+    let mut s = client
+        .connect(SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 8080))
+        .unwrap();
+    let mut sent = 0;
+    while sent < data.len() {
+        match s.write(&data[sent..]) {
+            Ok(n) => sent += n,
+            // the send queue is full
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(1))
+            }
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+    s.shutdown(std::net::Shutdown::Write).unwrap();
+
+    assert!(receiver.join().unwrap() == data, "data got mangled");
+    done.store(true, Ordering::SeqCst);
+    ticker.join().unwrap();
+}
+
+#[test]
+fn rejects_nonsense() {
+    let invalid = |outbound: rtcp::Impairment| {
+        let (a, _b) = rtcp::virtual_link().unwrap();
+        let config = rtcp::Impairments {
+            outbound,
+            ..Default::default()
+        };
+        let err = rtcp::Impaired::new(a, config).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    };
+    invalid(rtcp::Impairment {
+        rate: Some(0),
+        ..Default::default()
+    });
+    invalid(rtcp::Impairment {
+        loss: 1.5,
+        ..Default::default()
+    });
+    invalid(rtcp::Impairment {
+        duplicate: -0.1,
+        ..Default::default()
+    });
+    invalid(rtcp::Impairment {
+        loss: f64::NAN,
+        ..Default::default()
+    });
+    invalid(rtcp::Impairment {
+        burst_loss: Some(rtcp::GilbertElliott {
+            p: 0.1,
+            r: 2.0,
+            loss_good: 0.0,
+            loss_bad: 1.0,
+        }),
+        ..Default::default()
+    });
+}
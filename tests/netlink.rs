@@ -0,0 +1,90 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpStream};
+use std::{thread, time};
+
+use nix::sched::{unshare, CloneFlags};
+
+// runs f in a network namespace of its own, so it can set up devices and routes without
+// touching the host's. without the privileges for that, there's nothing to test.
+fn in_netns(f: impl FnOnce() + Send + 'static) {
+    thread::spawn(move || {
+        if let Err(e) = unshare(CloneFlags::CLONE_NEWNET) {
+            eprintln!("skipping, can't create a network namespace: {}", e);
+            return;
+        }
+        f()
+    })
+    .join()
+    .unwrap();
+}
+
+// the kernel connects to us over a tun device, using its own TCP
+fn echo_from_kernel(i: &mut rtcp::Interface, to: IpAddr) {
+    let mut l = i.bind(8080).unwrap();
+    let echo = thread::spawn(move || {
+        let mut s = l.accept().unwrap();
+        let mut got = Vec::new();
+        s.read_to_end(&mut got).unwrap();
+        s.write_all(&got).unwrap();
+        s.shutdown(Shutdown::Write).unwrap();
+        s
+    });
+
+    let mut k =
+        TcpStream::connect_timeout(&SocketAddr::new(to, 8080), time::Duration::from_secs(5))
+            .unwrap();
+    k.set_read_timeout(Some(time::Duration::from_secs(5)))
+        .unwrap();
+    k.write_all(b"hello kernel").unwrap();
+    k.shutdown(Shutdown::Write).unwrap();
+    let mut back = Vec::new();
+    k.read_to_end(&mut back).unwrap();
+    assert_eq!(back, b"hello kernel");
+    drop(echo.join().unwrap());
+}
+
+#[test]
+fn device_addr_and_link_up() {
+    in_netns(|| {
+        let mut i = rtcp::Interface::builder()
+            .name("rtcp0")
+            .device_addr(Ipv4Addr::new(10, 1, 0, 1).into(), 24)
+            .addr(Ipv4Addr::new(10, 1, 0, 2).into(), 24)
+            .up()
+            .build()
+            .unwrap();
+        echo_from_kernel(&mut i, Ipv4Addr::new(10, 1, 0, 2).into());
+    });
+}
+
+#[test]
+fn route() {
+    in_netns(|| {
+        let mut i = rtcp::Interface::builder()
+            .name("rtcp0")
+            .device_addr(Ipv4Addr::new(10, 3, 0, 1).into(), 24)
+            .route(Ipv4Addr::new(10, 4, 0, 0).into(), 16)
+            .up()
+            .build()
+            .unwrap();
+        // off the device's own network, only the route gets it to us
+        echo_from_kernel(&mut i, Ipv4Addr::new(10, 4, 1, 1).into());
+    });
+}
+
+// what the kernel refuses comes back as the error it gave
+#[test]
+fn route_exists() {
+    in_netns(|| {
+        let err = rtcp::Interface::builder()
+            .name("rtcp0")
+            .device_addr(Ipv4Addr::new(10, 5, 0, 1).into(), 24)
+            // the address came with a route to its network already
+            .route(Ipv4Addr::new(10, 5, 0, 0).into(), 24)
+            .up()
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(nix::libc::EEXIST));
+    });
+}
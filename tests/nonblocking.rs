@@ -0,0 +1,129 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::AsFd;
+use std::thread;
+
+mod common;
+
+// waits for the interface to say something may have changed, like an event loop would
+fn wait(r: &rtcp::Readiness) {
+    let mut pfd = [nix::poll::PollFd::new(
+        r.as_fd(),
+        nix::poll::PollFlags::POLLIN,
+    )];
+    let n = nix::poll::poll(&mut pfd[..], 5000u16).unwrap();
+    assert_eq!(n, 1, "no readiness notification in time");
+}
+
+// the fd turns readable once there's a connection to accept
+#[test]
+fn ready_to_accept() {
+    let (mut client, mut server) = common::pair();
+    let ready = server.readiness().unwrap();
+
+    let mut l = server.bind(8080).unwrap();
+    l.set_nonblocking(true).unwrap();
+    assert_eq!(l.accept().err().unwrap().kind(), ErrorKind::WouldBlock);
+
+    let c = client.connect(common::server_addr(8080)).unwrap();
+    let s = loop {
+        ready.clear().unwrap();
+        match l.accept() {
+            Ok(s) => break s,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => wait(&ready),
+            Err(e) => panic!("accept failed {:?}", e),
+        }
+    };
+    drop((c, s));
+}
+
+// a server that never blocks, driven by the interface's readiness fd, against a client
+// sending more than fits in the send queue
+#[test]
+fn event_loop() {
+    let (mut client, mut server) = common::pair();
+    let ready = server.readiness().unwrap();
+
+    let mut l = server.bind(8080).unwrap();
+    l.set_nonblocking(true).unwrap();
+    assert_eq!(l.accept().err().unwrap().kind(), ErrorKind::WouldBlock);
+
+    let msg: Vec<u8> = (0..16 * 1024).map(|i| i as u8).collect();
+    let sender = {
+        let msg = msg.clone();
+        thread::spawn(move || {
+            let mut s = client.connect(common::server_addr(8080)).unwrap();
+            // blocks whenever the send queue is full
+            s.write_all(&msg).unwrap();
+            s.flush().unwrap();
+            s.shutdown(Shutdown::Write).unwrap();
+            // the interface has to stay up until the FIN made it
+            (client, s)
+        })
+    };
+
+    let mut s = loop {
+        ready.clear().unwrap();
+        match l.accept() {
+            Ok(s) => break s,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => wait(&ready),
+            Err(e) => panic!("accept failed {:?}", e),
+        }
+    };
+    s.set_nonblocking(true).unwrap();
+
+    let mut got = Vec::new();
+    let mut buf = [0u8; 512];
+    'events: loop {
+        ready.clear().unwrap();
+        loop {
+            match s.read(&mut buf) {
+                Ok(0) => break 'events,
+                Ok(n) => got.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => panic!("read failed {:?}", e),
+            }
+        }
+        wait(&ready);
+    }
+    assert_eq!(got, msg);
+    drop(sender.join().unwrap());
+}
+
+#[test]
+fn would_block() {
+    let (mut client, mut server) = common::pair();
+
+    let mut l = server.bind(8080).unwrap();
+    let accepted = thread::spawn(move || l.accept().unwrap());
+    let mut c = client.connect(common::server_addr(8080)).unwrap();
+    let mut s = accepted.join().unwrap();
+
+    // nothing was sent, so there's nothing to read
+    s.set_nonblocking(true).unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(
+        s.read(&mut buf).err().unwrap().kind(),
+        ErrorKind::WouldBlock
+    );
+
+    // the server never reads, so the client's send queue fills up eventually
+    c.set_nonblocking(true).unwrap();
+    let chunk = [0u8; 4096];
+    let mut sent = 0;
+    let err = loop {
+        match c.write(&chunk) {
+            Ok(n) => sent += n,
+            Err(e) => break e,
+        }
+        assert!(sent < 1 << 20, "send queue never filled up");
+    };
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+    let u = server.bind_udp(5353).unwrap();
+    u.set_nonblocking(true).unwrap();
+    assert_eq!(
+        u.recv_from(&mut buf).err().unwrap().kind(),
+        ErrorKind::WouldBlock
+    );
+}
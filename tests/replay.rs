@@ -0,0 +1,203 @@
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr};
+use std::{thread, time};
+
+use rtcp::Device;
+
+const PEER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+const US: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+const HANDSHAKE: &str = "tests/data/handshake.pcap";
+// the stack that replays a capture has to pick the same ISN as the one it was made with, or
+// the peer's ACKs don't fit
+const SECRET: [u8; 16] = *b"replay handshake";
+
+fn stack(dev: impl Device + 'static) -> rtcp::Interface {
+    rtcp::Interface::builder()
+        .isn_secret(SECRET)
+        // time stands still, so no timer goes off and M in the ISN stays 0
+        .clock(rtcp::ManualClock::new())
+        .build_with_device(dev)
+        .unwrap()
+}
+
+// waits for the stack to have sent at least n packets
+fn sent(replay: &rtcp::Replay, n: usize) -> Vec<Vec<u8>> {
+    let deadline = time::Instant::now() + time::Duration::from_secs(5);
+    loop {
+        let sent = replay.sent();
+        if sent.len() >= n || time::Instant::now() > deadline {
+            return sent;
+        }
+        thread::sleep(time::Duration::from_millis(10));
+    }
+}
+
+fn tcp(packet: &[u8]) -> etherparse::TcpSlice<'_> {
+    let p = etherparse::SlicedPacket::from_ip(packet).unwrap();
+    let Some(etherparse::TransportSlice::Tcp(tcp)) = p.transport else {
+        panic!("expected a TCP segment, got {:?}", p);
+    };
+    tcp
+}
+
+// the capture has a peer pinging us, then opening a connection to a closed port and one to
+// a port we listen on, sending hello over it and closing it. it also has our side of the
+// conversation, which must not be replayed.
+fn replay_handshake(path: &str, capture: Option<&str>) {
+    let (dev, mut replay) = rtcp::replay(path, PEER, true).unwrap();
+    let mut i = stack(dev);
+    if let Some(capture) = capture {
+        i.capture(capture).unwrap();
+    }
+    let mut l = i.bind(8080).unwrap();
+    replay.start();
+    let mut s = l.accept().unwrap();
+    let mut got = Vec::new();
+    s.read_to_end(&mut got).unwrap();
+    assert_eq!(got, b"hello");
+    replay.wait();
+
+    let sent = sent(&replay, 5);
+    assert!(sent.len() >= 5, "only sent {:?}", sent);
+
+    let echo = etherparse::SlicedPacket::from_ip(&sent[0]).unwrap();
+    let Some(etherparse::TransportSlice::Icmpv4(echo)) = echo.transport else {
+        panic!("expected an echo reply, got {:?}", echo);
+    };
+    assert_eq!(echo.payload(), b"ping");
+
+    let rst = tcp(&sent[1]);
+    assert!(rst.rst());
+    assert_eq!(rst.destination_port(), 40000);
+    assert_eq!(rst.acknowledgment_number(), 1001);
+
+    let syn_ack = tcp(&sent[2]);
+    assert!(syn_ack.syn() && syn_ack.ack());
+    assert_eq!(syn_ack.destination_port(), 40001);
+    assert_eq!(syn_ack.acknowledgment_number(), 2001);
+
+    // the data, then the FIN
+    let ack = tcp(&sent[3]);
+    assert_eq!(ack.acknowledgment_number(), 2006);
+    assert_eq!(
+        ack.sequence_number(),
+        syn_ack.sequence_number().wrapping_add(1)
+    );
+    assert_eq!(tcp(&sent[4]).acknowledgment_number(), 2007);
+}
+
+#[test]
+fn replay_pcap() {
+    replay_handshake(HANDSHAKE, None);
+}
+
+// what we capture ourselves replays just the same
+#[test]
+fn replay_own_capture() {
+    let capture = format!("{}/handshake.pcapng", env!("CARGO_TARGET_TMPDIR"));
+    replay_handshake(HANDSHAKE, Some(&capture));
+    replay_handshake(&capture, None);
+}
+
+// the capture is what generate makes of a conversation with the stack, nothing more
+#[test]
+fn handshake_pcap_is_generated() {
+    let file = std::fs::read(HANDSHAKE).unwrap();
+    assert!(
+        file == generate(),
+        "{} is out of date, run the generate_handshake_pcap test with --ignored",
+        HANDSHAKE
+    );
+}
+
+#[test]
+#[ignore]
+fn generate_handshake_pcap() {
+    std::fs::write(HANDSHAKE, generate()).unwrap();
+}
+
+// plays the peer against a stack set up like the one that replays it, and writes down both
+// sides as a classic pcap of raw IP packets, a millisecond apart
+fn generate() -> Vec<u8> {
+    let (dev, mut peer) = rtcp::virtual_link().unwrap();
+    let mut i = stack(dev);
+    let mut l = i.bind(8080).unwrap();
+    let server = thread::spawn(move || {
+        let mut s = l.accept().unwrap();
+        let mut got = Vec::new();
+        s.read_to_end(&mut got).unwrap();
+        // keeps the connection from closing and sending its FIN until we're done
+        (s, got)
+    });
+
+    let mut packets = Vec::new();
+    // sends a packet from the peer, and takes the next n the stack sends back
+    let mut exchange = |packet: Vec<u8>, n: usize| {
+        peer.send(&packet).unwrap();
+        packets.push(packet);
+        let mut got = Vec::new();
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        let mut buf = [0u8; 1500];
+        while got.len() < n {
+            assert!(time::Instant::now() < deadline, "the stack didn't answer");
+            match peer.recv(&mut buf) {
+                Ok(len) => got.push(buf[..len].to_vec()),
+                Err(_) => thread::sleep(time::Duration::from_millis(1)),
+            }
+        }
+        packets.extend(got.iter().cloned());
+        got
+    };
+    let from_peer = || {
+        let octets = |a: IpAddr| match a {
+            IpAddr::V4(a) => a.octets(),
+            IpAddr::V6(_) => unreachable!(),
+        };
+        etherparse::PacketBuilder::ipv4(octets(PEER), octets(US), 64)
+    };
+    let build = |b: etherparse::PacketBuilderStep<etherparse::TcpHeader>, data: &[u8]| {
+        let mut packet = Vec::new();
+        b.write(&mut packet, data).unwrap();
+        packet
+    };
+
+    let mut ping = Vec::new();
+    from_peer()
+        .icmpv4_echo_request(1, 1)
+        .write(&mut ping, b"ping")
+        .unwrap();
+    exchange(ping, 1);
+    exchange(build(from_peer().tcp(40000, 9, 1000, 64240).syn(), &[]), 1);
+    let syn_ack = exchange(
+        build(from_peer().tcp(40001, 8080, 2000, 64240).syn(), &[]),
+        1,
+    );
+    let iss = tcp(&syn_ack[0]).sequence_number();
+    let ack = |seq| {
+        from_peer()
+            .tcp(40001, 8080, seq, 64240)
+            .ack(iss.wrapping_add(1))
+    };
+    exchange(build(ack(2001), &[]), 0);
+    exchange(build(ack(2001).psh(), b"hello"), 1);
+    exchange(build(ack(2006).fin(), &[]), 1);
+    let (_s, got) = server.join().unwrap();
+    assert_eq!(got, b"hello");
+
+    // pcap header, version 2.4, whole packets, LINKTYPE_RAW
+    let mut file = Vec::new();
+    file.extend(0xa1b2c3d4u32.to_le_bytes());
+    file.extend(2u16.to_le_bytes());
+    file.extend(4u16.to_le_bytes());
+    file.extend([0; 8]);
+    file.extend(0xffffu32.to_le_bytes());
+    file.extend(101u32.to_le_bytes());
+    for (n, p) in packets.iter().enumerate() {
+        file.extend(1_700_000_000u32.to_le_bytes());
+        file.extend((n as u32 * 1000).to_le_bytes());
+        file.extend((p.len() as u32).to_le_bytes());
+        file.extend((p.len() as u32).to_le_bytes());
+        file.extend(p);
+    }
+    file
+}
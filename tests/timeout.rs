@@ -0,0 +1,77 @@
+use std::io::{ErrorKind, Read, Write};
+use std::{thread, time};
+
+mod common;
+
+const TIMEOUT: time::Duration = time::Duration::from_millis(100);
+
+// runs f, which should give up after TIMEOUT
+fn times_out<T>(f: impl FnOnce() -> std::io::Result<T>) {
+    let start = time::Instant::now();
+    let err = f().err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    let took = start.elapsed();
+    assert!(took >= TIMEOUT, "gave up after only {:?}", took);
+    assert!(took < 10 * TIMEOUT, "took {:?} to give up", took);
+}
+
+#[test]
+fn accept_times_out() {
+    let (_client, mut server) = common::pair();
+    let mut l = server.bind(8080).unwrap();
+    assert_eq!(l.accept_timeout().unwrap(), None);
+    l.set_accept_timeout(Some(TIMEOUT)).unwrap();
+    assert_eq!(l.accept_timeout().unwrap(), Some(TIMEOUT));
+    times_out(|| l.accept());
+}
+
+#[test]
+fn read_and_write_time_out() {
+    let (mut client, mut server) = common::pair();
+    let mut l = server.bind(8080).unwrap();
+    let accepted = thread::spawn(move || l.accept().unwrap());
+    let mut c = client.connect(common::server_addr(8080)).unwrap();
+    let mut s = accepted.join().unwrap();
+
+    // the peer never says anything
+    s.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut buf = [0u8; 16];
+    times_out(|| s.read(&mut buf));
+
+    // but what it does say still gets through
+    c.write_all(b"hi").unwrap();
+    assert_eq!(s.read(&mut buf).unwrap(), 2);
+
+    // and it never reads, so the client's send queue fills up and stays full
+    c.set_write_timeout(Some(TIMEOUT)).unwrap();
+    let chunk = [0u8; 4096];
+    let start = time::Instant::now();
+    let err = loop {
+        if let Err(e) = c.write(&chunk) {
+            break e;
+        }
+        assert!(start.elapsed() < time::Duration::from_secs(5));
+    };
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    times_out(|| c.flush());
+}
+
+#[test]
+fn zero_timeout_is_invalid() {
+    let (_client, mut server) = common::pair();
+    let l = server.bind(8080).unwrap();
+    let err = l
+        .set_accept_timeout(Some(time::Duration::ZERO))
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn recv_from_times_out() {
+    let (_client, mut server) = common::pair();
+    let u = server.bind_udp(5353).unwrap();
+    u.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut buf = [0u8; 16];
+    times_out(|| u.recv_from(&mut buf));
+}
@@ -0,0 +1,152 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::thread;
+
+use rtcp::Device;
+
+mod common;
+
+// two stacks on either end of an in-memory link, one connecting to the other
+#[test]
+fn connect_transfer_close() {
+    let (mut client, mut server) = common::pair();
+
+    let mut l = server.bind(8080).unwrap();
+    let echo = thread::spawn(move || {
+        let mut s = l.accept().unwrap();
+        let mut got = Vec::new();
+        s.read_to_end(&mut got).unwrap();
+        let mut sent = 0;
+        while sent < got.len() {
+            sent += s.write(&got[sent..]).unwrap();
+        }
+        s.shutdown(Shutdown::Write).unwrap();
+        got
+    });
+
+    let mut s = client.connect(common::server_addr(8080)).unwrap();
+    let msg = b"hello over a virtual link";
+    assert_eq!(s.write(msg).unwrap(), msg.len());
+    s.shutdown(Shutdown::Write).unwrap();
+    let mut back = Vec::new();
+    s.read_to_end(&mut back).unwrap();
+
+    assert_eq!(echo.join().unwrap(), msg);
+    assert_eq!(back, msg);
+}
+
+#[test]
+fn connect_refused() {
+    let (mut client, _server) = common::pair();
+
+    let err = client.connect(common::server_addr(8080)).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+}
+
+// a connection nobody got around to accepting is reset when its listener goes
+#[test]
+fn listener_drop_resets_queued() {
+    let (mut client, mut server) = common::pair();
+
+    let l = server.bind(8080).unwrap();
+    let mut s = client.connect(common::server_addr(8080)).unwrap();
+    drop(l);
+
+    let mut buf = [0u8; 16];
+    let err = s.read(&mut buf).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+}
+
+// a blocking send_to waits for room in the send queue instead of failing with WouldBlock
+#[test]
+fn udp_send_waits_for_room() {
+    let (mut client, mut server) = common::pair();
+
+    let to = common::server_addr(5353);
+    let u = client.bind_udp(5000).unwrap();
+    let r = server.bind_udp(5353).unwrap();
+    // several times what the send queue holds
+    for _ in 0..1000 {
+        assert_eq!(u.send_to(&[7; 1400], to).unwrap(), 1400);
+    }
+    let mut buf = [0u8; 1500];
+    let (n, from) = r.recv_from(&mut buf).unwrap();
+    assert_eq!(n, 1400);
+    assert_eq!(from, SocketAddr::new(common::CLIENT.into(), 5000));
+}
+
+// lots of connections open at once, each only ticked when it has something to do
+#[test]
+fn many_connections() {
+    const N: usize = 100;
+    let (mut client, mut server) = common::pair();
+
+    let mut l = server.bind(8080).unwrap();
+    let receiver = thread::spawn(move || {
+        let accepted: Vec<_> = (0..N).map(|_| l.accept().unwrap()).collect();
+        let mut got: Vec<u8> = accepted
+            .into_iter()
+            .map(|mut s| {
+                let mut buf = Vec::new();
+                s.read_to_end(&mut buf).unwrap();
+                assert_eq!(buf.len(), 1);
+                buf[0]
+            })
+            .collect();
+        got.sort();
+        got
+    });
+
+    let mut streams: Vec<_> = (0..N)
+        .map(|_| client.connect(common::server_addr(8080)).unwrap())
+        .collect();
+    for (i, s) in streams.iter_mut().enumerate() {
+        s.write_all(&[i as u8]).unwrap();
+        s.shutdown(Shutdown::Write).unwrap();
+    }
+
+    assert_eq!(receiver.join().unwrap(), (0..N as u8).collect::<Vec<_>>());
+    drop(streams);
+}
+
+// a device that breaks the first time anything arrives
+struct Broken {
+    dev: rtcp::VirtualDevice,
+    broke: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl Device for Broken {
+    fn send(&mut self, frame: &[u8]) -> std::io::Result<usize> {
+        self.dev.send(frame)
+    }
+
+    fn recv(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        self.broke.store(true, std::sync::atomic::Ordering::SeqCst);
+        Err(std::io::Error::other("broken"))
+    }
+}
+
+impl std::os::unix::io::AsRawFd for Broken {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.dev.as_raw_fd()
+    }
+}
+
+// packet_loop giving up on a broken device is no reason to panic when the interface goes away
+#[test]
+fn drop_after_device_error() {
+    let (a, mut peer) = rtcp::virtual_link().unwrap();
+    let broke = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let i = rtcp::Interface::with_device(Broken {
+        dev: a,
+        broke: broke.clone(),
+    })
+    .unwrap();
+    peer.send(&[0x45; 20]).unwrap();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while !broke.load(std::sync::atomic::Ordering::SeqCst) {
+        assert!(std::time::Instant::now() < deadline, "nothing was read");
+        thread::sleep(std::time::Duration::from_millis(1));
+    }
+    drop(i);
+}